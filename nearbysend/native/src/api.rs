@@ -1,29 +1,139 @@
-use crate::discovery::ble::BleDevice;
 use flutter_rust_bridge::frb;
-use std::sync::Arc;
+use serde::{Deserialize, Serialize};
 
 // 导出模块
 pub use crate::discovery::ble::start_ble_discovery;
+pub use crate::discovery::ble::start_ble_discovery_with_config;
 pub use crate::discovery::ble::stop_ble_discovery;
+pub use crate::discovery::ble::list_ble_adapters;
+pub use crate::discovery::advertiser::start_ble_advertising;
+pub use crate::discovery::advertiser::stop_ble_advertising;
 pub use crate::discovery::mdns::start_mdns_discovery;
 pub use crate::discovery::mdns::stop_mdns_discovery;
+pub use crate::discovery::manager::get_devices;
+pub use crate::discovery::manager::listen_discovery_events;
+pub use crate::discovery::manager::set_device_expiry;
 pub use crate::connection::wifi_direct::connect_to_device;
+pub use crate::connection::wifi_direct::get_peer_info;
+pub use crate::connection::gatt::connect_to_ble_device;
+pub use crate::connection::gatt::set_ble_connection_info;
+pub use crate::connection::gatt::get_pending_connection_offers;
+pub use crate::connection::gatt::accept_connection_offer;
+pub use crate::connection::gatt::reject_connection_offer;
 pub use crate::transfer::protocol::send_file;
+pub use crate::transfer::protocol::send_files;
 pub use crate::transfer::protocol::receive_file;
+pub use crate::transfer::protocol::set_send_window_size;
+pub use crate::transfer::protocol::resume_interrupted_send;
+pub use crate::transfer::protocol::discard_interrupted_send;
+pub use crate::transfer::protocol::get_resumable_transfers;
+pub use crate::transfer::protocol::discard_resumable_transfer;
+pub use crate::transfer::protocol::set_conflict_policy;
+pub use crate::transfer::protocol::get_pending_conflicts;
+pub use crate::transfer::protocol::resolve_file_conflict;
+pub use crate::transfer::protocol::get_pending_requests;
+pub use crate::transfer::protocol::accept_transfer;
+pub use crate::transfer::protocol::reject_transfer;
+pub use crate::transfer::protocol::cancel_transfer;
+pub use crate::transfer::protocol::pause_transfer;
+pub use crate::transfer::protocol::resume_transfer;
+pub use crate::transfer::events::listen_transfer_events;
 
 // 设备结构体
 #[frb(dart_metadata=("freezed"))]
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Device {
     pub id: String,
     pub name: String,
     pub device_type: DeviceType,
     pub is_connected: bool,
+    // 可以通过哪些方式连接到该设备
+    pub transports: Vec<DeviceTransport>,
+    // 最后一次发现的时间（Unix毫秒）
+    pub last_seen_ms: u64,
+    // 最近一次BLE广播的信号强度
+    pub rssi: Option<i16>,
 }
 
-// 设备类型枚举
+// 设备的可达方式
+#[frb(dart_metadata=("freezed"))]
+#[derive(Clone, Debug, PartialEq)]
+pub enum DeviceTransport {
+    // 通过BLE发现，peripheral_id为本机蓝牙栈分配的外设ID
+    Ble { peripheral_id: String },
+    // 通过mDNS发现的局域网地址
+    Lan { ip_address: String, port: u16 },
+}
+
+// BLE扫描配置
+#[frb(dart_metadata=("freezed"))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct BleScanConfig {
+    // 扫描时长（秒），None表示持续扫描直到调用stop_ble_discovery
+    pub duration_seconds: Option<u64>,
+    // 间歇扫描以节省电量，None表示不间断扫描
+    pub duty_cycle: Option<BleDutyCycle>,
+    // 按名称或地址选择适配器，None时使用第一个已开启的适配器
+    pub adapter: Option<String>,
+}
+
+impl Default for BleScanConfig {
+    fn default() -> Self {
+        Self {
+            duration_seconds: Some(30),
+            duty_cycle: None,
+            adapter: None,
+        }
+    }
+}
+
+// 间歇扫描：每扫描scan_ms毫秒后暂停pause_ms毫秒
+#[frb(dart_metadata=("freezed"))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct BleDutyCycle {
+    pub scan_ms: u64,
+    pub pause_ms: u64,
+}
+
+// 蓝牙适配器信息
+#[frb(dart_metadata=("freezed"))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct BleAdapterInfo {
+    // 适配器名称（如Linux上的"hci0"）
+    pub id: String,
+    // 系统提供的适配器描述
+    pub name: String,
+    // 蓝牙地址（只有部分平台提供）
+    pub address: Option<String>,
+    pub powered: bool,
+}
+
+// 通过BLE交换的连接参数（扫描端读取外设的连接信息，或写入自己的连接信息让外设连接回来）
+#[frb(dart_metadata=("freezed"))]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ConnectionInfo {
+    // 已在同一局域网
+    Lan { ip_address: String, port: u16 },
+    // 先加入对方创建的热点
+    Hotspot { ssid: String, password: String, ip_address: String, port: u16 },
+    // 先以普通客户端身份加入对方的Wi-Fi Direct组
+    WifiDirect { group_name: String, passphrase: String, ip_address: String, port: u16 },
+}
+
+// 设备发现事件（由listen_discovery_events推送）
 #[frb(dart_metadata=("freezed"))]
 #[derive(Clone, Debug)]
+pub enum DiscoveryEvent {
+    Added { device: Device },
+    // 名称、类型或可达方式发生变化
+    Updated { device: Device },
+    // 所有来源都已失去该设备
+    Lost { device_id: String },
+}
+
+// 设备类型枚举
+#[frb(dart_metadata=("freezed"))]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum DeviceType {
     Android,
     IOS,
@@ -32,6 +142,153 @@ pub enum DeviceType {
     Unknown,
 }
 
+// 握手后得到的对端信息
+#[frb(dart_metadata=("freezed"))]
+#[derive(Clone, Debug)]
+pub struct PeerInfo {
+    pub device_id: String,
+    pub device_name: String,
+    pub device_type: DeviceType,
+    // 协商后的协议版本
+    pub protocol_version: u8,
+    // 双方都支持的功能
+    pub capabilities: Vec<String>,
+}
+
+// 错误类型（Flutter端按类型处理，不需要匹配错误消息）
+#[frb(dart_metadata=("freezed"))]
+#[derive(Clone, Debug, PartialEq, thiserror::Error)]
+pub enum NearbySendError {
+    #[error("No active connection")]
+    NotConnected,
+    // 对方拒绝了传输请求
+    #[error("Transfer rejected by receiver{}", reason.as_ref().map(|r| format!(": {}", r)).unwrap_or_default())]
+    Rejected { reason: Option<String> },
+    // 任意一方取消了传输
+    #[error("Transfer cancelled")]
+    Cancelled,
+    #[error("Timed out: {operation}")]
+    Timeout { operation: String },
+    // 文件或网络读写失败，包括连接被对方关闭
+    #[error("{message}")]
+    Io { message: String },
+    // 对端发送了不符合协议的数据，或双方不兼容
+    #[error("Protocol violation: {message}")]
+    ProtocolViolation { message: String },
+    // 数据块校验或文件哈希不一致
+    #[error("Verification failed: {message}")]
+    VerificationFailed { message: String },
+    #[error("Permission denied: {message}")]
+    PermissionDenied { message: String },
+    // 蓝牙适配器已关闭
+    #[error("Bluetooth is turned off")]
+    BluetoothOff,
+    #[error("Not found: {message}")]
+    NotFound { message: String },
+    // 参数无效，或传输当前的状态不允许该操作
+    #[error("Invalid request: {message}")]
+    InvalidRequest { message: String },
+    // 对端或本机不支持该功能
+    #[error("Unsupported: {message}")]
+    Unsupported { message: String },
+    #[error("Internal error: {message}")]
+    Internal { message: String },
+}
+
+impl NearbySendError {
+    // 按I/O错误的类型转换，并附加上下文
+    pub(crate) fn io(context: &str, error: std::io::Error) -> Self {
+        let message = format!("{}: {}", context, error);
+        match error.kind() {
+            std::io::ErrorKind::PermissionDenied => NearbySendError::PermissionDenied { message },
+            std::io::ErrorKind::NotFound => NearbySendError::NotFound { message },
+            std::io::ErrorKind::TimedOut => NearbySendError::Timeout { operation: message },
+            _ => NearbySendError::Io { message },
+        }
+    }
+
+    pub(crate) fn connection_closed() -> Self {
+        NearbySendError::Io {
+            message: "Connection closed by peer".to_string(),
+        }
+    }
+
+    pub(crate) fn timeout(operation: impl Into<String>) -> Self {
+        NearbySendError::Timeout { operation: operation.into() }
+    }
+
+    pub(crate) fn protocol(message: impl Into<String>) -> Self {
+        NearbySendError::ProtocolViolation { message: message.into() }
+    }
+
+    pub(crate) fn verification(message: impl Into<String>) -> Self {
+        NearbySendError::VerificationFailed { message: message.into() }
+    }
+
+    pub(crate) fn not_found(message: impl Into<String>) -> Self {
+        NearbySendError::NotFound { message: message.into() }
+    }
+
+    pub(crate) fn invalid(message: impl Into<String>) -> Self {
+        NearbySendError::InvalidRequest { message: message.into() }
+    }
+
+    pub(crate) fn unsupported(message: impl Into<String>) -> Self {
+        NearbySendError::Unsupported { message: message.into() }
+    }
+
+    pub(crate) fn internal(message: impl Into<String>) -> Self {
+        NearbySendError::Internal { message: message.into() }
+    }
+}
+
+impl From<std::io::Error> for NearbySendError {
+    fn from(error: std::io::Error) -> Self {
+        NearbySendError::io("I/O error", error)
+    }
+}
+
+// 锁中毒说明持有锁的线程发生了panic
+impl<T> From<std::sync::PoisonError<T>> for NearbySendError {
+    fn from(error: std::sync::PoisonError<T>) -> Self {
+        NearbySendError::internal(error.to_string())
+    }
+}
+
+impl From<btleplug::Error> for NearbySendError {
+    fn from(error: btleplug::Error) -> Self {
+        let message = error.to_string();
+        match error {
+            btleplug::Error::PermissionDenied => NearbySendError::PermissionDenied { message },
+            btleplug::Error::NotConnected => NearbySendError::NotConnected,
+            btleplug::Error::DeviceNotFound => NearbySendError::NotFound { message },
+            btleplug::Error::TimedOut(_) => NearbySendError::Timeout { operation: message },
+            btleplug::Error::NotSupported(_) => NearbySendError::Unsupported { message },
+            _ => NearbySendError::Io { message },
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl From<dbus::Error> for NearbySendError {
+    fn from(error: dbus::Error) -> Self {
+        let message = error.to_string();
+        match error.name().unwrap_or_default() {
+            "org.freedesktop.DBus.Error.AccessDenied" | "org.bluez.Error.NotPermitted" => NearbySendError::PermissionDenied { message },
+            "org.freedesktop.DBus.Error.ServiceUnknown" | "org.bluez.Error.NotSupported" => NearbySendError::Unsupported { message },
+            "org.bluez.Error.NotReady" => NearbySendError::BluetoothOff,
+            "org.freedesktop.DBus.Error.NoReply" | "org.freedesktop.DBus.Error.Timeout" => NearbySendError::Timeout { operation: message },
+            _ => NearbySendError::Io { message },
+        }
+    }
+}
+
+impl From<mdns_sd::Error> for NearbySendError {
+    fn from(error: mdns_sd::Error) -> Self {
+        NearbySendError::Io { message: error.to_string() }
+    }
+}
+
 // 文件传输状态枚举
 #[frb(dart_metadata=("freezed"))]
 #[derive(Clone, Debug)]
@@ -39,8 +296,26 @@ pub enum TransferStatus {
     Pending,
     Connecting,
     Transferring,
+    // 任意一方暂停，连接保持
+    Paused,
     Completed,
     Failed,
+    // 接收方已有同名文件，按冲突策略跳过
+    Skipped,
+    // 任意一方取消
+    Cancelled,
+}
+
+// 文件名冲突策略（接收方已存在同名文件时）
+#[frb(dart_metadata=("freezed"))]
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConflictPolicy {
+    // 自动重命名为"name (1).ext"
+    Rename,
+    Overwrite,
+    Skip,
+    // 询问用户
+    Ask,
 }
 
 // 文件传输结构体
@@ -52,10 +327,83 @@ pub struct FileTransfer {
     pub file_size: u64,
     pub transferred_bytes: u64,
     pub status: TransferStatus,
+    // 批量传输中单个文件所属的整批传输ID
+    pub parent_id: Option<String>,
+    // 接收文件的最终保存路径（处理文件名冲突之后）
+    pub save_path: Option<String>,
+}
+
+// 传输事件（由listen_transfer_events推送）
+#[frb(dart_metadata=("freezed"))]
+#[derive(Clone, Debug)]
+pub enum TransferEvent {
+    // 新增传输或传输状态变化
+    StatusChanged { transfer: FileTransfer },
+    // 传输进度（每个传输节流发送），速度为平滑后的字节每秒，速度未知时没有剩余时间
+    Progress {
+        transfer_id: String,
+        transferred_bytes: u64,
+        total_bytes: u64,
+        bytes_per_second: u64,
+        eta_seconds: Option<u64>,
+    },
+    // 通过BLE收到连接邀请，等待用户确认
+    ConnectionOfferReceived { offer: IncomingConnectionOffer },
+}
+
+// 等待用户决定的文件名冲突
+#[frb(dart_metadata=("freezed"))]
+#[derive(Clone, Debug)]
+pub struct FileConflict {
+    pub transfer_id: String,
+    pub file_name: String,
+    pub file_size: u64,
+    pub existing_path: String,
+}
+
+// 等待用户确认的传输请求
+#[frb(dart_metadata=("freezed"))]
+#[derive(Clone, Debug)]
+pub struct IncomingTransferRequest {
+    pub id: String,
+    pub sender_name: String,
+    pub sender_address: String,
+    pub files: Vec<IncomingFile>,
+    pub total_size: u64,
+}
+
+// 通过BLE收到的连接邀请（用户接受后才加入对方提供的网络）
+#[frb(dart_metadata=("freezed"))]
+#[derive(Clone, Debug)]
+pub struct IncomingConnectionOffer {
+    pub id: String,
+    // 写入邀请的设备的蓝牙地址（未知时为空）
+    pub sender_address: String,
+    // 要加入的网络：热点SSID、Wi-Fi Direct组名或局域网地址
+    pub network_name: String,
+}
+
+// 传输请求中的单个文件
+#[frb(dart_metadata=("freezed"))]
+#[derive(Clone, Debug)]
+pub struct IncomingFile {
+    pub file_name: String,
+    pub file_size: u64,
+}
+
+// 可续传的接收结构体
+#[frb(dart_metadata=("freezed"))]
+#[derive(Clone, Debug)]
+pub struct ResumableTransfer {
+    pub id: String,
+    pub file_name: String,
+    pub file_size: u64,
+    pub received_bytes: u64,
+    pub save_path: String,
 }
 
 // 初始化函数
-pub fn initialize() -> Result<(), String> {
+pub fn initialize() -> Result<(), NearbySendError> {
     // 初始化日志
     env_logger::init();
     log::info!("NearbySend Rust backend initialized");
@@ -85,7 +433,80 @@ pub fn get_device_name() -> String {
     }
 }
 
+// 应用数据目录中保存设备ID的文件
+const DEVICE_ID_FILE: &str = "device_id";
+
+lazy_static::lazy_static! {
+    // 设备ID（调用init_device_id之前为本次进程的临时ID）
+    static ref DEVICE_ID: std::sync::Mutex<String> = std::sync::Mutex::new(uuid::Uuid::new_v4().to_string());
+}
+
+// 从应用数据目录加载设备ID，不存在时生成并保存
+// 应在启动发现和广播之前调用，保证握手、BLE广播和mDNS在每次启动时使用同一个ID
+pub fn init_device_id(data_dir: String) -> Result<String, NearbySendError> {
+    let id = load_or_create_device_id(std::path::Path::new(&data_dir))?;
+    *DEVICE_ID.lock()? = id.clone();
+    Ok(id)
+}
+
+fn load_or_create_device_id(data_dir: &std::path::Path) -> Result<String, NearbySendError> {
+    let path = data_dir.join(DEVICE_ID_FILE);
+    match std::fs::read_to_string(&path) {
+        Ok(content) => match uuid::Uuid::parse_str(content.trim()) {
+            Ok(id) => return Ok(id.to_string()),
+            Err(_) => log::warn!("Invalid device id in {}, generating a new one", path.display()),
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(NearbySendError::io("Failed to read device id", e)),
+    }
+
+    let id = uuid::Uuid::new_v4().to_string();
+    std::fs::create_dir_all(data_dir)
+        .and_then(|_| std::fs::write(&path, &id))
+        .map_err(|e| NearbySendError::io("Failed to save device id", e))?;
+    Ok(id)
+}
+
+// 获取设备ID
+pub fn get_device_id() -> String {
+    DEVICE_ID.lock().map(|id| id.clone()).unwrap_or_else(|e| e.into_inner().clone())
+}
+
+// 获取设备类型
+pub fn get_device_type() -> DeviceType {
+    match std::env::consts::OS {
+        "android" => DeviceType::Android,
+        "ios" => DeviceType::IOS,
+        "macos" => DeviceType::MacOS,
+        "windows" => DeviceType::Windows,
+        _ => DeviceType::Unknown,
+    }
+}
+
 // 测试函数
 pub fn greet(name: String) -> String {
     format!("Hello, {}! Welcome to NearbySend", name)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_id_is_persisted() {
+        let dir = std::env::temp_dir().join(format!("nearbysend-api-{}", uuid::Uuid::new_v4()));
+
+        // 首次生成并保存，之后的启动读取同一个ID
+        let id = load_or_create_device_id(&dir).unwrap();
+        assert_eq!(load_or_create_device_id(&dir).unwrap(), id);
+        assert_eq!(std::fs::read_to_string(dir.join(DEVICE_ID_FILE)).unwrap(), id);
+
+        // 文件损坏时重新生成
+        std::fs::write(dir.join(DEVICE_ID_FILE), "garbage").unwrap();
+        let regenerated = load_or_create_device_id(&dir).unwrap();
+        assert_ne!(regenerated, id);
+        assert_eq!(load_or_create_device_id(&dir).unwrap(), regenerated);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
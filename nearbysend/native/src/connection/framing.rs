@@ -0,0 +1,159 @@
+use crate::api::NearbySendError;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// 帧头大小（4字节大端长度前缀）
+pub const FRAME_HEADER_SIZE: usize = 4;
+
+// 最大帧大小
+pub const MAX_FRAME_SIZE: usize = 4 * 1024 * 1024; // 4MB
+
+// 旧版本（协议版本1）对端不分帧，直接发送JSON对象
+const LEGACY_JSON_START: u8 = b'{';
+
+// 写入一帧：长度前缀 + 负载
+pub async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, payload: &[u8]) -> Result<(), NearbySendError> {
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(NearbySendError::invalid(format!("Frame too large: {} bytes (max {})", payload.len(), MAX_FRAME_SIZE)));
+    }
+
+    // 将帧头和负载合并后一次写入，避免帧头被单独发送
+    let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+
+    writer.write_all(&frame).await.map_err(|e| NearbySendError::io("Failed to write frame", e))?;
+    writer.flush().await.map_err(|e| NearbySendError::io("Failed to flush frame", e))?;
+
+    Ok(())
+}
+
+// 读取一帧，负载超过max_size时返回错误
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R, max_size: usize) -> Result<Vec<u8>, NearbySendError> {
+    // 读取帧头
+    let mut header = [0u8; FRAME_HEADER_SIZE];
+    reader.read_exact(&mut header).await.map_err(|e| match e.kind() {
+        std::io::ErrorKind::UnexpectedEof => NearbySendError::connection_closed(),
+        _ => NearbySendError::io("Failed to read frame header", e),
+    })?;
+
+    // 合法帧的长度不会超过MAX_FRAME_SIZE，首字节为'{'只能是旧版本对端直接发送的JSON
+    if header[0] == LEGACY_JSON_START {
+        return Err(NearbySendError::protocol("Peer uses unsupported protocol version 1"));
+    }
+
+    // 检查帧大小
+    let length = u32::from_be_bytes(header) as usize;
+    let limit = max_size.min(MAX_FRAME_SIZE);
+    if length > limit {
+        return Err(NearbySendError::protocol(format!("Frame too large: {} bytes (max {})", length, limit)));
+    }
+
+    // 读取完整负载
+    let mut payload = vec![0u8; length];
+    reader.read_exact(&mut payload).await.map_err(|e| NearbySendError::io("Failed to read frame payload", e))?;
+
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::ReadBuf;
+
+    // 每次最多返回一个字节的读取器，用于模拟分片到达
+    struct TrickleReader {
+        data: Vec<u8>,
+        position: usize,
+    }
+
+    impl AsyncRead for TrickleReader {
+        fn poll_read(mut self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+            if self.position < self.data.len() && buf.remaining() > 0 {
+                let byte = self.data[self.position];
+                buf.put_slice(&[byte]);
+                self.position += 1;
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let mut buffer = Vec::new();
+        write_frame(&mut buffer, b"hello").await.unwrap();
+        assert_eq!(buffer.len(), FRAME_HEADER_SIZE + 5);
+
+        let mut reader = &buffer[..];
+        assert_eq!(read_frame(&mut reader, 1024).await.unwrap(), b"hello");
+    }
+
+    #[tokio::test]
+    async fn partial_reads() {
+        let payload: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        let mut buffer = Vec::new();
+        write_frame(&mut buffer, &payload).await.unwrap();
+
+        let mut reader = TrickleReader { data: buffer, position: 0 };
+        assert_eq!(read_frame(&mut reader, MAX_FRAME_SIZE).await.unwrap(), payload);
+    }
+
+    #[tokio::test]
+    async fn partial_reads_over_small_pipe() {
+        let (mut client, mut server) = tokio::io::duplex(7);
+        let payload = vec![0xABu8; 64 * 1024];
+        let expected = payload.clone();
+
+        let writer = tokio::spawn(async move {
+            write_frame(&mut client, &payload).await.unwrap();
+            write_frame(&mut client, b"next").await.unwrap();
+        });
+
+        assert_eq!(read_frame(&mut server, MAX_FRAME_SIZE).await.unwrap(), expected);
+        assert_eq!(read_frame(&mut server, MAX_FRAME_SIZE).await.unwrap(), b"next");
+        writer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn coalesced_writes() {
+        let mut buffer = Vec::new();
+        write_frame(&mut buffer, b"first").await.unwrap();
+        write_frame(&mut buffer, b"").await.unwrap();
+        write_frame(&mut buffer, b"third").await.unwrap();
+
+        let mut reader = &buffer[..];
+        assert_eq!(read_frame(&mut reader, 1024).await.unwrap(), b"first");
+        assert_eq!(read_frame(&mut reader, 1024).await.unwrap(), b"");
+        assert_eq!(read_frame(&mut reader, 1024).await.unwrap(), b"third");
+        assert_eq!(read_frame(&mut reader, 1024).await.unwrap_err(), NearbySendError::connection_closed());
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_frame() {
+        let mut buffer = Vec::new();
+        write_frame(&mut buffer, &[0u8; 2048]).await.unwrap();
+
+        let mut reader = &buffer[..];
+        assert!(read_frame(&mut reader, 1024).await.unwrap_err().to_string().contains("Frame too large"));
+
+        let mut sink = Vec::new();
+        assert!(write_frame(&mut sink, &vec![0u8; MAX_FRAME_SIZE + 1]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn detects_legacy_peer() {
+        let mut reader = &br#"{"TransferRequest":{"id":"1"}}"#[..];
+        assert!(read_frame(&mut reader, MAX_FRAME_SIZE).await.unwrap_err().to_string().contains("protocol version 1"));
+    }
+
+    #[tokio::test]
+    async fn truncated_payload() {
+        let mut buffer = Vec::new();
+        write_frame(&mut buffer, b"truncated").await.unwrap();
+        buffer.truncate(buffer.len() - 3);
+
+        let mut reader = &buffer[..];
+        assert!(read_frame(&mut reader, 1024).await.unwrap_err().to_string().starts_with("Failed to read frame payload"));
+    }
+}
@@ -0,0 +1,492 @@
+use crate::api::{ConnectionInfo, IncomingConnectionOffer, NearbySendError, TransferEvent};
+use crate::transfer::codec::MIN_PROTOCOL_VERSION;
+use crate::connection::hotspot::connect_to_hotspot;
+use crate::connection::wifi_direct::connect_to_device;
+use crate::discovery::ble::{get_discovered_devices, NEARBYSEND_SERVICE_UUID};
+use crate::transfer::events::emit_transfer_event;
+use btleplug::api::{Peripheral as _, WriteType};
+use btleplug::platform::Peripheral;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+use tokio::time::{self, Duration};
+use uuid::Uuid;
+
+// 外设的连接信息（扫描端读取，外设没有可用的连接方式时为空）
+pub const CONNECTION_INFO_CHARACTERISTIC_UUID: Uuid = Uuid::from_u128(0x00001235_0000_1000_8000_00805f9b34fb);
+
+// 扫描端提供的连接信息（扫描端写入，外设收到后连接扫描端）
+pub const CONNECTION_OFFER_CHARACTERISTIC_UUID: Uuid = Uuid::from_u128(0x00001236_0000_1000_8000_00805f9b34fb);
+
+// 特征值的最大长度（ATT协议限制）
+pub const MAX_CHARACTERISTIC_SIZE: usize = 512;
+
+// 等待用户确认连接邀请的超时时间（超时后拒绝）
+const OFFER_DECISION_TIMEOUT: Duration = Duration::from_secs(60);
+
+// 等待用户确认的连接邀请，以及用于回复是否接受的通道
+type PendingOffer = (IncomingConnectionOffer, oneshot::Sender<bool>);
+
+// 本机通过GATT服务公布的连接信息，以及等待确认的连接邀请
+lazy_static::lazy_static! {
+    static ref LOCAL_CONNECTION_INFO: Arc<Mutex<Option<ConnectionInfo>>> = Arc::new(Mutex::new(None));
+    static ref PENDING_OFFERS: Arc<Mutex<HashMap<String, PendingOffer>>> = Arc::new(Mutex::new(HashMap::new()));
+}
+
+// 编码连接信息特征值
+pub fn encode_connection_info(info: &ConnectionInfo) -> Result<Vec<u8>, NearbySendError> {
+    let value = serde_json::to_vec(info).map_err(|e| NearbySendError::internal(e.to_string()))?;
+    if value.len() > MAX_CHARACTERISTIC_SIZE {
+        return Err(NearbySendError::invalid(format!(
+            "Connection info too large: {} bytes (maximum {})",
+            value.len(),
+            MAX_CHARACTERISTIC_SIZE
+        )));
+    }
+    Ok(value)
+}
+
+// 解码连接信息特征值
+pub fn decode_connection_info(value: &[u8]) -> Result<ConnectionInfo, NearbySendError> {
+    if value.len() > MAX_CHARACTERISTIC_SIZE {
+        return Err(NearbySendError::protocol(format!("Connection info too large: {} bytes", value.len())));
+    }
+    serde_json::from_slice(value).map_err(|e| NearbySendError::protocol(format!("Invalid connection info: {}", e)))
+}
+
+// 设置本机公布的连接信息（None表示只能由扫描端提供连接方式）
+pub fn set_ble_connection_info(info: Option<ConnectionInfo>) -> Result<(), NearbySendError> {
+    if let Some(info) = &info {
+        encode_connection_info(info)?;
+    }
+
+    *LOCAL_CONNECTION_INFO.lock()? = info;
+    Ok(())
+}
+
+// 获取本机公布的连接信息
+pub fn local_connection_info() -> Result<Option<ConnectionInfo>, NearbySendError> {
+    Ok(LOCAL_CONNECTION_INFO.lock()?.clone())
+}
+
+// 外设端：扫描端读取连接信息特征值
+pub fn read_connection_info_value() -> Result<Vec<u8>, NearbySendError> {
+    match local_connection_info()? {
+        Some(info) => encode_connection_info(&info),
+        None => Ok(Vec::new()),
+    }
+}
+
+// 外设端：扫描端写入了它的连接信息，用户确认后在后台连接过去
+// central为写入方的蓝牙地址（BlueZ只接受已配对且加密认证的连接写入）
+pub fn handle_connection_offer(value: &[u8], central: Option<String>) -> Result<(), NearbySendError> {
+    let info = decode_connection_info(value)?;
+    log::info!("Received connection offer over BLE from {}", central.as_deref().unwrap_or("unknown device"));
+
+    tokio::spawn(async move {
+        match confirm_connection_offer(&info, central).await {
+            Ok(true) => {
+                if let Err(e) = connect_with_info(&info).await {
+                    log::error!("Failed to connect using BLE connection offer: {}", e);
+                }
+            }
+            Ok(false) => log::info!("BLE connection offer declined"),
+            Err(e) => log::error!("Failed to confirm BLE connection offer: {}", e),
+        }
+    });
+    Ok(())
+}
+
+// 询问用户是否接受连接邀请，超时视为拒绝
+async fn confirm_connection_offer(info: &ConnectionInfo, central: Option<String>) -> Result<bool, NearbySendError> {
+    let network_name = match info {
+        ConnectionInfo::Lan { ip_address, port } => format!("{}:{}", ip_address, port),
+        ConnectionInfo::Hotspot { ssid, .. } => ssid.clone(),
+        ConnectionInfo::WifiDirect { group_name, .. } => group_name.clone(),
+    };
+    let offer = IncomingConnectionOffer {
+        id: Uuid::new_v4().to_string(),
+        sender_address: central.unwrap_or_default(),
+        network_name,
+    };
+
+    let offer_id = offer.id.clone();
+    let (sender, receiver) = oneshot::channel();
+    PENDING_OFFERS.lock()?.insert(offer_id.clone(), (offer.clone(), sender));
+    emit_transfer_event(TransferEvent::ConnectionOfferReceived { offer });
+
+    match time::timeout(OFFER_DECISION_TIMEOUT, receiver).await {
+        Ok(Ok(accepted)) => Ok(accepted),
+        _ => {
+            PENDING_OFFERS.lock()?.remove(&offer_id);
+
+            log::warn!("No decision for connection offer {}, declining", offer_id);
+            Ok(false)
+        }
+    }
+}
+
+// 获取等待用户确认的连接邀请
+pub fn get_pending_connection_offers() -> Result<Vec<IncomingConnectionOffer>, NearbySendError> {
+    let pending = PENDING_OFFERS.lock()?;
+    Ok(pending.values().map(|(offer, _)| offer.clone()).collect())
+}
+
+// 接受连接邀请，在后台加入对方提供的网络并连接
+pub fn accept_connection_offer(offer_id: &str) -> Result<(), NearbySendError> {
+    answer_connection_offer(offer_id, true)
+}
+
+// 拒绝连接邀请
+pub fn reject_connection_offer(offer_id: &str) -> Result<(), NearbySendError> {
+    answer_connection_offer(offer_id, false)
+}
+
+// 回复等待确认的连接邀请
+fn answer_connection_offer(offer_id: &str, accepted: bool) -> Result<(), NearbySendError> {
+    let (_, sender) = PENDING_OFFERS
+        .lock()?
+        .remove(offer_id)
+        .ok_or_else(|| NearbySendError::not_found(format!("No pending connection offer: {}", offer_id)))?;
+
+    sender
+        .send(accepted)
+        .map_err(|_| NearbySendError::invalid(format!("Connection offer {} is no longer pending", offer_id)))
+}
+
+// 按连接信息建立连接（需要时先加入对方的热点或Wi-Fi Direct组）
+pub async fn connect_with_info(info: &ConnectionInfo) -> Result<(), NearbySendError> {
+    let (ip_address, port) = match info {
+        ConnectionInfo::Lan { ip_address, port } => (ip_address, *port),
+        ConnectionInfo::Hotspot {
+            ssid,
+            password,
+            ip_address,
+            port,
+        }
+        | ConnectionInfo::WifiDirect {
+            group_name: ssid,
+            passphrase: password,
+            ip_address,
+            port,
+        } => {
+            connect_to_hotspot(ssid, password).await?;
+            (ip_address, *port)
+        }
+    };
+
+    let ip_address: IpAddr = ip_address
+        .parse()
+        .map_err(|_| NearbySendError::invalid(format!("Invalid IP address: {}", ip_address)))?;
+    connect_to_device(ip_address, port).await
+}
+
+// 连接信息交换的结果
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConnectionExchange {
+    // 按外设的连接信息连接过去
+    Connect(ConnectionInfo),
+    // 已把本机的连接信息写给外设，等待外设连接过来
+    PeerWillConnect,
+}
+
+// 扫描端访问外设GATT服务所需的操作（测试时替换为模拟外设）
+pub trait GattPeripheral: Send + Sync {
+    fn connect_gatt(&self) -> impl Future<Output = Result<(), NearbySendError>> + Send;
+
+    fn disconnect_gatt(&self) -> impl Future<Output = Result<(), NearbySendError>> + Send;
+
+    // 发现服务，返回指定服务中的特征UUID
+    fn service_characteristics(&self, service: Uuid) -> impl Future<Output = Result<Vec<Uuid>, NearbySendError>> + Send;
+
+    fn read_characteristic(&self, characteristic: Uuid) -> impl Future<Output = Result<Vec<u8>, NearbySendError>> + Send;
+
+    fn write_characteristic(&self, characteristic: Uuid, value: &[u8]) -> impl Future<Output = Result<(), NearbySendError>> + Send;
+}
+
+impl GattPeripheral for Peripheral {
+    async fn connect_gatt(&self) -> Result<(), NearbySendError> {
+        if !self.is_connected().await? {
+            self.connect().await?;
+        }
+        Ok(())
+    }
+
+    async fn disconnect_gatt(&self) -> Result<(), NearbySendError> {
+        self.disconnect().await?;
+        Ok(())
+    }
+
+    async fn service_characteristics(&self, service: Uuid) -> Result<Vec<Uuid>, NearbySendError> {
+        self.discover_services().await?;
+        Ok(self
+            .services()
+            .into_iter()
+            .filter(|s| s.uuid == service)
+            .flat_map(|s| s.characteristics.into_iter().map(|c| c.uuid))
+            .collect())
+    }
+
+    async fn read_characteristic(&self, characteristic: Uuid) -> Result<Vec<u8>, NearbySendError> {
+        let characteristic = find_characteristic(self, characteristic)?;
+        Ok(self.read(&characteristic).await?)
+    }
+
+    async fn write_characteristic(&self, characteristic: Uuid, value: &[u8]) -> Result<(), NearbySendError> {
+        let characteristic = find_characteristic(self, characteristic)?;
+        self.write(&characteristic, value, WriteType::WithResponse).await?;
+        Ok(())
+    }
+}
+
+// 在已发现的服务中查找特征
+fn find_characteristic(peripheral: &Peripheral, uuid: Uuid) -> Result<btleplug::api::Characteristic, NearbySendError> {
+    peripheral
+        .characteristics()
+        .into_iter()
+        .find(|c| c.uuid == uuid)
+        .ok_or_else(|| NearbySendError::not_found(format!("Characteristic {} not found", uuid)))
+}
+
+// 扫描端：连接外设的NearbySend服务交换连接信息（无论成功与否都会断开GATT连接）
+pub async fn exchange_connection_info<P: GattPeripheral>(
+    peripheral: &P,
+    offer: Option<&ConnectionInfo>,
+) -> Result<ConnectionExchange, NearbySendError> {
+    peripheral.connect_gatt().await?;
+    let result = exchange_with_connected(peripheral, offer).await;
+
+    if let Err(e) = peripheral.disconnect_gatt().await {
+        log::warn!("Failed to disconnect GATT: {}", e);
+    }
+    result
+}
+
+// 优先使用外设的连接信息，外设没有时提供本机的连接信息
+async fn exchange_with_connected<P: GattPeripheral>(
+    peripheral: &P,
+    offer: Option<&ConnectionInfo>,
+) -> Result<ConnectionExchange, NearbySendError> {
+    let service = Uuid::parse_str(NEARBYSEND_SERVICE_UUID).map_err(|e| NearbySendError::internal(e.to_string()))?;
+    let characteristics = peripheral.service_characteristics(service).await?;
+    if !characteristics.contains(&CONNECTION_INFO_CHARACTERISTIC_UUID) {
+        return Err(NearbySendError::unsupported("Device does not provide the NearbySend GATT service"));
+    }
+
+    let value = peripheral.read_characteristic(CONNECTION_INFO_CHARACTERISTIC_UUID).await?;
+    if !value.is_empty() {
+        return Ok(ConnectionExchange::Connect(decode_connection_info(&value)?));
+    }
+
+    match offer {
+        Some(offer) if characteristics.contains(&CONNECTION_OFFER_CHARACTERISTIC_UUID) => {
+            peripheral
+                .write_characteristic(CONNECTION_OFFER_CHARACTERISTIC_UUID, &encode_connection_info(offer)?)
+                .await?;
+            Ok(ConnectionExchange::PeerWillConnect)
+        }
+        _ => Err(NearbySendError::not_found("Neither device has connection info to share")),
+    }
+}
+
+// 连接通过BLE发现的设备
+pub async fn connect_to_ble_device(peripheral_id: String) -> Result<(), NearbySendError> {
+    let device = get_discovered_devices()?
+        .into_iter()
+        .find(|d| d.id == peripheral_id)
+        .ok_or_else(|| NearbySendError::not_found(format!("BLE device {} not found", peripheral_id)))?;
+
+    // 广播中的协议版本过低时不必连接，握手一定会失败
+    if device.protocol_version < MIN_PROTOCOL_VERSION {
+        return Err(NearbySendError::unsupported(format!(
+            "Device uses unsupported protocol version {}",
+            device.protocol_version
+        )));
+    }
+
+    let offer = local_connection_info()?;
+    match exchange_connection_info(device.peripheral.as_ref(), offer.as_ref()).await? {
+        ConnectionExchange::Connect(info) => connect_with_info(&info).await,
+        ConnectionExchange::PeerWillConnect => {
+            log::info!("Waiting for {} to connect back", device.name);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    // 模拟外设的GATT服务
+    #[derive(Default)]
+    struct MockPeripheral {
+        characteristics: HashMap<Uuid, Vec<u8>>,
+        calls: Mutex<Vec<String>>,
+        written: Mutex<Option<Vec<u8>>>,
+    }
+
+    impl MockPeripheral {
+        fn with_info(info: Option<&ConnectionInfo>) -> Self {
+            let value = info.map(|info| encode_connection_info(info).unwrap()).unwrap_or_default();
+            Self {
+                characteristics: HashMap::from([
+                    (CONNECTION_INFO_CHARACTERISTIC_UUID, value),
+                    (CONNECTION_OFFER_CHARACTERISTIC_UUID, Vec::new()),
+                ]),
+                ..Self::default()
+            }
+        }
+
+        fn record(&self, call: &str) {
+            self.calls.lock().unwrap().push(call.to_string());
+        }
+    }
+
+    impl GattPeripheral for MockPeripheral {
+        async fn connect_gatt(&self) -> Result<(), NearbySendError> {
+            self.record("connect");
+            Ok(())
+        }
+
+        async fn disconnect_gatt(&self) -> Result<(), NearbySendError> {
+            self.record("disconnect");
+            Ok(())
+        }
+
+        async fn service_characteristics(&self, service: Uuid) -> Result<Vec<Uuid>, NearbySendError> {
+            assert_eq!(service.to_string(), NEARBYSEND_SERVICE_UUID);
+            Ok(self.characteristics.keys().cloned().collect())
+        }
+
+        async fn read_characteristic(&self, characteristic: Uuid) -> Result<Vec<u8>, NearbySendError> {
+            self.record("read");
+            Ok(self.characteristics[&characteristic].clone())
+        }
+
+        async fn write_characteristic(&self, characteristic: Uuid, value: &[u8]) -> Result<(), NearbySendError> {
+            self.record("write");
+            assert_eq!(characteristic, CONNECTION_OFFER_CHARACTERISTIC_UUID);
+            *self.written.lock().unwrap() = Some(value.to_vec());
+            Ok(())
+        }
+    }
+
+    fn hotspot() -> ConnectionInfo {
+        ConnectionInfo::Hotspot {
+            ssid: "NearbySend-1234".to_string(),
+            password: "secret-password".to_string(),
+            ip_address: "192.168.43.1".to_string(),
+            port: 4000,
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_peer_connection_info() {
+        let peripheral = MockPeripheral::with_info(Some(&hotspot()));
+        let offer = ConnectionInfo::Lan {
+            ip_address: "192.168.1.2".to_string(),
+            port: 5000,
+        };
+
+        // 外设有连接信息时不写入本机的信息
+        let exchange = exchange_connection_info(&peripheral, Some(&offer)).await.unwrap();
+        assert_eq!(exchange, ConnectionExchange::Connect(hotspot()));
+        assert_eq!(*peripheral.calls.lock().unwrap(), vec!["connect", "read", "disconnect"]);
+    }
+
+    #[tokio::test]
+    async fn offers_local_info_when_peer_has_none() {
+        let peripheral = MockPeripheral::with_info(None);
+        let offer = ConnectionInfo::WifiDirect {
+            group_name: "DIRECT-ns-Laptop".to_string(),
+            passphrase: "group-passphrase".to_string(),
+            ip_address: "192.168.49.1".to_string(),
+            port: 4000,
+        };
+
+        let exchange = exchange_connection_info(&peripheral, Some(&offer)).await.unwrap();
+        assert_eq!(exchange, ConnectionExchange::PeerWillConnect);
+        let written = peripheral.written.lock().unwrap().clone().unwrap();
+        assert_eq!(decode_connection_info(&written).unwrap(), offer);
+
+        // 双方都没有连接信息
+        let peripheral = MockPeripheral::with_info(None);
+        assert!(matches!(
+            exchange_connection_info(&peripheral, None).await,
+            Err(NearbySendError::NotFound { .. })
+        ));
+        assert_eq!(peripheral.calls.lock().unwrap().last().unwrap(), "disconnect");
+    }
+
+    #[tokio::test]
+    async fn rejects_other_peripherals() {
+        // 没有NearbySend服务的外设
+        let peripheral = MockPeripheral::default();
+        assert!(matches!(
+            exchange_connection_info(&peripheral, None).await,
+            Err(NearbySendError::Unsupported { .. })
+        ));
+        assert_eq!(*peripheral.calls.lock().unwrap(), vec!["connect", "disconnect"]);
+
+        // 外设返回无效的连接信息
+        let mut peripheral = MockPeripheral::with_info(None);
+        peripheral
+            .characteristics
+            .insert(CONNECTION_INFO_CHARACTERISTIC_UUID, b"not json".to_vec());
+        assert!(matches!(
+            exchange_connection_info(&peripheral, None).await,
+            Err(NearbySendError::ProtocolViolation { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn connection_offer_requires_confirmation() {
+        use crate::transfer::events::subscribe_transfer_events;
+
+        let mut events = subscribe_transfer_events();
+        for (central, accept) in [("AA:BB:CC:DD:EE:01", false), ("AA:BB:CC:DD:EE:02", true)] {
+            let task = tokio::spawn(async move { confirm_connection_offer(&hotspot(), Some(central.to_string())).await });
+
+            // 邀请单独等待用户确认，并通过事件通知Flutter端
+            let offer = loop {
+                if let TransferEvent::ConnectionOfferReceived { offer } = events.recv().await.unwrap() {
+                    if offer.sender_address == central {
+                        break offer;
+                    }
+                }
+            };
+            assert_eq!(offer.network_name, "NearbySend-1234");
+            assert!(get_pending_connection_offers().unwrap().iter().any(|o| o.id == offer.id));
+
+            if accept {
+                accept_connection_offer(&offer.id).unwrap();
+            } else {
+                reject_connection_offer(&offer.id).unwrap();
+            }
+            assert_eq!(task.await.unwrap().unwrap(), accept);
+            assert!(reject_connection_offer(&offer.id).is_err());
+        }
+    }
+
+    #[test]
+    fn serves_local_connection_info() {
+        set_ble_connection_info(None).unwrap();
+        assert!(read_connection_info_value().unwrap().is_empty());
+
+        set_ble_connection_info(Some(hotspot())).unwrap();
+        assert_eq!(decode_connection_info(&read_connection_info_value().unwrap()).unwrap(), hotspot());
+
+        // 超过特征值长度限制的信息无法公布
+        let oversized = ConnectionInfo::Lan {
+            ip_address: "x".repeat(MAX_CHARACTERISTIC_SIZE),
+            port: 1,
+        };
+        assert!(set_ble_connection_info(Some(oversized)).is_err());
+        assert!(handle_connection_offer(b"{", None).is_err());
+        set_ble_connection_info(None).unwrap();
+    }
+}
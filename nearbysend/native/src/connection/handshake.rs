@@ -0,0 +1,208 @@
+use crate::api::{get_device_id, get_device_name, get_device_type, DeviceType, NearbySendError, PeerInfo};
+use crate::connection::framing::{read_frame, write_frame};
+use crate::transfer::codec::{MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::{self, Duration};
+
+// 功能标识
+pub const CAPABILITY_RESUME: &str = "resume";
+pub const CAPABILITY_BATCH: &str = "batch";
+pub const CAPABILITY_PAUSE: &str = "pause";
+pub const CAPABILITY_CANCEL: &str = "cancel";
+pub const CAPABILITY_HASH_BLAKE3: &str = "hash-blake3";
+pub const CAPABILITY_CHUNK_CRC32: &str = "chunk-crc32";
+
+// 本端支持的功能
+pub const LOCAL_CAPABILITIES: &[&str] = &[
+    CAPABILITY_RESUME,
+    CAPABILITY_BATCH,
+    CAPABILITY_PAUSE,
+    CAPABILITY_CANCEL,
+    CAPABILITY_HASH_BLAKE3,
+    CAPABILITY_CHUNK_CRC32,
+];
+
+// 对端必须支持的功能（没有可回退的实现）
+pub const REQUIRED_CAPABILITIES: &[&str] = &[CAPABILITY_HASH_BLAKE3, CAPABILITY_CHUNK_CRC32];
+
+// 握手帧标记（握手帧不经过消息编解码，格式不随协议版本变化）
+const HELLO_MAGIC: &[u8] = b"NSHELLO";
+
+// 握手帧的最大大小
+const HELLO_MAX_SIZE: usize = 16 * 1024; // 16KB
+
+// 等待对端握手的超时时间
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// 连接建立时双方交换的握手消息
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Hello {
+    pub protocol_version: u8,
+    pub min_protocol_version: u8,
+    pub device_id: String,
+    pub device_name: String,
+    pub device_type: DeviceType,
+    // 未知的功能标识直接忽略，便于协议扩展
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+}
+
+impl Hello {
+    // 本端的握手消息
+    pub fn local() -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+            device_id: get_device_id(),
+            device_name: get_device_name(),
+            device_type: get_device_type(),
+            capabilities: LOCAL_CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+}
+
+// 编码握手帧
+pub fn encode_hello(hello: &Hello) -> Result<Vec<u8>, NearbySendError> {
+    let json = serde_json::to_vec(hello).map_err(|e| NearbySendError::internal(e.to_string()))?;
+
+    let mut buffer = Vec::with_capacity(HELLO_MAGIC.len() + json.len());
+    buffer.extend_from_slice(HELLO_MAGIC);
+    buffer.extend_from_slice(&json);
+
+    Ok(buffer)
+}
+
+// 解码握手帧
+pub fn decode_hello(bytes: &[u8]) -> Result<Hello, NearbySendError> {
+    let body = bytes
+        .strip_prefix(HELLO_MAGIC)
+        .ok_or_else(|| NearbySendError::protocol("Peer did not send a hello"))?;
+    serde_json::from_slice(body).map_err(|e| NearbySendError::protocol(format!("Invalid hello: {}", e)))
+}
+
+// 协商双方共同的协议版本和功能，不兼容时返回错误
+pub fn negotiate(local: &Hello, peer: &Hello) -> Result<PeerInfo, NearbySendError> {
+    let protocol_version = local.protocol_version.min(peer.protocol_version);
+    if protocol_version < local.min_protocol_version.max(peer.min_protocol_version) {
+        return Err(NearbySendError::protocol(format!(
+            "Incompatible protocol version: peer supports {}-{}, this device supports {}-{}",
+            peer.min_protocol_version, peer.protocol_version, local.min_protocol_version, local.protocol_version
+        )));
+    }
+
+    if let Some(missing) = REQUIRED_CAPABILITIES
+        .iter()
+        .find(|required| !peer.capabilities.iter().any(|c| c == *required))
+    {
+        return Err(NearbySendError::protocol(format!("Peer does not support required capability: {}", missing)));
+    }
+
+    let capabilities = local
+        .capabilities
+        .iter()
+        .filter(|c| peer.capabilities.contains(c))
+        .cloned()
+        .collect();
+
+    Ok(PeerInfo {
+        device_id: peer.device_id.clone(),
+        device_name: peer.device_name.clone(),
+        device_type: peer.device_type.clone(),
+        protocol_version,
+        capabilities,
+    })
+}
+
+// 在新连接上交换握手消息（双方同时发送，再读取对方的握手）
+pub async fn perform_handshake<R, W>(reader: &mut R, writer: &mut W) -> Result<PeerInfo, NearbySendError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let local = Hello::local();
+    write_frame(writer, &encode_hello(&local)?).await?;
+
+    let data = time::timeout(HANDSHAKE_TIMEOUT, read_frame(reader, HELLO_MAX_SIZE))
+        .await
+        .map_err(|_| NearbySendError::timeout("Waiting for peer hello"))??;
+    let peer = decode_hello(&data)?;
+
+    let session = negotiate(&local, &peer)?;
+    log::info!(
+        "Handshake with {} ({}) complete: protocol version {}, capabilities {:?}",
+        session.device_name,
+        session.device_id,
+        session.protocol_version,
+        session.capabilities
+    );
+
+    Ok(session)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hello(min_protocol_version: u8, protocol_version: u8, capabilities: &[&str]) -> Hello {
+        Hello {
+            protocol_version,
+            min_protocol_version,
+            device_id: "peer-id".to_string(),
+            device_name: "Peer".to_string(),
+            device_type: DeviceType::Android,
+            capabilities: capabilities.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn hello_round_trip() {
+        let local = Hello::local();
+        let decoded = decode_hello(&encode_hello(&local).unwrap()).unwrap();
+        assert_eq!(decoded.device_id, local.device_id);
+        assert_eq!(decoded.protocol_version, PROTOCOL_VERSION);
+        assert_eq!(decoded.capabilities, local.capabilities);
+
+        assert!(decode_hello(br#"{"TransferRequest":{}}"#).is_err());
+        assert!(decode_hello(&[2, 1, b'{']).is_err());
+    }
+
+    #[test]
+    fn negotiates_common_features() {
+        let local = hello(2, 3, &[CAPABILITY_RESUME, CAPABILITY_BATCH, CAPABILITY_HASH_BLAKE3, CAPABILITY_CHUNK_CRC32]);
+        let peer = hello(1, 2, &[CAPABILITY_HASH_BLAKE3, CAPABILITY_CHUNK_CRC32, CAPABILITY_BATCH, "compression-zstd"]);
+
+        let session = negotiate(&local, &peer).unwrap();
+        assert_eq!(session.protocol_version, 2);
+        assert_eq!(session.capabilities, vec![CAPABILITY_BATCH, CAPABILITY_HASH_BLAKE3, CAPABILITY_CHUNK_CRC32]);
+        assert_eq!(session.device_id, "peer-id");
+    }
+
+    #[test]
+    fn rejects_incompatible_peers() {
+        let local = hello(2, 2, LOCAL_CAPABILITIES);
+
+        let newer = hello(3, 4, LOCAL_CAPABILITIES);
+        assert!(negotiate(&local, &newer).unwrap_err().to_string().contains("Incompatible protocol version"));
+
+        let no_hash = hello(2, 2, &[CAPABILITY_CHUNK_CRC32]);
+        assert!(negotiate(&local, &no_hash).unwrap_err().to_string().contains(CAPABILITY_HASH_BLAKE3));
+    }
+
+    #[tokio::test]
+    async fn handshake_over_stream() {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let (mut client_reader, mut client_writer) = tokio::io::split(client);
+        let (mut server_reader, mut server_writer) = tokio::io::split(server);
+
+        let (client_session, server_session) = tokio::join!(
+            perform_handshake(&mut client_reader, &mut client_writer),
+            perform_handshake(&mut server_reader, &mut server_writer),
+        );
+
+        let client_session = client_session.unwrap();
+        assert_eq!(client_session.protocol_version, PROTOCOL_VERSION);
+        assert_eq!(client_session.capabilities.len(), LOCAL_CAPABILITIES.len());
+        assert_eq!(server_session.unwrap().device_id, get_device_id());
+    }
+}
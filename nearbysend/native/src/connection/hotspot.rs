@@ -1,4 +1,5 @@
-use std::error::Error;
+use crate::api::{ConnectionInfo, NearbySendError};
+use crate::connection::gatt::set_ble_connection_info;
 use std::sync::{Arc, Mutex};
 use tokio::time::{self, Duration};
 
@@ -27,10 +28,10 @@ lazy_static::lazy_static! {
 }
 
 // 创建热点
-pub async fn create_hotspot(ssid: &str, password: &str, port: u16) -> Result<HotspotInfo, String> {
+pub async fn create_hotspot(ssid: &str, password: &str, port: u16) -> Result<HotspotInfo, NearbySendError> {
     // 更新热点状态
     {
-        let mut status = HOTSPOT_STATUS.lock().map_err(|e| e.to_string())?;
+        let mut status = HOTSPOT_STATUS.lock()?;
         *status = HotspotStatus::Creating;
     }
 
@@ -51,48 +52,58 @@ pub async fn create_hotspot(ssid: &str, password: &str, port: u16) -> Result<Hot
     
     // 更新热点状态和信息
     {
-        let mut status = HOTSPOT_STATUS.lock().map_err(|e| e.to_string())?;
+        let mut status = HOTSPOT_STATUS.lock()?;
         *status = HotspotStatus::Active;
         
-        let mut current = CURRENT_HOTSPOT.lock().map_err(|e| e.to_string())?;
+        let mut current = CURRENT_HOTSPOT.lock()?;
         *current = Some(hotspot_info.clone());
     }
     
+    // 通过BLE公布热点，让扫描到本机的设备可以直接加入
+    set_ble_connection_info(Some(ConnectionInfo::Hotspot {
+        ssid: hotspot_info.ssid.clone(),
+        password: hotspot_info.password.clone(),
+        ip_address: hotspot_info.ip_address.clone(),
+        port,
+    }))?;
+    
     log::info!("Hotspot created: SSID={}, Password={}", ssid, password);
     
     Ok(hotspot_info)
 }
 
 // 关闭热点
-pub fn close_hotspot() -> Result<(), String> {
+pub fn close_hotspot() -> Result<(), NearbySendError> {
     // 更新热点状态
     {
-        let mut status = HOTSPOT_STATUS.lock().map_err(|e| e.to_string())?;
+        let mut status = HOTSPOT_STATUS.lock()?;
         *status = HotspotStatus::Inactive;
         
-        let mut current = CURRENT_HOTSPOT.lock().map_err(|e| e.to_string())?;
+        let mut current = CURRENT_HOTSPOT.lock()?;
         *current = None;
     }
     
+    set_ble_connection_info(None)?;
+    
     log::info!("Hotspot closed");
     
     Ok(())
 }
 
 // 获取热点状态
-pub fn get_hotspot_status() -> Result<HotspotStatus, String> {
-    let status = HOTSPOT_STATUS.lock().map_err(|e| e.to_string())?;
+pub fn get_hotspot_status() -> Result<HotspotStatus, NearbySendError> {
+    let status = HOTSPOT_STATUS.lock()?;
     Ok(status.clone())
 }
 
 // 获取当前热点信息
-pub fn get_current_hotspot() -> Result<Option<HotspotInfo>, String> {
-    let current = CURRENT_HOTSPOT.lock().map_err(|e| e.to_string())?;
+pub fn get_current_hotspot() -> Result<Option<HotspotInfo>, NearbySendError> {
+    let current = CURRENT_HOTSPOT.lock()?;
     Ok(current.clone())
 }
 
 // 连接到热点
-pub async fn connect_to_hotspot(ssid: &str, password: &str) -> Result<(), String> {
+pub async fn connect_to_hotspot(ssid: &str, password: &str) -> Result<(), NearbySendError> {
     // 这里是平台特定的热点连接逻辑
     // 在实际实现中，需要根据不同平台调用不同的API
     // 这里只是一个模拟实现
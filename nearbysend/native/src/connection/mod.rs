@@ -1,6 +1,12 @@
 pub mod wifi_direct;
 pub mod hotspot;
+pub mod framing;
+pub mod handshake;
+pub mod gatt;
 
 // 重新导出模块
 pub use wifi_direct::*;
 pub use hotspot::*;
+pub use framing::*;
+pub use handshake::*;
+pub use gatt::*;
@@ -1,10 +1,12 @@
-use std::error::Error;
-use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::net::{IpAddr, SocketAddr};
 use std::sync::{Arc, Mutex};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::{TcpListener as TokioTcpListener, TcpStream as TokioTcpStream};
-use tokio::sync::mpsc;
-use tokio::time::{self, Duration};
+use tokio::sync::watch;
+use tokio::sync::Mutex as AsyncMutex;
+use crate::api::{NearbySendError, PeerInfo};
+use crate::connection::framing::{read_frame, write_frame};
+use crate::connection::handshake::perform_handshake;
 
 // 连接状态枚举
 #[derive(Clone, Debug, PartialEq)]
@@ -15,37 +17,82 @@ pub enum ConnectionStatus {
     Failed,
 }
 
+// 当前连接（读写两端分离，各自加异步锁以保证帧不交错）
+struct Connection {
+    reader: Arc<AsyncMutex<OwnedReadHalf>>,
+    writer: Arc<AsyncMutex<OwnedWriteHalf>>,
+    peer_address: Option<SocketAddr>,
+    // 握手协商的结果
+    peer: PeerInfo,
+}
+
+impl Connection {
+    // 在新的TCP连接上完成握手
+    async fn establish(stream: TokioTcpStream) -> Result<Self, NearbySendError> {
+        let peer_address = stream.peer_addr().ok();
+        let (mut reader, mut writer) = stream.into_split();
+        let peer = perform_handshake(&mut reader, &mut writer).await?;
+
+        Ok(Self {
+            reader: Arc::new(AsyncMutex::new(reader)),
+            writer: Arc::new(AsyncMutex::new(writer)),
+            peer_address,
+            peer,
+        })
+    }
+}
+
 // 全局连接状态
 lazy_static::lazy_static! {
     static ref CONNECTION_STATUS: Arc<Mutex<ConnectionStatus>> = Arc::new(Mutex::new(ConnectionStatus::Disconnected));
-    static ref CURRENT_CONNECTION: Arc<Mutex<Option<TokioTcpStream>>> = Arc::new(Mutex::new(None));
+    static ref CURRENT_CONNECTION: Arc<Mutex<Option<Connection>>> = Arc::new(Mutex::new(None));
+    // 连接代数，每次建立或断开连接时递增
+    static ref CONNECTION_GENERATION: watch::Sender<u64> = watch::Sender::new(0);
+}
+
+// 替换当前连接，并通知等待在旧连接上的读取者
+fn set_connection(connection: Option<Connection>) -> Result<(), NearbySendError> {
+    {
+        let mut current = CURRENT_CONNECTION.lock()?;
+        *current = connection;
+    }
+
+    CONNECTION_GENERATION.send_modify(|generation| *generation += 1);
+    Ok(())
+}
+
+// 订阅连接变化
+pub fn subscribe_connection_changes() -> watch::Receiver<u64> {
+    CONNECTION_GENERATION.subscribe()
 }
 
 // 连接到设备
-pub async fn connect_to_device(ip_address: IpAddr, port: u16) -> Result<(), String> {
+pub async fn connect_to_device(ip_address: IpAddr, port: u16) -> Result<(), NearbySendError> {
     // 更新连接状态
     {
-        let mut status = CONNECTION_STATUS.lock().map_err(|e| e.to_string())?;
+        let mut status = CONNECTION_STATUS.lock()?;
         *status = ConnectionStatus::Connecting;
     }
 
     // 创建Socket地址
     let socket_addr = SocketAddr::new(ip_address, port);
 
-    // 尝试连接
-    match TokioTcpStream::connect(socket_addr).await {
-        Ok(stream) => {
+    // 尝试连接并握手
+    let connection = match TokioTcpStream::connect(socket_addr).await {
+        Ok(stream) => Connection::establish(stream).await,
+        Err(e) => Err(NearbySendError::io("Failed to connect to device", e)),
+    };
+
+    match connection {
+        Ok(connection) => {
             // 更新连接状态
             {
-                let mut status = CONNECTION_STATUS.lock().map_err(|e| e.to_string())?;
+                let mut status = CONNECTION_STATUS.lock()?;
                 *status = ConnectionStatus::Connected;
             }
 
             // 保存连接
-            {
-                let mut connection = CURRENT_CONNECTION.lock().map_err(|e| e.to_string())?;
-                *connection = Some(stream);
-            }
+            set_connection(Some(connection))?;
 
             log::info!("Connected to device at {}:{}", ip_address, port);
             Ok(())
@@ -53,53 +100,87 @@ pub async fn connect_to_device(ip_address: IpAddr, port: u16) -> Result<(), Stri
         Err(e) => {
             // 更新连接状态
             {
-                let mut status = CONNECTION_STATUS.lock().map_err(|e| e.to_string())?;
+                let mut status = CONNECTION_STATUS.lock()?;
                 *status = ConnectionStatus::Failed;
             }
 
             log::error!("Failed to connect to device: {}", e);
-            Err(format!("Failed to connect to device: {}", e))
+            Err(e)
         }
     }
 }
 
 // 断开连接
-pub fn disconnect() -> Result<(), String> {
+pub fn disconnect() -> Result<(), NearbySendError> {
     // 更新连接状态
     {
-        let mut status = CONNECTION_STATUS.lock().map_err(|e| e.to_string())?;
+        let mut status = CONNECTION_STATUS.lock()?;
         *status = ConnectionStatus::Disconnected;
     }
 
     // 清除连接
-    {
-        let mut connection = CURRENT_CONNECTION.lock().map_err(|e| e.to_string())?;
-        *connection = None;
-    }
+    set_connection(None)?;
 
     log::info!("Disconnected from device");
     Ok(())
 }
 
 // 获取连接状态
-pub fn get_connection_status() -> Result<ConnectionStatus, String> {
-    let status = CONNECTION_STATUS.lock().map_err(|e| e.to_string())?;
+pub fn get_connection_status() -> Result<ConnectionStatus, NearbySendError> {
+    let status = CONNECTION_STATUS.lock()?;
     Ok(status.clone())
 }
 
+// 获取当前连接的对端地址
+pub fn get_peer_address() -> Result<String, NearbySendError> {
+    let connection = CURRENT_CONNECTION.lock()?;
+    match &*connection {
+        Some(connection) => Ok(connection.peer_address.map(|addr| addr.to_string()).unwrap_or_default()),
+        None => Err(NearbySendError::NotConnected),
+    }
+}
+
+// 获取当前连接的对端信息（握手协商结果）
+pub fn get_peer_info() -> Result<PeerInfo, NearbySendError> {
+    let connection = CURRENT_CONNECTION.lock()?;
+    match &*connection {
+        Some(connection) => Ok(connection.peer.clone()),
+        None => Err(NearbySendError::NotConnected),
+    }
+}
+
+// 当前连接协商的协议版本（传输消息按此版本编解码）
+pub fn get_protocol_version() -> Result<u8, NearbySendError> {
+    let connection = CURRENT_CONNECTION.lock()?;
+    match &*connection {
+        Some(connection) => Ok(connection.peer.protocol_version),
+        None => Err(NearbySendError::NotConnected),
+    }
+}
+
+// 要求当前连接的双方都支持某个功能
+pub fn require_peer_capability(capability: &str) -> Result<(), NearbySendError> {
+    let peer = get_peer_info()?;
+    if peer.capabilities.iter().any(|c| c == capability) {
+        Ok(())
+    } else {
+        Err(NearbySendError::unsupported(format!("Peer {} does not support {}", peer.device_name, capability)))
+    }
+}
+
 // 启动监听服务器
-pub async fn start_server(port: u16) -> Result<u16, String> {
+pub async fn start_server(port: u16) -> Result<u16, NearbySendError> {
     // 创建监听器
     let listener = match TokioTcpListener::bind(format!("0.0.0.0:{}", port)).await {
         Ok(listener) => listener,
         Err(_) => {
             // 如果指定端口不可用，尝试使用随机端口
-            TokioTcpListener::bind("0.0.0.0:0").await.map_err(|e| e.to_string())?
+            TokioTcpListener::bind("0.0.0.0:0").await.map_err(|e| NearbySendError::io("Failed to start server", e))?
         }
     };
 
     // 获取实际端口
-    let actual_port = listener.local_addr().map_err(|e| e.to_string())?.port();
+    let actual_port = listener.local_addr()?.port();
     log::info!("Server started on port {}", actual_port);
 
     // 在后台处理连接
@@ -107,58 +188,67 @@ pub async fn start_server(port: u16) -> Result<u16, String> {
         while let Ok((stream, addr)) = listener.accept().await {
             log::info!("New connection from {}", addr);
 
-            // 更新连接状态
-            if let Ok(mut status) = CONNECTION_STATUS.lock() {
-                *status = ConnectionStatus::Connected;
-            }
-
-            // 保存连接
-            if let Ok(mut connection) = CURRENT_CONNECTION.lock() {
-                *connection = Some(stream);
-            }
-
-            // 处理连接...
+            // 握手在单独的任务中进行，避免慢速的对端阻塞监听
+            tokio::spawn(async move {
+                let connection = match Connection::establish(stream).await {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        log::warn!("Rejected connection from {}: {}", addr, e);
+                        return;
+                    }
+                };
+
+                // 更新连接状态
+                if let Ok(mut status) = CONNECTION_STATUS.lock() {
+                    *status = ConnectionStatus::Connected;
+                }
+
+                // 保存连接
+                if let Err(e) = set_connection(Some(connection)) {
+                    log::error!("Failed to save connection: {}", e);
+                }
+            });
         }
     });
 
     Ok(actual_port)
 }
 
-// 发送数据
-pub async fn send_data(data: &[u8]) -> Result<(), String> {
-    // 获取当前连接
-    let mut stream = {
-        let connection = CURRENT_CONNECTION.lock().map_err(|e| e.to_string())?;
+// 发送数据（作为一个完整的长度前缀帧）
+pub async fn send_data(data: &[u8]) -> Result<(), NearbySendError> {
+    // 获取当前连接的写端
+    let writer = {
+        let connection = CURRENT_CONNECTION.lock()?;
         match &*connection {
-            Some(stream) => stream.clone(),
-            None => return Err("No active connection".to_string()),
+            Some(connection) => connection.writer.clone(),
+            None => return Err(NearbySendError::NotConnected),
         }
     };
 
     // 发送数据
-    stream.write_all(data).await.map_err(|e| e.to_string())?;
+    let mut writer = writer.lock().await;
+    write_frame(&mut *writer, data).await?;
     log::info!("Sent {} bytes of data", data.len());
 
     Ok(())
 }
 
-// 接收数据
-pub async fn receive_data(max_size: usize) -> Result<Vec<u8>, String> {
-    // 获取当前连接
-    let mut stream = {
-        let connection = CURRENT_CONNECTION.lock().map_err(|e| e.to_string())?;
+// 接收数据（读取一个完整帧，超过max_size的帧视为错误）
+pub async fn receive_data(max_size: usize) -> Result<Vec<u8>, NearbySendError> {
+    // 获取当前连接的读端
+    let reader = {
+        let connection = CURRENT_CONNECTION.lock()?;
         match &*connection {
-            Some(stream) => stream.clone(),
-            None => return Err("No active connection".to_string()),
+            Some(connection) => connection.reader.clone(),
+            None => return Err(NearbySendError::NotConnected),
         }
     };
 
     // 接收数据
-    let mut buffer = vec![0u8; max_size];
-    let n = stream.read(&mut buffer).await.map_err(|e| e.to_string())?;
-    buffer.truncate(n);
+    let mut reader = reader.lock().await;
+    let buffer = read_frame(&mut *reader, max_size).await?;
 
-    log::info!("Received {} bytes of data", n);
+    log::info!("Received {} bytes of data", buffer.len());
 
     Ok(buffer)
 }
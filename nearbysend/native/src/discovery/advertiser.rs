@@ -0,0 +1,189 @@
+use crate::api::{get_device_id, get_device_name, get_device_type, DeviceType, NearbySendError};
+use crate::transfer::codec::PROTOCOL_VERSION;
+
+// 广播负载大小（协议版本1 + 设备类型1 + 短设备ID 8 + 名称哈希4）
+pub const ADVERTISEMENT_PAYLOAD_SIZE: usize = 14;
+
+// 短设备ID的字节数
+const SHORT_ID_SIZE: usize = 8;
+
+// 名称哈希的字节数
+const NAME_HASH_SIZE: usize = 4;
+
+// BLE广播中的服务数据（传统广播只有31字节，只放最必要的信息）
+#[derive(Clone, Debug, PartialEq)]
+pub struct AdvertisementPayload {
+    pub protocol_version: u8,
+    pub device_type: DeviceType,
+    // 设备ID哈希的前8字节
+    pub short_id: [u8; SHORT_ID_SIZE],
+    // 设备名称哈希的前4字节，用于发现改名和与其他来源合并
+    pub name_hash: [u8; NAME_HASH_SIZE],
+}
+
+impl AdvertisementPayload {
+    // 本机的广播负载
+    pub fn local() -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            device_type: get_device_type(),
+            short_id: short_id_bytes(&get_device_id()),
+            name_hash: name_hash(&get_device_name()),
+        }
+    }
+
+    // 编码为服务数据
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(ADVERTISEMENT_PAYLOAD_SIZE);
+        buffer.push(self.protocol_version);
+        buffer.push(device_type_code(&self.device_type));
+        buffer.extend_from_slice(&self.short_id);
+        buffer.extend_from_slice(&self.name_hash);
+        buffer
+    }
+
+    // 解码服务数据（多出的字节留给以后的版本扩展，直接忽略）
+    pub fn decode(bytes: &[u8]) -> Result<Self, NearbySendError> {
+        if bytes.len() < ADVERTISEMENT_PAYLOAD_SIZE {
+            return Err(NearbySendError::protocol(format!(
+                "Advertisement payload too short: {} bytes",
+                bytes.len()
+            )));
+        }
+
+        let mut short_id = [0u8; SHORT_ID_SIZE];
+        short_id.copy_from_slice(&bytes[2..2 + SHORT_ID_SIZE]);
+        let mut name_hash = [0u8; NAME_HASH_SIZE];
+        name_hash.copy_from_slice(&bytes[2 + SHORT_ID_SIZE..ADVERTISEMENT_PAYLOAD_SIZE]);
+
+        Ok(Self {
+            protocol_version: bytes[0],
+            device_type: device_type_from_code(bytes[1]),
+            short_id,
+            name_hash,
+        })
+    }
+
+    // 短设备ID的十六进制表示
+    pub fn short_id_hex(&self) -> String {
+        self.short_id.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+// 由完整设备ID计算短设备ID（其他来源带完整ID时用它与BLE发现的设备合并）
+pub fn short_device_id(device_id: &str) -> String {
+    short_id_bytes(device_id).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn short_id_bytes(device_id: &str) -> [u8; SHORT_ID_SIZE] {
+    let mut short_id = [0u8; SHORT_ID_SIZE];
+    short_id.copy_from_slice(&blake3::hash(device_id.as_bytes()).as_bytes()[..SHORT_ID_SIZE]);
+    short_id
+}
+
+// 设备名称的哈希（忽略大小写和首尾空白）
+pub fn name_hash(name: &str) -> [u8; NAME_HASH_SIZE] {
+    let mut hash = [0u8; NAME_HASH_SIZE];
+    hash.copy_from_slice(&blake3::hash(name.trim().to_lowercase().as_bytes()).as_bytes()[..NAME_HASH_SIZE]);
+    hash
+}
+
+// 设备类型在广播中的编码
+fn device_type_code(device_type: &DeviceType) -> u8 {
+    match device_type {
+        DeviceType::Unknown => 0,
+        DeviceType::Android => 1,
+        DeviceType::IOS => 2,
+        DeviceType::MacOS => 3,
+        DeviceType::Windows => 4,
+    }
+}
+
+fn device_type_from_code(code: u8) -> DeviceType {
+    match code {
+        1 => DeviceType::Android,
+        2 => DeviceType::IOS,
+        3 => DeviceType::MacOS,
+        4 => DeviceType::Windows,
+        _ => DeviceType::Unknown,
+    }
+}
+
+// 全局广播器（只有Linux通过BlueZ广播，其他平台由系统应用层负责）
+#[cfg(target_os = "linux")]
+lazy_static::lazy_static! {
+    static ref ADVERTISER: tokio::sync::Mutex<Option<crate::discovery::bluez::BluezAdvertiser<crate::discovery::bluez::DbusBluezBus>>> =
+        tokio::sync::Mutex::new(None);
+}
+
+// 开始通过BLE广播本机（已在广播时按最新的设备信息重新广播）
+pub async fn start_ble_advertising() -> Result<(), NearbySendError> {
+    #[cfg(target_os = "linux")]
+    {
+        use crate::discovery::bluez::{BluezAdvertiser, DbusBluezBus};
+
+        let mut advertiser = ADVERTISER.lock().await;
+        let advertiser = match &mut *advertiser {
+            Some(advertiser) => advertiser,
+            empty => empty.insert(BluezAdvertiser::new(DbusBluezBus::shared()?)),
+        };
+
+        advertiser.start(&AdvertisementPayload::local()).await
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        Err(NearbySendError::unsupported("BLE advertising is not supported on this platform"))
+    }
+}
+
+// 停止BLE广播
+pub async fn stop_ble_advertising() -> Result<(), NearbySendError> {
+    #[cfg(target_os = "linux")]
+    {
+        if let Some(advertiser) = ADVERTISER.lock().await.as_ref() {
+            advertiser.stop().await?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payload_round_trip() {
+        let payload = AdvertisementPayload::local();
+        let encoded = payload.encode();
+        assert_eq!(encoded.len(), ADVERTISEMENT_PAYLOAD_SIZE);
+        assert_eq!(AdvertisementPayload::decode(&encoded).unwrap(), payload);
+        assert_eq!(payload.short_id_hex(), short_device_id(&get_device_id()));
+
+        // 以后的版本可以在末尾追加字段
+        let mut extended = encoded.clone();
+        extended.push(0xff);
+        assert_eq!(AdvertisementPayload::decode(&extended).unwrap(), payload);
+
+        assert!(AdvertisementPayload::decode(&encoded[..ADVERTISEMENT_PAYLOAD_SIZE - 1]).is_err());
+    }
+
+    #[test]
+    fn encodes_device_type() {
+        let payload = AdvertisementPayload {
+            protocol_version: 3,
+            device_type: DeviceType::Android,
+            short_id: [1, 2, 3, 4, 5, 6, 7, 8],
+            name_hash: name_hash("Pixel"),
+        };
+        let encoded = payload.encode();
+        assert_eq!(&encoded[..3], &[3, 1, 1]);
+        assert_eq!(name_hash(" pixel "), payload.name_hash);
+
+        // 未知的类型编码按Unknown处理
+        let mut unknown = encoded;
+        unknown[1] = 200;
+        assert_eq!(AdvertisementPayload::decode(&unknown).unwrap().device_type, DeviceType::Unknown);
+    }
+}
@@ -1,115 +1,373 @@
-use btleplug::api::{Central, Manager as _, Peripheral as _, ScanFilter};
+use crate::api::{BleAdapterInfo, BleScanConfig, DeviceTransport, DeviceType, NearbySendError};
+use crate::discovery::advertiser::AdvertisementPayload;
+use crate::discovery::manager::{device_expiry, is_expired, remove_expired, report_lost, report_sighting, Sighting, EXPIRY_CHECK_INTERVAL};
+use btleplug::api::{Central, CentralState, Manager as _, Peripheral as _, PeripheralProperties, ScanFilter};
 use btleplug::platform::{Adapter, Manager, Peripheral};
 use futures::stream::StreamExt;
-use std::error::Error;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
-use tokio::sync::mpsc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch, Mutex as AsyncMutex};
+use tokio::task::JoinHandle;
 use tokio::time;
 use uuid::Uuid;
 
 // NearbySend服务UUID
-const NEARBYSEND_SERVICE_UUID: &str = "00001234-0000-1000-8000-00805f9b34fb";
+pub const NEARBYSEND_SERVICE_UUID: &str = "00001234-0000-1000-8000-00805f9b34fb";
 
 // BLE设备结构体
 #[derive(Clone, Debug)]
 pub struct BleDevice {
     pub id: String,
+    // 广播中没有名称时为空
     pub name: String,
+    // 广播的短设备ID
+    pub device_id: String,
+    pub device_type: DeviceType,
+    pub protocol_version: u8,
     pub peripheral: Arc<Peripheral>,
+    // 最近一次广播的信号强度
+    pub rssi: Option<i16>,
+    // 最后一次收到广播的时间
+    pub last_seen: Instant,
 }
 
-// 全局设备列表
-lazy_static::lazy_static! {
-    static ref DISCOVERED_DEVICES: Arc<Mutex<Vec<BleDevice>>> = Arc::new(Mutex::new(Vec::new()));
-    static ref DISCOVERY_RUNNING: Arc<Mutex<bool>> = Arc::new(Mutex::new(false));
+// 一次正在运行的扫描，停止信号由句柄持有（句柄被丢弃时扫描也会结束）
+pub struct DiscoveryHandle {
+    stop: watch::Sender<bool>,
+    task: JoinHandle<()>,
 }
 
-// 启动BLE设备发现
-pub async fn start_ble_discovery() -> Result<(), String> {
-    // 检查是否已经在运行
+impl DiscoveryHandle {
+    // 在后台运行扫描，扫描函数收到停止信号后应尽快返回
+    pub fn spawn<F, Fut>(scan: F) -> Self
+    where
+        F: FnOnce(watch::Receiver<bool>) -> Fut,
+        Fut: Future<Output = Result<(), NearbySendError>> + Send + 'static,
     {
-        let mut running = DISCOVERY_RUNNING.lock().map_err(|e| e.to_string())?;
-        if *running {
-            return Ok(());
-        }
-        *running = true;
+        let (stop, stopped) = watch::channel(false);
+        let scan = scan(stopped);
+        let task = tokio::spawn(async move {
+            if let Err(e) = scan.await {
+                log::error!("BLE discovery error: {}", e);
+            }
+        });
+
+        Self { stop, task }
     }
 
-    // 清空设备列表
-    {
-        let mut devices = DISCOVERED_DEVICES.lock().map_err(|e| e.to_string())?;
-        devices.clear();
+    // 扫描是否还在运行（扫描超时后自行结束）
+    pub fn is_running(&self) -> bool {
+        !self.task.is_finished()
     }
 
-    // 创建通道用于接收发现的设备
-    let (tx, mut rx) = mpsc::channel(10);
+    // 发出停止信号，并等待扫描真正结束
+    pub async fn stop(self) {
+        let _ = self.stop.send(true);
+        if let Err(e) = self.task.await {
+            log::error!("BLE discovery task failed: {}", e);
+        }
+    }
+}
 
-    // 在后台运行设备发现
-    tokio::spawn(async move {
-        if let Err(e) = discover_devices(tx).await {
-            log::error!("BLE discovery error: {:?}", e);
+// 保证同一时间最多只有一个扫描（启动和停止互斥执行）
+#[derive(Default)]
+pub struct DiscoveryController {
+    current: AsyncMutex<Option<DiscoveryHandle>>,
+}
+
+impl DiscoveryController {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // 没有扫描在运行时通过launch启动新的扫描，返回是否启动了新的扫描
+    pub async fn start<L>(&self, launch: L) -> Result<bool, NearbySendError>
+    where
+        L: Future<Output = Result<DiscoveryHandle, NearbySendError>>,
+    {
+        let mut current = self.current.lock().await;
+        if current.as_ref().is_some_and(|handle| handle.is_running()) {
+            return Ok(false);
         }
 
-        // 发现结束后，更新状态
-        if let Ok(mut running) = DISCOVERY_RUNNING.lock() {
-            *running = false;
+        *current = Some(launch.await?);
+        Ok(true)
+    }
+
+    // 停止当前的扫描，返回后扫描已经结束（没有扫描时直接返回）
+    pub async fn stop(&self) {
+        // 等待扫描结束期间持有锁，避免新的扫描与正在停止的扫描重叠
+        let mut current = self.current.lock().await;
+        if let Some(handle) = current.take() {
+            handle.stop().await;
         }
-    });
+    }
+
+    // 是否有扫描在运行
+    pub async fn is_running(&self) -> bool {
+        self.current.lock().await.as_ref().is_some_and(|handle| handle.is_running())
+    }
+}
+
+// 全局设备列表
+lazy_static::lazy_static! {
+    static ref DISCOVERED_DEVICES: Arc<Mutex<Vec<BleDevice>>> = Arc::new(Mutex::new(Vec::new()));
+    static ref DISCOVERY: DiscoveryController = DiscoveryController::new();
+}
+
+// 扫描代数，每次启动扫描时递增
+static SCAN_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+// 启动BLE设备发现（默认扫描30秒）
+pub async fn start_ble_discovery() -> Result<(), NearbySendError> {
+    start_ble_discovery_with_config(BleScanConfig::default()).await
+}
+
+// 按配置启动BLE设备发现（已在扫描时直接返回）
+pub async fn start_ble_discovery_with_config(config: BleScanConfig) -> Result<(), NearbySendError> {
+    validate_scan_config(&config)?;
+
+    DISCOVERY
+        .start(async move {
+            // 先打开适配器，蓝牙关闭等错误直接返回给调用者
+            let adapter = open_adapter(config.adapter.as_deref()).await?;
+
+            // 创建通道用于接收发现的设备
+            let (tx, rx) = mpsc::channel(10);
+            process_devices(rx);
+
+            Ok(DiscoveryHandle::spawn(move |stopped| discover_devices(adapter, config, tx, stopped)))
+        })
+        .await?;
+
+    Ok(())
+}
+
+// 在后台处理发现的设备，并定期移除过期的设备
+fn process_devices(mut rx: mpsc::Receiver<BleDevice>) {
+    let generation = SCAN_GENERATION.fetch_add(1, Ordering::SeqCst) + 1;
 
-    // 处理发现的设备
     tokio::spawn(async move {
-        while let Some(device) = rx.recv().await {
-            if let Ok(mut devices) = DISCOVERED_DEVICES.lock() {
-                // 检查设备是否已存在
-                if !devices.iter().any(|d| d.id == device.id) {
-                    devices.push(device);
+        let mut check = time::interval(EXPIRY_CHECK_INTERVAL);
+        let mut scanning = true;
+
+        loop {
+            tokio::select! {
+                device = rx.recv(), if scanning => match device {
+                    Some(device) => record_device(device),
+                    None => scanning = false,
+                },
+                _ = check.tick() => {
+                    // 扫描结束后，等所有设备都过期再退出；新的扫描启动后由新的任务接管
+                    if !scanning && SCAN_GENERATION.load(Ordering::SeqCst) != generation {
+                        break;
+                    }
+
+                    expire_devices();
+
+                    let empty = DISCOVERED_DEVICES.lock().map(|devices| devices.is_empty()).unwrap_or(true);
+                    if !scanning && empty {
+                        break;
+                    }
                 }
             }
         }
     });
+}
 
-    Ok(())
+// 记录收到的广播：更新设备列表并报告给设备发现管理器
+fn record_device(device: BleDevice) {
+    let sighting = Sighting {
+        device_id: Some(device.device_id.clone()),
+        name: device.name.clone(),
+        device_type: device.device_type.clone(),
+        transport: DeviceTransport::Ble {
+            peripheral_id: device.id.clone(),
+        },
+        rssi: device.rssi,
+    };
+    if let Err(e) = report_sighting(&format!("ble:{}", device.id), sighting) {
+        log::error!("Failed to report BLE device: {}", e);
+    }
+
+    if let Ok(mut devices) = DISCOVERED_DEVICES.lock() {
+        match devices.iter_mut().find(|d| d.id == device.id) {
+            Some(existing) => *existing = device,
+            None => devices.push(device),
+        }
+    }
 }
 
-// 停止BLE设备发现
-pub fn stop_ble_discovery() -> Result<(), String> {
-    let mut running = DISCOVERY_RUNNING.lock().map_err(|e| e.to_string())?;
-    *running = false;
+// 移除超过过期时间没有收到广播的设备
+fn expire_devices() {
+    let expiry = match device_expiry() {
+        Ok(expiry) => expiry,
+        Err(e) => {
+            log::error!("Failed to get device expiry: {}", e);
+            return;
+        }
+    };
+
+    let expired = match DISCOVERED_DEVICES.lock() {
+        Ok(mut devices) => remove_expired(&mut devices, Instant::now(), |d: &BleDevice, now| is_expired(d.last_seen, now, expiry)),
+        Err(e) => {
+            log::error!("Failed to lock BLE device list: {}", e);
+            return;
+        }
+    };
+
+    for device in expired {
+        log::info!("BLE device {} expired", device.id);
+        if let Err(e) = report_lost(&format!("ble:{}", device.id)) {
+            log::error!("Failed to report lost BLE device: {}", e);
+        }
+    }
+}
+
+// 停止BLE设备发现（返回时扫描已经停止）
+pub async fn stop_ble_discovery() -> Result<(), NearbySendError> {
+    DISCOVERY.stop().await;
     Ok(())
 }
 
 // 获取已发现的设备列表
-pub fn get_discovered_devices() -> Result<Vec<BleDevice>, String> {
-    let devices = DISCOVERED_DEVICES.lock().map_err(|e| e.to_string())?;
+pub fn get_discovered_devices() -> Result<Vec<BleDevice>, NearbySendError> {
+    let devices = DISCOVERED_DEVICES.lock()?;
     Ok(devices.clone())
 }
 
-// 内部设备发现函数
-async fn discover_devices(tx: mpsc::Sender<BleDevice>) -> Result<(), Box<dyn Error>> {
-    // 获取BLE适配器
+// 检查扫描配置
+fn validate_scan_config(config: &BleScanConfig) -> Result<(), NearbySendError> {
+    if config.duration_seconds == Some(0) {
+        return Err(NearbySendError::invalid("Scan duration must be at least 1 second"));
+    }
+    if config.duty_cycle.as_ref().is_some_and(|cycle| cycle.scan_ms == 0) {
+        return Err(NearbySendError::invalid("Duty cycle scan time must be greater than 0"));
+    }
+    Ok(())
+}
+
+// 列出所有蓝牙适配器
+pub async fn list_ble_adapters() -> Result<Vec<BleAdapterInfo>, NearbySendError> {
     let manager = Manager::new().await?;
     let adapters = manager.adapters().await?;
-    
+    describe_adapters(&adapters).await
+}
+
+// 读取适配器信息
+async fn describe_adapters(adapters: &[Adapter]) -> Result<Vec<BleAdapterInfo>, NearbySendError> {
+    let addresses = adapter_addresses().await;
+
+    let mut infos = Vec::with_capacity(adapters.len());
+    for adapter in adapters {
+        let name = adapter.adapter_info().await?;
+        let id = name.split_whitespace().next().unwrap_or_default().to_string();
+        infos.push(BleAdapterInfo {
+            address: addresses.get(&id).cloned(),
+            id,
+            name,
+            // 无法获取状态的平台按已开启处理，由扫描时的错误说明原因
+            powered: adapter.adapter_state().await? != CentralState::PoweredOff,
+        });
+    }
+    Ok(infos)
+}
+
+// 适配器名称到蓝牙地址的映射（btleplug不提供地址，Linux上从BlueZ读取）
+async fn adapter_addresses() -> HashMap<String, String> {
+    #[cfg(target_os = "linux")]
+    {
+        let addresses = match crate::discovery::bluez::DbusBluezBus::shared() {
+            Ok(bus) => bus.adapter_addresses().await,
+            Err(e) => Err(e),
+        };
+        match addresses {
+            Ok(addresses) => return addresses,
+            Err(e) => log::warn!("Failed to read Bluetooth adapter addresses: {}", e),
+        }
+    }
+
+    HashMap::new()
+}
+
+// 按名称或地址选择适配器，没有指定时优先选择已开启的适配器
+pub fn select_adapter(adapters: &[BleAdapterInfo], selector: Option<&str>) -> Result<usize, NearbySendError> {
     if adapters.is_empty() {
-        return Err("No Bluetooth adapters found".into());
+        return Err(NearbySendError::not_found("No Bluetooth adapters found"));
+    }
+
+    match selector {
+        Some(selector) => adapters
+            .iter()
+            .position(|adapter| {
+                adapter.id.eq_ignore_ascii_case(selector)
+                    || adapter.name.eq_ignore_ascii_case(selector)
+                    || adapter.address.as_deref().is_some_and(|address| address.eq_ignore_ascii_case(selector))
+            })
+            .ok_or_else(|| NearbySendError::not_found(format!("Bluetooth adapter {} not found", selector))),
+        None => Ok(adapters.iter().position(|adapter| adapter.powered).unwrap_or(0)),
+    }
+}
+
+// 打开用于扫描的适配器
+async fn open_adapter(selector: Option<&str>) -> Result<Adapter, NearbySendError> {
+    let manager = Manager::new().await?;
+    let mut adapters = manager.adapters().await?;
+    let infos = describe_adapters(&adapters).await?;
+
+    let index = select_adapter(&infos, selector)?;
+    if !infos[index].powered {
+        return Err(NearbySendError::BluetoothOff);
     }
+
+    log::info!("Using Bluetooth adapter {}", infos[index].name);
+    Ok(adapters.swap_remove(index))
+}
+
+// 内部设备发现函数
+async fn discover_devices(
+    adapter: Adapter,
+    config: BleScanConfig,
+    tx: mpsc::Sender<BleDevice>,
+    mut stopped: watch::Receiver<bool>,
+) -> Result<(), NearbySendError> {
+    // 设置服务UUID
+    let service_uuid = Uuid::parse_str(NEARBYSEND_SERVICE_UUID).map_err(|e| NearbySendError::internal(e.to_string()))?;
     
-    let adapter = adapters.into_iter().next().unwrap();
+    // 只扫描广播了NearbySend服务的设备
+    let filter = ScanFilter {
+        services: vec![service_uuid],
+    };
+    
+    // 监听发现的设备
+    let mut events = adapter.events().await?;
     
     // 开始扫描
-    adapter.start_scan(ScanFilter::default()).await?;
+    adapter.start_scan(filter.clone()).await?;
     log::info!("BLE scanning started");
+    let mut scanning = true;
     
-    // 设置服务UUID
-    let service_uuid = Uuid::parse_str(NEARBYSEND_SERVICE_UUID)?;
+    // 设置超时（持续扫描时不超时）
+    let timeout = async {
+        match config.duration_seconds {
+            Some(seconds) => time::sleep(Duration::from_secs(seconds)).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(timeout);
     
-    // 监听发现的设备
-    let mut events = adapter.events().await?;
+    // 收到停止信号（或句柄被丢弃）时结束扫描
+    let stop_requested = async move {
+        let _ = stopped.wait_for(|stop| *stop).await;
+    };
+    tokio::pin!(stop_requested);
     
-    // 设置超时
-    let timeout = time::sleep(Duration::from_secs(30));
-    tokio::pin!(timeout);
+    // 间歇扫描时在扫描和暂停之间切换
+    let toggle = time::sleep(config.duty_cycle.as_ref().map(|cycle| Duration::from_millis(cycle.scan_ms)).unwrap_or_default());
+    tokio::pin!(toggle);
     
     loop {
         tokio::select! {
@@ -117,25 +375,45 @@ async fn discover_devices(tx: mpsc::Sender<BleDevice>) -> Result<(), Box<dyn Err
                 log::info!("BLE scanning timeout");
                 break;
             }
+            _ = &mut toggle, if config.duty_cycle.is_some() => {
+                let Some(cycle) = &config.duty_cycle else { continue };
+                let next = if scanning {
+                    adapter.stop_scan().await?;
+                    cycle.pause_ms
+                } else {
+                    adapter.start_scan(filter.clone()).await?;
+                    cycle.scan_ms
+                };
+                scanning = !scanning;
+                toggle.as_mut().reset(time::Instant::now() + Duration::from_millis(next));
+            }
             event = events.next() => {
-                if let Some(event) = event {
-                    if let btleplug::api::CentralEvent::DeviceDiscovered(id) = event {
-                        if let Ok(peripheral) = adapter.peripheral(&id).await {
-                            if let Ok(properties) = peripheral.properties().await {
-                                if let Some(properties) = properties {
-                                    if let Some(name) = properties.local_name {
-                                        // 创建设备对象
-                                        let device = BleDevice {
-                                            id: id.to_string(),
-                                            name,
-                                            peripheral: Arc::new(peripheral),
-                                        };
-                                        
-                                        // 发送到通道
-                                        if tx.send(device).await.is_err() {
-                                            log::error!("Failed to send device to channel");
-                                        }
-                                    }
+                // 事件流结束说明适配器已不可用（例如蓝牙被关闭），无法再收到广播
+                let Some(event) = event else {
+                    log::warn!("BLE event stream ended, stopping scan");
+                    break;
+                };
+                // 设备的每次广播都会产生更新事件，用于刷新信号强度和最后发现时间
+                if let btleplug::api::CentralEvent::DeviceDiscovered(id) | btleplug::api::CentralEvent::DeviceUpdated(id) = event {
+                    if let Ok(peripheral) = adapter.peripheral(&id).await {
+                        if let Ok(Some(properties)) = peripheral.properties().await {
+                            // 部分平台不支持按服务过滤扫描，这里再检查一次服务数据
+                            if let Some(payload) = parse_advertisement(&properties, &service_uuid) {
+                                // 创建设备对象
+                                let device = BleDevice {
+                                    id: id.to_string(),
+                                    name: properties.local_name.unwrap_or_default(),
+                                    device_id: payload.short_id_hex(),
+                                    device_type: payload.device_type,
+                                    protocol_version: payload.protocol_version,
+                                    peripheral: Arc::new(peripheral),
+                                    rssi: properties.rssi,
+                                    last_seen: Instant::now(),
+                                };
+                                
+                                // 发送到通道
+                                if tx.send(device).await.is_err() {
+                                    log::error!("Failed to send device to channel");
                                 }
                             }
                         }
@@ -143,16 +421,7 @@ async fn discover_devices(tx: mpsc::Sender<BleDevice>) -> Result<(), Box<dyn Err
                 }
             }
             // 检查是否应该停止
-            _ = async {
-                loop {
-                    if let Ok(running) = DISCOVERY_RUNNING.lock() {
-                        if !*running {
-                            return;
-                        }
-                    }
-                    time::sleep(Duration::from_millis(100)).await;
-                }
-            } => {
+            _ = &mut stop_requested => {
                 log::info!("BLE scanning stopped");
                 break;
             }
@@ -165,3 +434,184 @@ async fn discover_devices(tx: mpsc::Sender<BleDevice>) -> Result<(), Box<dyn Err
     
     Ok(())
 }
+
+
+// 解析广播中的NearbySend服务数据，不是NearbySend设备时返回None
+fn parse_advertisement(properties: &PeripheralProperties, service_uuid: &Uuid) -> Option<AdvertisementPayload> {
+    let data = properties.service_data.get(service_uuid)?;
+    match AdvertisementPayload::decode(data) {
+        Ok(payload) => Some(payload),
+        Err(e) => {
+            log::debug!("Ignoring BLE device {} with invalid service data: {}", properties.address, e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::BleDutyCycle;
+    use std::sync::atomic::AtomicUsize;
+
+    fn properties(service_data: HashMap<Uuid, Vec<u8>>) -> PeripheralProperties {
+        PeripheralProperties {
+            local_name: Some("Headphones".to_string()),
+            service_data,
+            ..PeripheralProperties::default()
+        }
+    }
+
+    fn adapter(id: &str, address: Option<&str>, powered: bool) -> BleAdapterInfo {
+        BleAdapterInfo {
+            id: id.to_string(),
+            name: format!("{} (usb:v1D6Bp0246d0540)", id),
+            address: address.map(|a| a.to_string()),
+            powered,
+        }
+    }
+
+    #[test]
+    fn selects_adapter() {
+        let adapters = vec![
+            adapter("hci0", Some("00:1A:7D:DA:71:13"), false),
+            adapter("hci1", Some("5C:F3:70:8B:12:01"), true),
+        ];
+
+        // 没有指定时选择已开启的适配器
+        assert_eq!(select_adapter(&adapters, None).unwrap(), 1);
+        assert_eq!(select_adapter(&adapters, Some("hci0")).unwrap(), 0);
+        assert_eq!(select_adapter(&adapters, Some("5c:f3:70:8b:12:01")).unwrap(), 1);
+        assert_eq!(select_adapter(&adapters, Some("HCI0 (usb:v1D6Bp0246d0540)")).unwrap(), 0);
+
+        assert!(matches!(select_adapter(&adapters, Some("hci2")), Err(NearbySendError::NotFound { .. })));
+        assert!(matches!(select_adapter(&[], None), Err(NearbySendError::NotFound { .. })));
+
+        // 都没有开启时仍然选择第一个，由调用者报告蓝牙已关闭
+        let off = vec![adapter("hci0", None, false)];
+        assert_eq!(select_adapter(&off, None).unwrap(), 0);
+    }
+
+    #[test]
+    fn validates_scan_config() {
+        assert!(validate_scan_config(&BleScanConfig::default()).is_ok());
+
+        let continuous = BleScanConfig {
+            duration_seconds: None,
+            duty_cycle: Some(BleDutyCycle { scan_ms: 2_000, pause_ms: 8_000 }),
+            adapter: Some("hci0".to_string()),
+        };
+        assert!(validate_scan_config(&continuous).is_ok());
+
+        let zero_duration = BleScanConfig {
+            duration_seconds: Some(0),
+            ..BleScanConfig::default()
+        };
+        assert!(validate_scan_config(&zero_duration).is_err());
+
+        let zero_scan = BleScanConfig {
+            duty_cycle: Some(BleDutyCycle { scan_ms: 0, pause_ms: 1_000 }),
+            ..BleScanConfig::default()
+        };
+        assert!(validate_scan_config(&zero_scan).is_err());
+    }
+
+    // 模拟扫描：记录同时运行的扫描数量和最大值
+    async fn fake_scan(active: Arc<AtomicUsize>, peak: Arc<AtomicUsize>) -> Result<DiscoveryHandle, NearbySendError> {
+        Ok(DiscoveryHandle::spawn(move |mut stopped| async move {
+            let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+            peak.fetch_max(now, Ordering::SeqCst);
+
+            let _ = stopped.wait_for(|stop| *stop).await;
+            // 模拟停止扫描需要一点时间
+            time::sleep(Duration::from_millis(1)).await;
+            active.fetch_sub(1, Ordering::SeqCst);
+            Ok(())
+        }))
+    }
+
+    #[tokio::test]
+    async fn start_and_stop_are_idempotent() {
+        let controller = DiscoveryController::new();
+        let active = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+
+        assert!(controller.start(fake_scan(active.clone(), peak.clone())).await.unwrap());
+        assert!(!controller.start(fake_scan(active.clone(), peak.clone())).await.unwrap());
+        assert!(controller.is_running().await);
+
+        controller.stop().await;
+        assert!(!controller.is_running().await);
+        assert_eq!(active.load(Ordering::SeqCst), 0);
+        controller.stop().await;
+
+        // 启动失败时不留下扫描
+        let failed = controller
+            .start(async { Err(NearbySendError::BluetoothOff) })
+            .await;
+        assert_eq!(failed.unwrap_err(), NearbySendError::BluetoothOff);
+        assert!(!controller.is_running().await);
+    }
+
+    #[tokio::test]
+    async fn restarts_after_scan_ends() {
+        let controller = DiscoveryController::new();
+        let finished = async { Ok(DiscoveryHandle::spawn(|_| async { Ok(()) })) };
+        assert!(controller.start(finished).await.unwrap());
+
+        // 扫描超时自行结束后可以再次启动
+        while controller.is_running().await {
+            tokio::task::yield_now().await;
+        }
+        let active = Arc::new(AtomicUsize::new(0));
+        assert!(controller.start(fake_scan(active.clone(), Arc::default())).await.unwrap());
+        controller.stop().await;
+        assert_eq!(active.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn rapid_toggling_never_overlaps() {
+        let controller = Arc::new(DiscoveryController::new());
+        let active = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+
+        let tasks: Vec<_> = (0..8)
+            .map(|i| {
+                let controller = controller.clone();
+                let active = active.clone();
+                let peak = peak.clone();
+                tokio::spawn(async move {
+                    for j in 0..50 {
+                        if (i + j) % 2 == 0 {
+                            controller.start(fake_scan(active.clone(), peak.clone())).await.unwrap();
+                        } else {
+                            controller.stop().await;
+                        }
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        controller.stop().await;
+        assert_eq!(peak.load(Ordering::SeqCst), 1);
+        assert_eq!(active.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn parses_nearbysend_service_data() {
+        let service_uuid = Uuid::parse_str(NEARBYSEND_SERVICE_UUID).unwrap();
+        let payload = AdvertisementPayload::local();
+
+        let parsed = parse_advertisement(&properties(HashMap::from([(service_uuid, payload.encode())])), &service_uuid);
+        assert_eq!(parsed, Some(payload.clone()));
+
+        // 其他服务的数据、没有服务数据和无效的服务数据都不是NearbySend设备
+        let other = Uuid::from_u128(0x0000180d_0000_1000_8000_00805f9b34fb);
+        assert_eq!(parse_advertisement(&properties(HashMap::from([(other, payload.encode())])), &service_uuid), None);
+        assert_eq!(parse_advertisement(&properties(HashMap::new()), &service_uuid), None);
+        assert_eq!(parse_advertisement(&properties(HashMap::from([(service_uuid, vec![2, 1])])), &service_uuid), None);
+    }
+}
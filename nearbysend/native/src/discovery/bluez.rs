@@ -0,0 +1,579 @@
+use crate::api::NearbySendError;
+use crate::discovery::advertiser::AdvertisementPayload;
+use crate::discovery::ble::NEARBYSEND_SERVICE_UUID;
+use std::collections::HashMap;
+use std::future::Future;
+use tokio::sync::Mutex;
+
+// BlueZ的LE广播管理接口（适配器对象上）
+pub const ADVERTISING_MANAGER_INTERFACE: &str = "org.bluez.LEAdvertisingManager1";
+
+// 广播对象需要实现的接口
+pub const ADVERTISEMENT_INTERFACE: &str = "org.bluez.LEAdvertisement1";
+
+// 适配器上注册GATT应用的接口
+pub const GATT_MANAGER_INTERFACE: &str = "org.bluez.GattManager1";
+
+pub const GATT_SERVICE_INTERFACE: &str = "org.bluez.GattService1";
+pub const GATT_CHARACTERISTIC_INTERFACE: &str = "org.bluez.GattCharacteristic1";
+
+// 本机广播对象的路径
+pub const ADVERTISEMENT_PATH: &str = "/org/nearbysend/advertisement0";
+
+// GATT应用及其服务、特征的路径
+pub const GATT_APPLICATION_PATH: &str = "/org/nearbysend/gatt";
+pub const GATT_SERVICE_PATH: &str = "/org/nearbysend/gatt/service0";
+pub const CONNECTION_INFO_PATH: &str = "/org/nearbysend/gatt/service0/char0";
+pub const CONNECTION_OFFER_PATH: &str = "/org/nearbysend/gatt/service0/char1";
+
+// 导出给BlueZ读取的广播属性
+#[derive(Clone, Debug, PartialEq)]
+pub struct AdvertisementProperties {
+    // 广播类型（可连接的外设广播为"peripheral"）
+    pub kind: String,
+    pub service_uuids: Vec<String>,
+    // 服务UUID到服务数据的映射
+    pub service_data: HashMap<String, Vec<u8>>,
+}
+
+impl AdvertisementProperties {
+    // 广播NearbySend服务UUID及其服务数据
+    pub fn for_payload(payload: &AdvertisementPayload) -> Self {
+        Self {
+            kind: "peripheral".to_string(),
+            service_uuids: vec![NEARBYSEND_SERVICE_UUID.to_string()],
+            service_data: HashMap::from([(NEARBYSEND_SERVICE_UUID.to_string(), payload.encode())]),
+        }
+    }
+}
+
+// 导出给BlueZ调用的对象
+#[derive(Clone, Debug, PartialEq)]
+pub enum BluezObject {
+    Advertisement(AdvertisementProperties),
+    // NearbySend GATT服务（特征值的读写由connection::gatt处理）
+    GattApplication,
+}
+
+// 广播和GATT服务用到的BlueZ D-Bus操作（测试时替换为模拟实现）
+pub trait BluezBus: Send + Sync {
+    // 同时支持LE广播和GATT服务的适配器对象路径
+    fn advertising_adapters(&self) -> impl Future<Output = Result<Vec<String>, NearbySendError>> + Send;
+
+    // 在本机连接上导出对象，供BlueZ读取属性和调用方法
+    fn export_object(&self, path: &str, object: BluezObject) -> Result<(), NearbySendError>;
+
+    // 取消导出对象
+    fn unexport_object(&self, path: &str);
+
+    // 调用适配器的RegisterApplication
+    fn register_application(&self, adapter: &str, path: &str) -> impl Future<Output = Result<(), NearbySendError>> + Send;
+
+    // 调用适配器的UnregisterApplication
+    fn unregister_application(&self, adapter: &str, path: &str) -> impl Future<Output = Result<(), NearbySendError>> + Send;
+
+    // 调用适配器的RegisterAdvertisement
+    fn register_advertisement(&self, adapter: &str, path: &str) -> impl Future<Output = Result<(), NearbySendError>> + Send;
+
+    // 调用适配器的UnregisterAdvertisement
+    fn unregister_advertisement(&self, adapter: &str, path: &str) -> impl Future<Output = Result<(), NearbySendError>> + Send;
+}
+
+// 通过BlueZ广播NearbySend服务，并提供交换连接信息的GATT服务
+pub struct BluezAdvertiser<B: BluezBus> {
+    bus: B,
+    // 正在广播时为注册广播的适配器路径
+    active_adapter: Mutex<Option<String>>,
+}
+
+impl<B: BluezBus> BluezAdvertiser<B> {
+    pub fn new(bus: B) -> Self {
+        Self {
+            bus,
+            active_adapter: Mutex::new(None),
+        }
+    }
+
+    // 开始广播（BlueZ只在注册时读取属性，已在广播时先注销再重新注册）
+    pub async fn start(&self, payload: &AdvertisementPayload) -> Result<(), NearbySendError> {
+        let mut active_adapter = self.active_adapter.lock().await;
+        if let Some(adapter) = active_adapter.take() {
+            self.withdraw(&adapter).await?;
+        }
+
+        let adapter = self
+            .bus
+            .advertising_adapters()
+            .await?
+            .into_iter()
+            .next()
+            .ok_or_else(|| NearbySendError::not_found("No Bluetooth adapter supports LE advertising"))?;
+
+        // 扫描端发现本机后马上会连接GATT服务，先注册服务再开始广播
+        self.bus.export_object(GATT_APPLICATION_PATH, BluezObject::GattApplication)?;
+        self.bus.export_object(
+            ADVERTISEMENT_PATH,
+            BluezObject::Advertisement(AdvertisementProperties::for_payload(payload)),
+        )?;
+        if let Err(e) = self.register(&adapter).await {
+            self.bus.unexport_object(ADVERTISEMENT_PATH);
+            self.bus.unexport_object(GATT_APPLICATION_PATH);
+            return Err(e);
+        }
+
+        log::info!("BLE advertising started on {}", adapter);
+        *active_adapter = Some(adapter);
+        Ok(())
+    }
+
+    // 停止广播（没有在广播时直接返回）
+    pub async fn stop(&self) -> Result<(), NearbySendError> {
+        match self.active_adapter.lock().await.take() {
+            Some(adapter) => {
+                self.withdraw(&adapter).await?;
+                log::info!("BLE advertising stopped on {}", adapter);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    // 是否正在广播
+    pub async fn is_advertising(&self) -> bool {
+        self.active_adapter.lock().await.is_some()
+    }
+
+    // 注册GATT应用和广播（广播注册失败时注销已注册的GATT应用）
+    async fn register(&self, adapter: &str) -> Result<(), NearbySendError> {
+        self.bus.register_application(adapter, GATT_APPLICATION_PATH).await?;
+        if let Err(e) = self.bus.register_advertisement(adapter, ADVERTISEMENT_PATH).await {
+            if let Err(e) = self.bus.unregister_application(adapter, GATT_APPLICATION_PATH).await {
+                log::warn!("Failed to unregister GATT application: {}", e);
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    // 注销并取消导出广播和GATT应用（注销失败时也取消导出，避免残留对象）
+    async fn withdraw(&self, adapter: &str) -> Result<(), NearbySendError> {
+        let advertisement = self.bus.unregister_advertisement(adapter, ADVERTISEMENT_PATH).await;
+        self.bus.unexport_object(ADVERTISEMENT_PATH);
+        let application = self.bus.unregister_application(adapter, GATT_APPLICATION_PATH).await;
+        self.bus.unexport_object(GATT_APPLICATION_PATH);
+        advertisement.and(application)
+    }
+}
+
+// 从BlueZ设备对象路径（如/org/bluez/hci0/dev_AA_BB_CC_DD_EE_FF）中取出蓝牙地址
+pub fn device_address(path: &str) -> Option<String> {
+    let address = path.rsplit('/').next()?.strip_prefix("dev_")?.replace('_', ":");
+    (address.len() == 17).then_some(address)
+}
+
+#[cfg(target_os = "linux")]
+pub use system::DbusBluezBus;
+
+// 通过系统总线访问BlueZ
+#[cfg(target_os = "linux")]
+mod system {
+    use super::*;
+    use crate::connection::gatt::{
+        handle_connection_offer, read_connection_info_value, CONNECTION_INFO_CHARACTERISTIC_UUID, CONNECTION_OFFER_CHARACTERISTIC_UUID,
+    };
+    use dbus::arg::{prop_cast, PropMap, RefArg, Variant};
+    use dbus::channel::{MatchingReceiver, Sender};
+    use dbus::message::MatchRule;
+    use dbus::nonblock::{Proxy, SyncConnection};
+    use dbus::strings::ErrorName;
+    use dbus::{Message, Path};
+    use std::ffi::CString;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    // BlueZ的总线名称
+    const BLUEZ_SERVICE: &str = "org.bluez";
+
+    const OBJECT_MANAGER_INTERFACE: &str = "org.freedesktop.DBus.ObjectManager";
+    const PROPERTIES_INTERFACE: &str = "org.freedesktop.DBus.Properties";
+
+    // D-Bus调用的超时时间
+    const CALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+    // 已导出的对象（路径到属性）
+    type ExportedObjects = Arc<std::sync::Mutex<HashMap<String, BluezObject>>>;
+
+    // 蓝牙适配器的接口
+    const ADAPTER_INTERFACE: &str = "org.bluez.Adapter1";
+
+    // 共享的系统总线连接
+    lazy_static::lazy_static! {
+        static ref SYSTEM_BUS: std::sync::Mutex<Option<DbusBluezBus>> = std::sync::Mutex::new(None);
+    }
+
+    #[derive(Clone)]
+    pub struct DbusBluezBus {
+        connection: Arc<SyncConnection>,
+        objects: ExportedObjects,
+    }
+
+    impl DbusBluezBus {
+        // 获取共享的连接（第一次使用时连接系统总线）
+        pub fn shared() -> Result<Self, NearbySendError> {
+            let mut bus = SYSTEM_BUS.lock()?;
+            match &*bus {
+                Some(bus) => Ok(bus.clone()),
+                None => {
+                    let connected = Self::connect()?;
+                    *bus = Some(connected.clone());
+                    Ok(connected)
+                }
+            }
+        }
+
+        // 连接系统总线，并开始处理BlueZ对导出对象的调用
+        fn connect() -> Result<Self, NearbySendError> {
+            let (resource, connection) = dbus_tokio::connection::new_system_sync()?;
+            tokio::spawn(async move {
+                let error = resource.await;
+                log::error!("Lost connection to D-Bus: {}", error);
+            });
+
+            let objects: ExportedObjects = Arc::default();
+            let exported = objects.clone();
+            connection.start_receive(
+                MatchRule::new_method_call(),
+                Box::new(move |message, connection| {
+                    handle_method_call(&exported, message, connection);
+                    true
+                }),
+            );
+
+            Ok(Self { connection, objects })
+        }
+
+        // 各适配器名称（如"hci0"）对应的蓝牙地址
+        pub async fn adapter_addresses(&self) -> Result<HashMap<String, String>, NearbySendError> {
+            let (objects,): (HashMap<Path<'static>, HashMap<String, PropMap>>,) = self
+                .proxy("/")
+                .method_call(OBJECT_MANAGER_INTERFACE, "GetManagedObjects", ())
+                .await?;
+
+            Ok(objects
+                .into_iter()
+                .filter_map(|(path, interfaces)| {
+                    let address = prop_cast::<String>(interfaces.get(ADAPTER_INTERFACE)?, "Address")?.clone();
+                    let id = path.rsplit('/').next()?.to_string();
+                    Some((id, address))
+                })
+                .collect())
+        }
+
+        fn proxy(&self, path: &str) -> Proxy<'static, Arc<SyncConnection>> {
+            Proxy::new(BLUEZ_SERVICE, path.to_string(), CALL_TIMEOUT, self.connection.clone())
+        }
+    }
+
+    impl BluezBus for DbusBluezBus {
+        async fn advertising_adapters(&self) -> Result<Vec<String>, NearbySendError> {
+            let (objects,): (HashMap<Path<'static>, HashMap<String, PropMap>>,) = self
+                .proxy("/")
+                .method_call(OBJECT_MANAGER_INTERFACE, "GetManagedObjects", ())
+                .await?;
+
+            let mut adapters: Vec<String> = objects
+                .into_iter()
+                .filter(|(_, interfaces)| {
+                    interfaces.contains_key(ADVERTISING_MANAGER_INTERFACE) && interfaces.contains_key(GATT_MANAGER_INTERFACE)
+                })
+                .map(|(path, _)| path.to_string())
+                .collect();
+            adapters.sort();
+            Ok(adapters)
+        }
+
+        fn export_object(&self, path: &str, object: BluezObject) -> Result<(), NearbySendError> {
+            self.objects.lock()?.insert(path.to_string(), object);
+            Ok(())
+        }
+
+        fn unexport_object(&self, path: &str) {
+            if let Ok(mut objects) = self.objects.lock() {
+                objects.remove(path);
+            }
+        }
+
+        async fn register_application(&self, adapter: &str, path: &str) -> Result<(), NearbySendError> {
+            self.proxy(adapter)
+                .method_call::<(), _, _, _>(GATT_MANAGER_INTERFACE, "RegisterApplication", (Path::from(path.to_string()), PropMap::new()))
+                .await
+                .map_err(NearbySendError::from)
+        }
+
+        async fn unregister_application(&self, adapter: &str, path: &str) -> Result<(), NearbySendError> {
+            self.proxy(adapter)
+                .method_call::<(), _, _, _>(GATT_MANAGER_INTERFACE, "UnregisterApplication", (Path::from(path.to_string()),))
+                .await
+                .map_err(NearbySendError::from)
+        }
+
+        async fn register_advertisement(&self, adapter: &str, path: &str) -> Result<(), NearbySendError> {
+            self.proxy(adapter)
+                .method_call::<(), _, _, _>(ADVERTISING_MANAGER_INTERFACE, "RegisterAdvertisement", (Path::from(path.to_string()), PropMap::new()))
+                .await
+                .map_err(NearbySendError::from)
+        }
+
+        async fn unregister_advertisement(&self, adapter: &str, path: &str) -> Result<(), NearbySendError> {
+            self.proxy(adapter)
+                .method_call::<(), _, _, _>(ADVERTISING_MANAGER_INTERFACE, "UnregisterAdvertisement", (Path::from(path.to_string()),))
+                .await
+                .map_err(NearbySendError::from)
+        }
+    }
+
+    // 广播属性转换为D-Bus属性表
+    fn property_map(properties: &AdvertisementProperties) -> PropMap {
+        let service_data: PropMap = properties
+            .service_data
+            .iter()
+            .map(|(uuid, data)| (uuid.clone(), Variant(Box::new(data.clone()) as Box<dyn RefArg>)))
+            .collect();
+
+        let mut map = PropMap::new();
+        map.insert("Type".to_string(), Variant(Box::new(properties.kind.clone())));
+        map.insert("ServiceUUIDs".to_string(), Variant(Box::new(properties.service_uuids.clone())));
+        map.insert("ServiceData".to_string(), Variant(Box::new(service_data)));
+        map
+    }
+
+    // GATT应用下的服务和特征（BlueZ注册应用时通过GetManagedObjects读取）
+    fn gatt_objects() -> HashMap<Path<'static>, HashMap<String, PropMap>> {
+        let mut service = PropMap::new();
+        service.insert("UUID".to_string(), Variant(Box::new(NEARBYSEND_SERVICE_UUID.to_string())));
+        service.insert("Primary".to_string(), Variant(Box::new(true)));
+
+        HashMap::from([
+            (Path::from(GATT_SERVICE_PATH), HashMap::from([(GATT_SERVICE_INTERFACE.to_string(), service)])),
+            // 连接信息含有热点密码，邀请会让本机加入对方的网络，都要求已配对并加密认证的连接
+            (Path::from(CONNECTION_INFO_PATH), characteristic(CONNECTION_INFO_CHARACTERISTIC_UUID, "encrypt-authenticated-read")),
+            (Path::from(CONNECTION_OFFER_PATH), characteristic(CONNECTION_OFFER_CHARACTERISTIC_UUID, "encrypt-authenticated-write")),
+        ])
+    }
+
+    fn characteristic(uuid: uuid::Uuid, flag: &str) -> HashMap<String, PropMap> {
+        let mut properties = PropMap::new();
+        properties.insert("UUID".to_string(), Variant(Box::new(uuid.to_string())));
+        properties.insert("Service".to_string(), Variant(Box::new(Path::from(GATT_SERVICE_PATH))));
+        properties.insert("Flags".to_string(), Variant(Box::new(vec![flag.to_string()])));
+        HashMap::from([(GATT_CHARACTERISTIC_INTERFACE.to_string(), properties)])
+    }
+
+    // 处理发给本连接的方法调用（BlueZ读取广播属性、释放广播、读写GATT特征）
+    fn handle_method_call(objects: &ExportedObjects, message: Message, connection: &SyncConnection) {
+        let path = message.path().map(|p| p.to_string()).unwrap_or_default();
+        let interface = message.interface().map(|i| i.to_string()).unwrap_or_default();
+        let member = message.member().map(|m| m.to_string()).unwrap_or_default();
+        let (object, gatt_exported) = match objects.lock() {
+            Ok(objects) => (objects.get(&path).cloned(), objects.contains_key(GATT_APPLICATION_PATH)),
+            Err(_) => (None, false),
+        };
+
+        let reply = match (object, path.as_str(), interface.as_str(), member.as_str()) {
+            (Some(BluezObject::Advertisement(properties)), _, PROPERTIES_INTERFACE, "GetAll") => {
+                message.method_return().append1(property_map(&properties))
+            }
+            (Some(BluezObject::Advertisement(properties)), _, PROPERTIES_INTERFACE, "Get") => {
+                let value = message
+                    .read2::<&str, &str>()
+                    .ok()
+                    .and_then(|(_, name)| property_map(&properties).remove(name));
+                match value {
+                    Some(value) => message.method_return().append1(value),
+                    None => error_reply(&message, "org.freedesktop.DBus.Error.InvalidArgs", "No such property"),
+                }
+            }
+            (Some(BluezObject::Advertisement(_)), _, ADVERTISEMENT_INTERFACE, "Release") => {
+                // BlueZ移除了广播（如适配器关闭），对象保留到停止广播时再取消导出
+                log::warn!("BlueZ released advertisement {}", path);
+                message.method_return()
+            }
+            (Some(BluezObject::GattApplication), _, OBJECT_MANAGER_INTERFACE, "GetManagedObjects") => {
+                message.method_return().append1(gatt_objects())
+            }
+            (None, CONNECTION_INFO_PATH, GATT_CHARACTERISTIC_INTERFACE, "ReadValue") if gatt_exported => {
+                // 长特征值分多次读取，每次从offset开始
+                let offset = message
+                    .read1::<PropMap>()
+                    .ok()
+                    .and_then(|options| prop_cast::<u16>(&options, "offset").copied())
+                    .unwrap_or(0) as usize;
+                match read_connection_info_value() {
+                    Ok(value) => message.method_return().append1(value.get(offset..).unwrap_or_default().to_vec()),
+                    Err(e) => error_reply(&message, "org.bluez.Error.Failed", &e.to_string()),
+                }
+            }
+            (None, CONNECTION_OFFER_PATH, GATT_CHARACTERISTIC_INTERFACE, "WriteValue") if gatt_exported => {
+                let result = message
+                    .read2::<Vec<u8>, PropMap>()
+                    .map_err(|e| NearbySendError::protocol(e.to_string()))
+                    .and_then(|(value, options)| {
+                        let central = prop_cast::<Path>(&options, "device").and_then(|device| device_address(device));
+                        handle_connection_offer(&value, central)
+                    });
+                match result {
+                    Ok(()) => message.method_return(),
+                    Err(e) => error_reply(&message, "org.bluez.Error.Failed", &e.to_string()),
+                }
+            }
+            _ => error_reply(&message, "org.freedesktop.DBus.Error.UnknownMethod", "Unknown method"),
+        };
+
+        if !message.get_no_reply() && connection.send(reply).is_err() {
+            log::error!("Failed to reply to D-Bus call {}.{}", interface, member);
+        }
+    }
+
+    fn error_reply(message: &Message, name: &'static str, text: &str) -> Message {
+        let text = CString::new(text).unwrap_or_default();
+        message.error(&ErrorName::from(name), &text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex as StdMutex;
+
+    // 记录调用的模拟BlueZ
+    #[derive(Default)]
+    struct MockBus {
+        adapters: Vec<String>,
+        fail_register: bool,
+        calls: StdMutex<Vec<String>>,
+        exported: StdMutex<HashMap<String, BluezObject>>,
+    }
+
+    impl MockBus {
+        fn with_adapters(adapters: &[&str]) -> Self {
+            Self {
+                adapters: adapters.iter().map(|a| a.to_string()).collect(),
+                ..Self::default()
+            }
+        }
+
+        fn record(&self, call: String) {
+            self.calls.lock().unwrap().push(call);
+        }
+    }
+
+    impl BluezBus for MockBus {
+        async fn advertising_adapters(&self) -> Result<Vec<String>, NearbySendError> {
+            Ok(self.adapters.clone())
+        }
+
+        fn export_object(&self, path: &str, object: BluezObject) -> Result<(), NearbySendError> {
+            self.record(format!("export {}", path));
+            self.exported.lock().unwrap().insert(path.to_string(), object);
+            Ok(())
+        }
+
+        fn unexport_object(&self, path: &str) {
+            self.record(format!("unexport {}", path));
+            self.exported.lock().unwrap().remove(path);
+        }
+
+        async fn register_application(&self, adapter: &str, path: &str) -> Result<(), NearbySendError> {
+            self.record(format!("register {} {}", adapter, path));
+            Ok(())
+        }
+
+        async fn unregister_application(&self, adapter: &str, path: &str) -> Result<(), NearbySendError> {
+            self.record(format!("unregister {} {}", adapter, path));
+            Ok(())
+        }
+
+        async fn register_advertisement(&self, adapter: &str, path: &str) -> Result<(), NearbySendError> {
+            self.record(format!("register {} {}", adapter, path));
+            if self.fail_register {
+                return Err(NearbySendError::Io {
+                    message: "org.bluez.Error.Failed".to_string(),
+                });
+            }
+            Ok(())
+        }
+
+        async fn unregister_advertisement(&self, adapter: &str, path: &str) -> Result<(), NearbySendError> {
+            self.record(format!("unregister {} {}", adapter, path));
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn advertises_service_data() {
+        let advertiser = BluezAdvertiser::new(MockBus::with_adapters(&["/org/bluez/hci0", "/org/bluez/hci1"]));
+        let payload = AdvertisementPayload::local();
+
+        advertiser.start(&payload).await.unwrap();
+        assert!(advertiser.is_advertising().await);
+
+        let exported = advertiser.bus.exported.lock().unwrap().clone();
+        assert_eq!(exported[GATT_APPLICATION_PATH], BluezObject::GattApplication);
+        let properties = match &exported[ADVERTISEMENT_PATH] {
+            BluezObject::Advertisement(properties) => properties.clone(),
+            other => panic!("unexpected object: {:?}", other),
+        };
+        assert_eq!(properties.kind, "peripheral");
+        assert_eq!(properties.service_uuids, vec![NEARBYSEND_SERVICE_UUID]);
+        let data = &properties.service_data[NEARBYSEND_SERVICE_UUID];
+        assert_eq!(AdvertisementPayload::decode(data).unwrap(), payload);
+
+        // 重复启动时重新注册，重复停止没有影响
+        advertiser.start(&payload).await.unwrap();
+        advertiser.stop().await.unwrap();
+        advertiser.stop().await.unwrap();
+        assert!(!advertiser.is_advertising().await);
+        assert!(advertiser.bus.exported.lock().unwrap().is_empty());
+
+        // 每次启动都先注册GATT应用再广播，停止时按相反顺序注销
+        let cycle = vec![
+            format!("export {}", GATT_APPLICATION_PATH),
+            format!("export {}", ADVERTISEMENT_PATH),
+            format!("register /org/bluez/hci0 {}", GATT_APPLICATION_PATH),
+            format!("register /org/bluez/hci0 {}", ADVERTISEMENT_PATH),
+            format!("unregister /org/bluez/hci0 {}", ADVERTISEMENT_PATH),
+            format!("unexport {}", ADVERTISEMENT_PATH),
+            format!("unregister /org/bluez/hci0 {}", GATT_APPLICATION_PATH),
+            format!("unexport {}", GATT_APPLICATION_PATH),
+        ];
+        assert_eq!(*advertiser.bus.calls.lock().unwrap(), [cycle.clone(), cycle].concat());
+    }
+
+    #[tokio::test]
+    async fn reports_missing_adapter_and_failed_registration() {
+        let advertiser = BluezAdvertiser::new(MockBus::default());
+        let error = advertiser.start(&AdvertisementPayload::local()).await.unwrap_err();
+        assert!(matches!(error, NearbySendError::NotFound { .. }));
+
+        // 广播注册失败时注销GATT应用，不留下导出的对象
+        let mut bus = MockBus::with_adapters(&["/org/bluez/hci0"]);
+        bus.fail_register = true;
+        let advertiser = BluezAdvertiser::new(bus);
+        assert!(advertiser.start(&AdvertisementPayload::local()).await.is_err());
+        assert!(!advertiser.is_advertising().await);
+        assert!(advertiser.bus.exported.lock().unwrap().is_empty());
+        assert!(advertiser
+            .bus
+            .calls
+            .lock()
+            .unwrap()
+            .contains(&format!("unregister /org/bluez/hci0 {}", GATT_APPLICATION_PATH)));
+    }
+
+    #[test]
+    fn parses_device_address_from_path() {
+        assert_eq!(device_address("/org/bluez/hci0/dev_AA_BB_CC_DD_EE_FF").as_deref(), Some("AA:BB:CC:DD:EE:FF"));
+        assert_eq!(device_address("/org/bluez/hci0"), None);
+        assert_eq!(device_address("/org/bluez/hci0/dev_AA_BB"), None);
+    }
+}
@@ -0,0 +1,363 @@
+use crate::api::{Device, DeviceTransport, DeviceType, DiscoveryEvent, NearbySendError};
+use flutter_rust_bridge::DartFnFuture;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+// 事件通道容量（订阅者处理过慢时丢弃最早的事件）
+const EVENT_CHANNEL_CAPACITY: usize = 64;
+
+// 默认的设备过期时间（BLE设备超过该时间没有收到广播即视为消失）
+const DEFAULT_DEVICE_EXPIRY: Duration = Duration::from_secs(30);
+
+// 检查过期设备的间隔
+pub const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+// 某个发现来源的一次发现记录
+#[derive(Clone, Debug)]
+pub struct Sighting {
+    // 对方广播的设备ID（没有时按名称合并）
+    pub device_id: Option<String>,
+    // 来源不提供名称时为空（如BLE广播没有放名称）
+    pub name: String,
+    pub device_type: DeviceType,
+    pub transport: DeviceTransport,
+    // BLE信号强度
+    pub rssi: Option<i16>,
+}
+
+// 合并各发现来源的设备列表
+#[derive(Default)]
+pub struct DiscoveryManager {
+    devices: HashMap<String, Device>,
+    // 来源ID（如"ble:外设ID"、"mdns:服务全名"）到设备ID和可达方式的映射
+    sources: HashMap<String, (String, DeviceTransport)>,
+}
+
+impl DiscoveryManager {
+    // 创建空的设备列表
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // 记录一次发现，返回产生的事件
+    pub fn observe(&mut self, source_id: &str, sighting: Sighting) -> Vec<DiscoveryEvent> {
+        let device_id = sighting.device_id.clone().unwrap_or_else(|| fallback_device_id(&sighting.name));
+        let mut events = Vec::new();
+
+        // 来源改为指向另一台设备（如改名或广播了设备ID）时，先从原设备移除
+        let previous = self
+            .sources
+            .insert(source_id.to_string(), (device_id.clone(), sighting.transport.clone()));
+        if let Some((previous_id, _)) = previous {
+            if previous_id != device_id {
+                events.extend(self.refresh(&previous_id, None));
+            }
+        }
+
+        events.extend(self.refresh(&device_id, Some(&sighting)));
+        events
+    }
+
+    // 某个来源失去了设备，返回产生的事件
+    pub fn lose(&mut self, source_id: &str) -> Vec<DiscoveryEvent> {
+        match self.sources.remove(source_id) {
+            Some((device_id, _)) => self.refresh(&device_id, None).into_iter().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    // 当前所有设备
+    pub fn devices(&self) -> Vec<Device> {
+        let mut devices: Vec<Device> = self.devices.values().cloned().collect();
+        devices.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| a.id.cmp(&b.id)));
+        devices
+    }
+
+    // 按当前的来源重新生成设备，并与之前的状态比较
+    fn refresh(&mut self, device_id: &str, sighting: Option<&Sighting>) -> Option<DiscoveryEvent> {
+        let mut transports: Vec<(&String, &DeviceTransport)> = self
+            .sources
+            .iter()
+            .filter(|(_, (id, _))| id == device_id)
+            .map(|(source_id, (_, transport))| (source_id, transport))
+            .collect();
+
+        if transports.is_empty() {
+            return self.devices.remove(device_id).map(|_| DiscoveryEvent::Lost {
+                device_id: device_id.to_string(),
+            });
+        }
+        transports.sort_by(|a, b| a.0.cmp(b.0));
+
+        let existing = self.devices.get(device_id);
+        let (name, mut device_type, is_connected, last_seen_ms, rssi) = match (sighting, existing) {
+            (Some(sighting), existing) => (
+                match (sighting.name.is_empty(), existing) {
+                    (true, Some(existing)) => existing.name.clone(),
+                    (true, None) => "Unknown Device".to_string(),
+                    (false, _) => sighting.name.clone(),
+                },
+                sighting.device_type.clone(),
+                existing.is_some_and(|d| d.is_connected),
+                unix_time_ms(),
+                sighting.rssi.or_else(|| existing.and_then(|d| d.rssi)),
+            ),
+            (None, Some(existing)) => (
+                existing.name.clone(),
+                existing.device_type.clone(),
+                existing.is_connected,
+                existing.last_seen_ms,
+                existing.rssi,
+            ),
+            (None, None) => return None,
+        };
+
+        // BLE广播可能不带设备类型，保留其他来源提供的类型
+        if device_type == DeviceType::Unknown {
+            if let Some(existing) = existing {
+                device_type = existing.device_type.clone();
+            }
+        }
+
+        let device = Device {
+            id: device_id.to_string(),
+            name,
+            device_type,
+            is_connected,
+            transports: transports.into_iter().map(|(_, transport)| transport.clone()).collect(),
+            last_seen_ms,
+            rssi,
+        };
+
+        // 只有信号强度和最后发现时间变化时不发送事件
+        let event = match existing {
+            None => Some(DiscoveryEvent::Added { device: device.clone() }),
+            Some(existing) if !same_listing(existing, &device) => Some(DiscoveryEvent::Updated { device: device.clone() }),
+            Some(_) => None,
+        };
+        self.devices.insert(device_id.to_string(), device);
+        event
+    }
+}
+
+// 两个设备在列表中显示的内容是否相同
+fn same_listing(a: &Device, b: &Device) -> bool {
+    a.name == b.name && a.device_type == b.device_type && a.is_connected == b.is_connected && a.transports == b.transports
+}
+
+// 当前的Unix时间（毫秒）
+fn unix_time_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+// 没有广播设备ID时按名称生成稳定的ID
+fn fallback_device_id(name: &str) -> String {
+    let hash = blake3::hash(name.trim().to_lowercase().as_bytes());
+    format!("name-{}", &hash.to_hex()[..16])
+}
+
+// 在now时距最后发现是否已超过过期时间（刚好到期的仍保留）
+pub fn is_expired(last_seen: Instant, now: Instant, expiry: Duration) -> bool {
+    now.saturating_duration_since(last_seen) > expiry
+}
+
+// 从列表中移除在now时已过期的记录，返回被移除的记录
+pub fn remove_expired<T>(records: &mut Vec<T>, now: Instant, is_expired_at: impl Fn(&T, Instant) -> bool) -> Vec<T> {
+    let (expired, kept) = records.drain(..).partition(|record| is_expired_at(record, now));
+    *records = kept;
+    expired
+}
+// 全局设备列表和事件通道
+lazy_static::lazy_static! {
+    static ref DISCOVERY_MANAGER: Arc<Mutex<DiscoveryManager>> = Arc::new(Mutex::new(DiscoveryManager::new()));
+    static ref DISCOVERY_EVENTS: broadcast::Sender<DiscoveryEvent> = broadcast::channel(EVENT_CHANNEL_CAPACITY).0;
+    static ref DEVICE_EXPIRY: Arc<Mutex<Duration>> = Arc::new(Mutex::new(DEFAULT_DEVICE_EXPIRY));
+}
+
+// 设置设备过期时间（秒）
+pub fn set_device_expiry(seconds: u64) -> Result<(), NearbySendError> {
+    if seconds == 0 {
+        return Err(NearbySendError::invalid("Device expiry must be at least 1 second"));
+    }
+
+    *DEVICE_EXPIRY.lock()? = Duration::from_secs(seconds);
+    Ok(())
+}
+
+// 获取设备过期时间
+pub fn device_expiry() -> Result<Duration, NearbySendError> {
+    Ok(*DEVICE_EXPIRY.lock()?)
+}
+
+// 发现来源报告发现了设备
+pub fn report_sighting(source_id: &str, sighting: Sighting) -> Result<(), NearbySendError> {
+    let events = DISCOVERY_MANAGER.lock()?.observe(source_id, sighting);
+    emit_discovery_events(events);
+    Ok(())
+}
+
+// 发现来源报告设备已消失
+pub fn report_lost(source_id: &str) -> Result<(), NearbySendError> {
+    let events = DISCOVERY_MANAGER.lock()?.lose(source_id);
+    emit_discovery_events(events);
+    Ok(())
+}
+
+// 发送设备发现事件（没有订阅者时直接丢弃）
+fn emit_discovery_events(events: Vec<DiscoveryEvent>) {
+    for event in events {
+        let _ = DISCOVERY_EVENTS.send(event);
+    }
+}
+
+// 获取合并后的设备列表
+pub fn get_devices() -> Result<Vec<Device>, NearbySendError> {
+    Ok(DISCOVERY_MANAGER.lock()?.devices())
+}
+
+// 订阅设备发现事件
+pub fn subscribe_discovery_events() -> broadcast::Receiver<DiscoveryEvent> {
+    DISCOVERY_EVENTS.subscribe()
+}
+
+// 将设备发现事件持续推送给Flutter端的回调
+pub async fn listen_discovery_events(on_event: impl Fn(DiscoveryEvent) -> DartFnFuture<()>) -> Result<(), NearbySendError> {
+    let mut events = subscribe_discovery_events();
+
+    loop {
+        match events.recv().await {
+            Ok(event) => on_event(event).await,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                log::warn!("Discovery event listener lagged, skipped {} events", skipped);
+            }
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ble(name: &str, peripheral_id: &str) -> Sighting {
+        Sighting {
+            device_id: None,
+            name: name.to_string(),
+            device_type: DeviceType::Unknown,
+            transport: DeviceTransport::Ble {
+                peripheral_id: peripheral_id.to_string(),
+            },
+            rssi: Some(-60),
+        }
+    }
+
+    fn lan(name: &str, port: u16) -> Sighting {
+        Sighting {
+            device_id: None,
+            name: name.to_string(),
+            device_type: DeviceType::MacOS,
+            transport: DeviceTransport::Lan {
+                ip_address: "192.168.1.20".to_string(),
+                port,
+            },
+            rssi: None,
+        }
+    }
+
+    #[test]
+    fn merges_sources_into_one_device() {
+        let mut manager = DiscoveryManager::new();
+
+        let events = manager.observe("ble:AA", ble("Laptop", "AA"));
+        let id = match &events[..] {
+            [DiscoveryEvent::Added { device }] => device.id.clone(),
+            other => panic!("unexpected events: {:?}", other),
+        };
+
+        // 同名设备通过mDNS出现，合并为同一设备并补充类型
+        let events = manager.observe("mdns:Laptop", lan("Laptop", 4000));
+        match &events[..] {
+            [DiscoveryEvent::Updated { device }] => {
+                assert_eq!(device.id, id);
+                assert_eq!(device.device_type, DeviceType::MacOS);
+                assert_eq!(device.transports.len(), 2);
+            }
+            other => panic!("unexpected events: {:?}", other),
+        }
+
+        // 重复的发现和信号强度变化不产生事件，BLE不会覆盖已知的类型
+        let mut weaker = ble("Laptop", "AA");
+        weaker.rssi = Some(-80);
+        assert!(manager.observe("ble:AA", weaker).is_empty());
+        assert_eq!(manager.devices()[0].device_type, DeviceType::MacOS);
+        assert_eq!(manager.devices()[0].rssi, Some(-80));
+
+        // 没有信号强度的来源保留BLE的信号强度
+        assert!(manager.observe("mdns:Laptop", lan("Laptop", 4000)).is_empty());
+        assert_eq!(manager.devices()[0].rssi, Some(-80));
+
+        // 端口变化产生更新事件
+        assert!(matches!(&manager.observe("mdns:Laptop", lan("Laptop", 4001))[..], [DiscoveryEvent::Updated { .. }]));
+    }
+
+    #[test]
+    fn reports_lost_after_last_source() {
+        let mut manager = DiscoveryManager::new();
+        manager.observe("ble:AA", ble("Phone", "AA"));
+        manager.observe("mdns:Phone", lan("Phone", 4000));
+
+        assert!(matches!(&manager.lose("ble:AA")[..], [DiscoveryEvent::Updated { device }] if device.transports.len() == 1));
+        assert!(matches!(&manager.lose("mdns:Phone")[..], [DiscoveryEvent::Lost { .. }]));
+        assert!(manager.lose("mdns:Phone").is_empty());
+        assert!(manager.devices().is_empty());
+    }
+
+    #[test]
+    fn expires_records_after_expiry() {
+        let expiry = Duration::from_secs(30);
+        let start = Instant::now();
+        let mut manager = DiscoveryManager::new();
+        manager.observe("ble:AA", ble("Phone", "AA"));
+        manager.observe("ble:BB", ble("Watch", "BB"));
+
+        // (来源ID, 最后发现时间)
+        let mut records = vec![("ble:AA", start), ("ble:BB", start + Duration::from_secs(10))];
+        let expired_at = |record: &(&str, Instant), now| is_expired(record.1, now, expiry);
+
+        // 刚好到期的记录仍保留
+        assert!(remove_expired(&mut records, start + expiry, expired_at).is_empty());
+        assert_eq!(records.len(), 2);
+
+        // 超过过期时间后移除，来源失去设备时发出Lost事件
+        let expired = remove_expired(&mut records, start + expiry + Duration::from_millis(1), expired_at);
+        assert_eq!(expired, vec![("ble:AA", start)]);
+        assert_eq!(records, vec![("ble:BB", start + Duration::from_secs(10))]);
+        let events: Vec<DiscoveryEvent> = expired.iter().flat_map(|(source_id, _)| manager.lose(source_id)).collect();
+        assert!(matches!(&events[..], [DiscoveryEvent::Lost { .. }]));
+        assert_eq!(manager.devices().len(), 1);
+
+        // 时钟回退时不视为过期
+        assert!(!is_expired(start + Duration::from_secs(60), start, expiry));
+    }
+
+    #[test]
+    fn advertised_id_takes_precedence() {
+        let mut manager = DiscoveryManager::new();
+        manager.observe("ble:AA", ble("Tablet", "AA"));
+
+        // 同一来源开始广播设备ID后，旧的按名称合并的设备消失
+        let mut sighting = ble("Tablet", "AA");
+        sighting.device_id = Some("device-1".to_string());
+        let events = manager.observe("ble:AA", sighting);
+        assert!(matches!(&events[..], [DiscoveryEvent::Lost { .. }, DiscoveryEvent::Added { device }] if device.id == "device-1"));
+        assert_eq!(manager.devices().len(), 1);
+
+        // 不带名称的广播保留已知的名称
+        let mut unnamed = ble("", "AA");
+        unnamed.device_id = Some("device-1".to_string());
+        assert!(manager.observe("ble:AA", unnamed).is_empty());
+        assert_eq!(manager.devices()[0].name, "Tablet");
+    }
+}
@@ -1,13 +1,35 @@
-use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use crate::api::{get_device_id, DeviceTransport, DeviceType, NearbySendError};
+use crate::connection::handshake::{LOCAL_CAPABILITIES, REQUIRED_CAPABILITIES};
+use crate::discovery::advertiser::short_device_id;
+use crate::discovery::manager::{is_expired, remove_expired, report_lost, report_sighting, Sighting, EXPIRY_CHECK_INTERVAL};
+use crate::security::tls::local_certificate_fingerprint;
+use crate::transfer::codec::{MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo, VERIFY_TIMEOUT_DEFAULT};
 use std::collections::HashMap;
 use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::time;
 
 // NearbySend服务类型
 const SERVICE_TYPE: &str = "_nearbysend._tcp.local.";
 
+// TXT记录的键
+const TXT_ID: &str = "id";
+const TXT_NAME: &str = "name";
+const TXT_DEVICE_TYPE: &str = "device_type";
+const TXT_PROTOCOL_VERSION: &str = "protocol_version";
+const TXT_FINGERPRINT: &str = "fingerprint";
+const TXT_TRANSPORTS: &str = "transports";
+const TXT_CAPABILITIES: &str = "capabilities";
+
+// 传输方式标识（mDNS发现的设备只能通过局域网连接）
+const TRANSPORT_LAN: &str = "lan";
+
+// 本机支持的传输方式
+const LOCAL_TRANSPORTS: &[&str] = &[TRANSPORT_LAN, "ble", "hotspot", "wifi-direct"];
+
 // mDNS设备结构体
 #[derive(Clone, Debug)]
 pub struct MdnsDevice {
@@ -16,6 +38,17 @@ pub struct MdnsDevice {
     pub ip_address: IpAddr,
     pub port: u16,
     pub device_type: String,
+    // 对方的完整设备ID（旧版本不公布时为None）
+    pub device_id: Option<String>,
+    pub protocol_version: Option<u8>,
+    // 对方TLS证书的指纹，连接时用来校验证书
+    pub fingerprint: Option<String>,
+    pub transports: Vec<String>,
+    pub capabilities: Vec<String>,
+    // 最后一次解析或确认的时间
+    pub last_seen: Instant,
+    // 记录的TTL，超过后向网络确认设备是否仍然存在
+    pub ttl: Duration,
 }
 
 // 全局设备列表
@@ -25,32 +58,30 @@ lazy_static::lazy_static! {
     static ref MDNS_SERVICE: Arc<Mutex<Option<ServiceDaemon>>> = Arc::new(Mutex::new(None));
 }
 
+// mDNS发现的代数，每次启动递增，旧的后台任务发现代数改变后退出
+static MDNS_GENERATION: AtomicU64 = AtomicU64::new(0);
+
 // 启动mDNS设备发现
-pub fn start_mdns_discovery() -> Result<(), String> {
+pub fn start_mdns_discovery() -> Result<(), NearbySendError> {
     // 检查是否已经在运行
     {
-        let mut running = MDNS_DISCOVERY_RUNNING.lock().map_err(|e| e.to_string())?;
+        let mut running = MDNS_DISCOVERY_RUNNING.lock()?;
         if *running {
             return Ok(());
         }
         *running = true;
     }
-
-    // 清空设备列表
-    {
-        let mut devices = DISCOVERED_MDNS_DEVICES.lock().map_err(|e| e.to_string())?;
-        devices.clear();
-    }
+    let generation = MDNS_GENERATION.fetch_add(1, Ordering::SeqCst) + 1;
 
     // 创建mDNS服务
-    let mdns = ServiceDaemon::new().map_err(|e| e.to_string())?;
+    let mdns = ServiceDaemon::new()?;
     
     // 创建浏览器
-    let receiver = mdns.browse(SERVICE_TYPE).map_err(|e| e.to_string())?;
+    let receiver = mdns.browse(SERVICE_TYPE)?;
 
     // 保存服务实例
     {
-        let mut service = MDNS_SERVICE.lock().map_err(|e| e.to_string())?;
+        let mut service = MDNS_SERVICE.lock()?;
         *service = Some(mdns);
     }
 
@@ -60,39 +91,15 @@ pub fn start_mdns_discovery() -> Result<(), String> {
             match event {
                 ServiceEvent::ServiceResolved(info) => {
                     log::info!("mDNS service resolved: {:?}", info);
-                    
-                    // 获取设备信息
-                    if let Some(ip_address) = info.get_addresses().first() {
-                        let port = info.get_port();
-                        let fullname = info.get_fullname();
-                        let id = fullname.to_string();
-                        
-                        // 获取设备名称和类型
-                        let properties = info.get_properties();
-                        let name = properties.get("name").cloned().unwrap_or_else(|| "Unknown Device".to_string());
-                        let device_type = properties.get("device_type").cloned().unwrap_or_else(|| "unknown".to_string());
-                        
-                        // 创建设备对象
-                        let device = MdnsDevice {
-                            id,
-                            name,
-                            ip_address: *ip_address,
-                            port,
-                            device_type,
-                        };
-                        
-                        // 添加到设备列表
-                        if let Ok(mut devices) = DISCOVERED_MDNS_DEVICES.lock() {
-                            // 检查设备是否已存在
-                            if !devices.iter().any(|d| d.id == device.id) {
-                                devices.push(device);
-                            }
-                        }
-                    }
+                    handle_resolved(&info);
                 }
                 ServiceEvent::ServiceRemoved(service_type, fullname) => {
                     log::info!("mDNS service removed: {} {}", service_type, fullname);
                     
+                    if let Err(e) = report_lost(&format!("mdns:{}", fullname)) {
+                        log::error!("Failed to report lost mDNS device: {}", e);
+                    }
+                    
                     // 从设备列表中移除
                     if let Ok(mut devices) = DISCOVERED_MDNS_DEVICES.lock() {
                         devices.retain(|d| d.id != fullname);
@@ -101,28 +108,140 @@ pub fn start_mdns_discovery() -> Result<(), String> {
                 _ => {}
             }
             
-            // 检查是否应该停止
-            if let Ok(running) = MDNS_DISCOVERY_RUNNING.lock() {
-                if !*running {
-                    break;
-                }
+            // 检查是否应该停止（已停止或新的发现已经启动）
+            let running = MDNS_DISCOVERY_RUNNING.lock().map(|running| *running).unwrap_or(false);
+            if !running || MDNS_GENERATION.load(Ordering::SeqCst) != generation {
+                break;
             }
         }
         
         log::info!("mDNS discovery stopped");
     });
 
+    // 定期确认超过TTL没有刷新的设备（同一时间只有最新一次启动的任务在检查）
+    tokio::spawn(async move {
+        let mut check = time::interval(EXPIRY_CHECK_INTERVAL);
+        loop {
+            check.tick().await;
+            if MDNS_GENERATION.load(Ordering::SeqCst) != generation {
+                break;
+            }
+            verify_stale_devices();
+
+            // 停止发现后，等所有设备都过期再退出
+            let running = MDNS_DISCOVERY_RUNNING.lock().map(|running| *running).unwrap_or(false);
+            let empty = DISCOVERED_MDNS_DEVICES.lock().map(|devices| devices.is_empty()).unwrap_or(true);
+            if !running && empty {
+                break;
+            }
+        }
+    });
+
     Ok(())
 }
 
+// 处理解析完成的服务
+fn handle_resolved(info: &ServiceInfo) {
+    let device = match parse_service(info) {
+        Some(device) => device,
+        None => return,
+    };
+
+    // 忽略本机注册的服务
+    if device.device_id.as_deref() == Some(get_device_id().as_str()) {
+        return;
+    }
+
+    // 不兼容的设备不报告，连接时握手一定会失败
+    if let Err(e) = check_compatible(&device) {
+        log::debug!("Ignoring mDNS device {}: {}", device.id, e);
+        return;
+    }
+
+    // 报告给设备发现管理器（带设备ID时与其他来源发现的同一设备合并）
+    let sighting = Sighting {
+        device_id: device.device_id.as_deref().map(short_device_id),
+        name: device.name.clone(),
+        device_type: parse_device_type(&device.device_type),
+        transport: DeviceTransport::Lan {
+            ip_address: device.ip_address.to_string(),
+            port: device.port,
+        },
+        rssi: None,
+    };
+    if let Err(e) = report_sighting(&format!("mdns:{}", device.id), sighting) {
+        log::error!("Failed to report mDNS device: {}", e);
+    }
+
+    // 添加或更新设备列表（同一设备ID只保留最新的服务实例）
+    if let Ok(mut devices) = DISCOVERED_MDNS_DEVICES.lock() {
+        let existing = devices.iter_mut().find(|d| {
+            d.id == device.id || (device.device_id.is_some() && d.device_id == device.device_id)
+        });
+        match existing {
+            Some(existing) => *existing = device,
+            None => devices.push(device),
+        }
+    }
+}
+
+// 处理超过TTL的设备：mDNS服务运行时发送确认查询（无应答时服务会发出移除事件），否则直接移除
+fn verify_stale_devices() {
+    let service = MDNS_SERVICE.lock().ok().and_then(|service| service.clone());
+
+    let stale = match DISCOVERED_MDNS_DEVICES.lock() {
+        Ok(mut devices) => take_stale_devices(&mut devices, Instant::now(), service.is_some()),
+        Err(e) => {
+            log::error!("Failed to lock mDNS device list: {}", e);
+            return;
+        }
+    };
+
+    for id in stale {
+        match &service {
+            Some(service) => {
+                log::info!("mDNS device {} missed its TTL refresh, verifying", id);
+                if let Err(e) = service.verify(id, VERIFY_TIMEOUT_DEFAULT) {
+                    log::error!("Failed to verify mDNS device: {}", e);
+                }
+            }
+            None => {
+                log::info!("mDNS device {} expired", id);
+                if let Err(e) = report_lost(&format!("mdns:{}", id)) {
+                    log::error!("Failed to report lost mDNS device: {}", e);
+                }
+            }
+        }
+    }
+}
+
+// 找出在now时已超过TTL的设备：能向网络确认时标记为确认中（确认期间不再重复查询），否则直接移除
+fn take_stale_devices(devices: &mut Vec<MdnsDevice>, now: Instant, can_verify: bool) -> Vec<String> {
+    if can_verify {
+        devices
+            .iter_mut()
+            .filter(|d| is_expired(d.last_seen, now, d.ttl))
+            .map(|d| {
+                d.last_seen = now;
+                d.id.clone()
+            })
+            .collect()
+    } else {
+        remove_expired(devices, now, |d: &MdnsDevice, now| is_expired(d.last_seen, now, d.ttl))
+            .into_iter()
+            .map(|d| d.id)
+            .collect()
+    }
+}
+
 // 停止mDNS设备发现
-pub fn stop_mdns_discovery() -> Result<(), String> {
-    let mut running = MDNS_DISCOVERY_RUNNING.lock().map_err(|e| e.to_string())?;
+pub fn stop_mdns_discovery() -> Result<(), NearbySendError> {
+    let mut running = MDNS_DISCOVERY_RUNNING.lock()?;
     *running = false;
     
     // 关闭mDNS服务
     {
-        let mut service = MDNS_SERVICE.lock().map_err(|e| e.to_string())?;
+        let mut service = MDNS_SERVICE.lock()?;
         *service = None;
     }
     
@@ -130,22 +249,89 @@ pub fn stop_mdns_discovery() -> Result<(), String> {
 }
 
 // 获取已发现的设备列表
-pub fn get_discovered_mdns_devices() -> Result<Vec<MdnsDevice>, String> {
-    let devices = DISCOVERED_MDNS_DEVICES.lock().map_err(|e| e.to_string())?;
+pub fn get_discovered_mdns_devices() -> Result<Vec<MdnsDevice>, NearbySendError> {
+    let devices = DISCOVERED_MDNS_DEVICES.lock()?;
     Ok(devices.clone())
 }
 
+// 解析已解析的服务（没有地址时返回None，旧版本缺少的TXT键留空）
+fn parse_service(info: &ServiceInfo) -> Option<MdnsDevice> {
+    // 优先使用IPv4地址
+    let ip_address = info
+        .get_addresses_v4()
+        .into_iter()
+        .next()
+        .map(|ip| IpAddr::V4(*ip))
+        .or_else(|| info.get_addresses().iter().next().copied())?;
+
+    let property = |key: &str| info.get_property_val_str(key).filter(|value| !value.is_empty());
+    let list = |key: &str| {
+        property(key)
+            .map(|value| value.split(',').map(|item| item.trim().to_string()).filter(|item| !item.is_empty()).collect())
+            .unwrap_or_default()
+    };
+
+    Some(MdnsDevice {
+        id: info.get_fullname().to_string(),
+        name: property(TXT_NAME).unwrap_or("Unknown Device").to_string(),
+        ip_address,
+        port: info.get_port(),
+        device_type: property(TXT_DEVICE_TYPE).unwrap_or("unknown").to_string(),
+        device_id: property(TXT_ID).map(str::to_string),
+        protocol_version: property(TXT_PROTOCOL_VERSION).and_then(|version| version.parse().ok()),
+        fingerprint: property(TXT_FINGERPRINT).map(str::to_lowercase),
+        transports: list(TXT_TRANSPORTS),
+        capabilities: list(TXT_CAPABILITIES),
+        last_seen: Instant::now(),
+        ttl: Duration::from_secs(info.get_host_ttl() as u64),
+    })
+}
+
+// 根据TXT记录检查对方的协议版本、传输方式和必需功能（没有公布协议版本的是旧版本）
+fn check_compatible(device: &MdnsDevice) -> Result<(), NearbySendError> {
+    match device.protocol_version {
+        Some(version) if version >= MIN_PROTOCOL_VERSION => {}
+        Some(version) => {
+            return Err(NearbySendError::unsupported(format!("Device uses unsupported protocol version {}", version)));
+        }
+        None => return Err(NearbySendError::unsupported("Device does not publish a protocol version")),
+    }
+
+    if !device.transports.iter().any(|t| t == TRANSPORT_LAN) {
+        return Err(NearbySendError::unsupported("Device does not accept LAN connections"));
+    }
+
+    if let Some(missing) = REQUIRED_CAPABILITIES
+        .iter()
+        .find(|required| !device.capabilities.iter().any(|c| c == *required))
+    {
+        return Err(NearbySendError::unsupported(format!("Device does not support required capability: {}", missing)));
+    }
+
+    Ok(())
+}
+
+// 解析TXT记录中的设备类型
+fn parse_device_type(device_type: &str) -> DeviceType {
+    match device_type {
+        "android" => DeviceType::Android,
+        "ios" => DeviceType::IOS,
+        "macos" => DeviceType::MacOS,
+        "windows" => DeviceType::Windows,
+        _ => DeviceType::Unknown,
+    }
+}
+
 // 注册本机为可发现设备
-pub fn register_device(name: &str, port: u16) -> Result<(), String> {
+pub fn register_device(name: &str, port: u16) -> Result<(), NearbySendError> {
     // 获取mDNS服务
     let service = {
-        let service = MDNS_SERVICE.lock().map_err(|e| e.to_string())?;
-        match &*service {
-            Some(service) => service.clone(),
+        let mut service = MDNS_SERVICE.lock()?;
+        match service.clone() {
+            Some(service) => service,
             None => {
-                // 如果服务不存在，创建一个新的
-                let mdns = ServiceDaemon::new().map_err(|e| e.to_string())?;
-                let mut service = MDNS_SERVICE.lock().map_err(|e| e.to_string())?;
+                // 如果服务不存在，在持有同一个锁时创建一个新的
+                let mdns = ServiceDaemon::new()?;
                 *service = Some(mdns.clone());
                 mdns
             }
@@ -153,8 +339,10 @@ pub fn register_device(name: &str, port: u16) -> Result<(), String> {
     };
     
     // 创建服务信息
+    let device_id = get_device_id();
     let mut properties = HashMap::new();
-    properties.insert("name".to_string(), name.to_string());
+    properties.insert(TXT_ID.to_string(), device_id.clone());
+    properties.insert(TXT_NAME.to_string(), name.to_string());
     
     // 添加设备类型
     let device_type = match std::env::consts::OS {
@@ -164,22 +352,155 @@ pub fn register_device(name: &str, port: u16) -> Result<(), String> {
         "windows" => "windows",
         _ => "unknown",
     };
-    properties.insert("device_type".to_string(), device_type.to_string());
+    properties.insert(TXT_DEVICE_TYPE.to_string(), device_type.to_string());
+    properties.insert(TXT_PROTOCOL_VERSION.to_string(), PROTOCOL_VERSION.to_string());
+    properties.insert(TXT_TRANSPORTS.to_string(), LOCAL_TRANSPORTS.join(","));
+    properties.insert(TXT_CAPABILITIES.to_string(), LOCAL_CAPABILITIES.join(","));
+
+    // 没有证书时仍然注册，对方连接时无法提前校验证书
+    match local_certificate_fingerprint() {
+        Ok(fingerprint) => {
+            properties.insert(TXT_FINGERPRINT.to_string(), fingerprint);
+        }
+        Err(e) => log::warn!("Failed to get certificate fingerprint for mDNS: {}", e),
+    }
     
-    // 创建服务信息
+    // 实例名和主机名使用稳定的短设备ID（显示名称可能重复、含有非法字符或被修改）
+    let instance = format!("nearbysend-{}", short_device_id(&device_id));
     let service_info = ServiceInfo::new(
         SERVICE_TYPE,
-        name,
-        name,
-        "",
+        &instance,
+        &format!("{}.local.", instance),
+        (),
         port,
         properties,
-    ).map_err(|e| e.to_string())?;
+    )?
+    .enable_addr_auto();
     
     // 注册服务
-    service.register(service_info).map_err(|e| e.to_string())?;
+    service.register(service_info)?;
     
-    log::info!("Device registered with mDNS: {}", name);
+    log::info!("Device registered with mDNS: {} ({})", name, instance);
     
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service(instance: &str, ip: &str, properties: &[(&str, &str)]) -> ServiceInfo {
+        let properties: HashMap<String, String> =
+            properties.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect();
+        ServiceInfo::new(SERVICE_TYPE, instance, "peer.local.", ip, 8080, properties).unwrap()
+    }
+
+    #[test]
+    fn parses_txt_records() {
+        let info = service(
+            "nearbysend-0011223344556677",
+            "192.168.1.20",
+            &[
+                (TXT_ID, "peer-device-id"),
+                (TXT_NAME, "Pixel"),
+                (TXT_DEVICE_TYPE, "android"),
+                (TXT_PROTOCOL_VERSION, "3"),
+                (TXT_FINGERPRINT, "ABCDEF"),
+                (TXT_TRANSPORTS, "lan, ble,,hotspot"),
+                (TXT_CAPABILITIES, "resume,batch"),
+            ],
+        );
+
+        let device = parse_service(&info).unwrap();
+        assert_eq!(device.id, "nearbysend-0011223344556677._nearbysend._tcp.local.");
+        assert_eq!(device.name, "Pixel");
+        assert_eq!(device.ip_address, "192.168.1.20".parse::<IpAddr>().unwrap());
+        assert_eq!(device.port, 8080);
+        assert_eq!(device.device_type, "android");
+        assert_eq!(device.device_id.as_deref(), Some("peer-device-id"));
+        assert_eq!(device.protocol_version, Some(3));
+        assert_eq!(device.fingerprint.as_deref(), Some("abcdef"));
+        assert_eq!(device.transports, vec!["lan", "ble", "hotspot"]);
+        assert_eq!(device.capabilities, vec!["resume", "batch"]);
+    }
+
+    #[test]
+    fn tolerates_missing_txt_keys() {
+        // 旧版本没有的键留空，无法解析的值忽略
+        let info = service("Old Laptop", "192.168.1.21", &[(TXT_NAME, "Old Laptop"), (TXT_PROTOCOL_VERSION, "x")]);
+
+        let device = parse_service(&info).unwrap();
+        assert_eq!(device.name, "Old Laptop");
+        assert_eq!(device.device_type, "unknown");
+        assert_eq!(device.device_id, None);
+        assert_eq!(device.protocol_version, None);
+        assert_eq!(device.fingerprint, None);
+        assert!(device.transports.is_empty());
+        assert!(device.capabilities.is_empty());
+
+        // 没有地址时无法连接
+        let info = service("No Address", "", &[(TXT_NAME, "No Address")]);
+        assert!(parse_service(&info).is_none());
+    }
+
+    #[test]
+    fn rejects_incompatible_devices() {
+        let capabilities = LOCAL_CAPABILITIES.join(",");
+        let device = |version: &str, transports: &str, capabilities: &str| {
+            parse_service(&service(
+                "Peer",
+                "192.168.1.23",
+                &[(TXT_PROTOCOL_VERSION, version), (TXT_TRANSPORTS, transports), (TXT_CAPABILITIES, capabilities)],
+            ))
+            .unwrap()
+        };
+
+        assert!(check_compatible(&device(&PROTOCOL_VERSION.to_string(), "lan,ble", &capabilities)).is_ok());
+
+        // 协议版本过低或没有公布（旧版本）
+        assert!(check_compatible(&device(&(MIN_PROTOCOL_VERSION - 1).to_string(), "lan", &capabilities)).is_err());
+        assert!(check_compatible(&device("", "lan", &capabilities)).is_err());
+
+        // 不接受局域网连接
+        assert!(check_compatible(&device(&PROTOCOL_VERSION.to_string(), "ble", &capabilities)).is_err());
+
+        // 缺少必需的功能
+        let error = check_compatible(&device(&PROTOCOL_VERSION.to_string(), "lan", "resume,chunk-crc32")).unwrap_err();
+        assert!(error.to_string().contains(REQUIRED_CAPABILITIES[0]));
+    }
+
+    #[test]
+    fn stale_devices_are_verified_or_removed() {
+        let start = Instant::now();
+        let ttl = Duration::from_secs(120);
+        let device = |instance: &str, last_seen: Instant| MdnsDevice {
+            last_seen,
+            ttl,
+            ..parse_service(&service(instance, "192.168.1.30", &[])).unwrap()
+        };
+        let fresh = device("Fresh", start + Duration::from_secs(200));
+        let mut devices = vec![device("Stale", start), fresh.clone()];
+
+        // 刚好到达TTL时不处理
+        assert!(take_stale_devices(&mut devices, start + ttl, true).is_empty());
+
+        // 服务运行时发送确认查询，确认期间不再重复
+        let now = start + ttl + Duration::from_millis(1);
+        assert_eq!(take_stale_devices(&mut devices, now, true), vec!["Stale._nearbysend._tcp.local.".to_string()]);
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].last_seen, now);
+        assert!(take_stale_devices(&mut devices, now + Duration::from_secs(1), true).is_empty());
+
+        // 服务已关闭时直接移除
+        let later = now + ttl + Duration::from_millis(1);
+        assert_eq!(take_stale_devices(&mut devices, later, false), vec!["Stale._nearbysend._tcp.local.".to_string()]);
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].id, fresh.id);
+    }
+
+    #[test]
+    fn prefers_ipv4_address() {
+        let info = service("Dual Stack", "fe80::1,192.168.1.22", &[]);
+        assert_eq!(parse_service(&info).unwrap().ip_address, "192.168.1.22".parse::<IpAddr>().unwrap());
+    }
+}
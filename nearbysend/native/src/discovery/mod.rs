@@ -1,6 +1,11 @@
+pub mod advertiser;
 pub mod ble;
+pub mod bluez;
 pub mod mdns;
+pub mod manager;
 
 // 重新导出模块
+pub use advertiser::*;
 pub use ble::*;
 pub use mdns::*;
+pub use manager::*;
@@ -1,53 +1,95 @@
-use rustls::{Certificate, PrivateKey, ServerConfig, ClientConfig};
-use std::io::{Read, Write};
-use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tokio_rustls::server::TlsStream as ServerTlsStream;
-use tokio_rustls::client::TlsStream as ClientTlsStream;
-use tokio_rustls::{TlsConnector, TlsAcceptor};
+use crate::api::NearbySendError;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, ServerConfig, SignatureScheme};
+use std::sync::{Arc, Mutex};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
 
 // 生成自签名证书
-pub fn generate_self_signed_cert() -> Result<(Vec<Certificate>, PrivateKey), String> {
+pub fn generate_self_signed_cert() -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>), NearbySendError> {
     // 在实际实现中，应该使用适当的库生成自签名证书
     // 这里只是一个简化的示例
-    
+
     // 模拟证书和私钥
     let cert_data = vec![0u8; 10]; // 模拟证书数据
     let key_data = vec![0u8; 10];  // 模拟私钥数据
-    
-    let cert = Certificate(cert_data);
-    let key = PrivateKey(key_data);
-    
+
+    let cert = CertificateDer::from(cert_data);
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key_data));
+
     Ok((vec![cert], key))
 }
 
-// 创建TLS服务器配置
-pub fn create_server_config(certs: Vec<Certificate>, key: PrivateKey) -> Result<ServerConfig, String> {
+// 本机证书（进程内只生成一次，保证对外公布的指纹与实际使用的证书一致）
+lazy_static::lazy_static! {
+    static ref LOCAL_CERTIFICATE: Mutex<Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>> = Mutex::new(None);
+}
+
+// 获取本机证书和私钥
+pub fn local_certificate() -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>), NearbySendError> {
+    let mut local = LOCAL_CERTIFICATE.lock()?;
+    let (certs, key) = match &*local {
+        Some(existing) => existing,
+        None => local.insert(generate_self_signed_cert()?),
+    };
+
+    Ok((certs.clone(), key.clone_key()))
+}
+
+// 证书指纹（证书DER数据的BLAKE3哈希）
+pub fn certificate_fingerprint(cert: &CertificateDer<'_>) -> String {
+    blake3::hash(cert.as_ref()).to_hex().to_string()
+}
+
+// 本机证书的指纹
+pub fn local_certificate_fingerprint() -> Result<String, NearbySendError> {
+    let (certs, _) = local_certificate()?;
+    certs
+        .first()
+        .map(certificate_fingerprint)
+        .ok_or_else(|| NearbySendError::internal("Local certificate chain is empty"))
+}
+
+// 校验对端证书与发现时公布的指纹是否一致
+pub fn verify_certificate_fingerprint(cert: &CertificateDer<'_>, expected: &str) -> Result<(), NearbySendError> {
+    if certificate_fingerprint(cert).eq_ignore_ascii_case(expected) {
+        Ok(())
+    } else {
+        Err(NearbySendError::verification(format!("Certificate fingerprint mismatch, expected {}", expected)))
+    }
+}
+
+// 创建TLS服务器配置（使用本机证书，与mDNS公布的指纹一致）
+pub fn create_server_config() -> Result<ServerConfig, NearbySendError> {
+    let (certs, key) = local_certificate()?;
+
     // 创建服务器配置
     let mut config = ServerConfig::builder()
-        .with_safe_defaults()
         .with_no_client_auth()
         .with_single_cert(certs, key)
-        .map_err(|e| format!("Failed to create server config: {}", e))?;
-    
+        .map_err(|e| NearbySendError::internal(format!("Failed to create server config: {}", e)))?;
+
     // 配置其他选项
     config.alpn_protocols = vec![b"nearbysend".to_vec()];
-    
+
     Ok(config)
 }
 
-// 创建TLS客户端配置
-pub fn create_client_config() -> Result<ClientConfig, String> {
+// 创建TLS客户端配置（有发现时公布的指纹时只接受该证书，否则接受任意证书）
+pub fn create_client_config(expected_fingerprint: Option<String>) -> Result<ClientConfig, NearbySendError> {
     // 创建客户端配置
-    let mut config = ClientConfig::builder()
-        .with_safe_defaults()
-        .with_custom_certificate_verifier(Arc::new(AcceptAllCertificates {}))
+    let provider = default_provider();
+    let mut config = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| NearbySendError::internal(format!("Failed to create client config: {}", e)))?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(FingerprintVerifier { expected_fingerprint, provider }))
         .with_no_client_auth();
-    
+
     // 配置其他选项
     config.alpn_protocols = vec![b"nearbysend".to_vec()];
-    
+
     Ok(config)
 }
 
@@ -61,20 +103,89 @@ pub fn create_tls_connector(config: ClientConfig) -> TlsConnector {
     TlsConnector::from(Arc::new(config))
 }
 
-// 接受所有证书的验证器
-struct AcceptAllCertificates {}
+// 进程默认的加密实现（未安装时使用rustls按特性选择的实现）
+fn default_provider() -> Arc<CryptoProvider> {
+    CryptoProvider::get_default()
+        .cloned()
+        .unwrap_or_else(|| Arc::new(rustls::crypto::aws_lc_rs::default_provider()))
+}
+
+// 按证书指纹校验自签名证书的验证器（仍然校验握手签名）
+#[derive(Debug)]
+struct FingerprintVerifier {
+    expected_fingerprint: Option<String>,
+    provider: Arc<CryptoProvider>,
+}
 
-impl rustls::client::ServerCertVerifier for AcceptAllCertificates {
+impl ServerCertVerifier for FingerprintVerifier {
     fn verify_server_cert(
         &self,
-        _end_entity: &Certificate,
-        _intermediates: &[Certificate],
-        _server_name: &rustls::ServerName,
-        _scts: &mut dyn Iterator<Item = &[u8]>,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
         _ocsp_response: &[u8],
-        _now: std::time::SystemTime,
-    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
-        // 接受所有证书
-        Ok(rustls::client::ServerCertVerified::assertion())
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        // 没有公布指纹的对端（例如通过BLE或手动输入地址连接）无法提前校验
+        if let Some(expected) = &self.expected_fingerprint {
+            verify_certificate_fingerprint(end_entity, expected)
+                .map_err(|e| rustls::Error::General(e.to_string()))?;
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_client_config() {
+        let config = create_client_config(None).unwrap();
+        assert_eq!(config.alpn_protocols, vec![b"nearbysend".to_vec()]);
+    }
+
+    #[test]
+    fn verifies_certificate_fingerprint() {
+        let fingerprint = local_certificate_fingerprint().unwrap();
+        assert_eq!(fingerprint, local_certificate_fingerprint().unwrap());
+
+        let (certs, _) = local_certificate().unwrap();
+        assert!(verify_certificate_fingerprint(&certs[0], &fingerprint).is_ok());
+        assert!(verify_certificate_fingerprint(&certs[0], &fingerprint.to_uppercase()).is_ok());
+        assert!(verify_certificate_fingerprint(&CertificateDer::from(vec![1, 2, 3]), &fingerprint).is_err());
+
+        // 客户端只接受与公布的指纹一致的证书
+        let verifier = FingerprintVerifier {
+            expected_fingerprint: Some(fingerprint),
+            provider: default_provider(),
+        };
+        let server_name = ServerName::try_from("nearbysend").unwrap();
+        assert!(verifier.verify_server_cert(&certs[0], &[], &server_name, &[], UnixTime::now()).is_ok());
+        assert!(verifier
+            .verify_server_cert(&CertificateDer::from(vec![1, 2, 3]), &[], &server_name, &[], UnixTime::now())
+            .is_err());
     }
 }
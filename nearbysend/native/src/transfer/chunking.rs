@@ -1,29 +1,37 @@
-use std::fs::File;
+use crate::api::NearbySendError;
+use std::fs::{File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 // 默认块大小
 pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024; // 64KB
 
-// 文件分块器
+// 接收中的临时文件后缀
+pub const PARTIAL_FILE_EXTENSION: &str = "nearbysend-part";
+
+// 文件分块器（顺序读取时同时计算整个文件的哈希）
 pub struct FileChunker {
     file: File,
     chunk_size: usize,
     file_size: u64,
     current_position: u64,
+    hasher: blake3::Hasher,
+    hashed_size: u64,
 }
 
 impl FileChunker {
     // 创建新的文件分块器
-    pub fn new(file_path: &str, chunk_size: Option<usize>) -> Result<Self, String> {
-        let file = File::open(file_path).map_err(|e| format!("Failed to open file: {}", e))?;
-        let file_size = file.metadata().map_err(|e| format!("Failed to get file metadata: {}", e))?.len();
+    pub fn new(file_path: &str, chunk_size: Option<usize>) -> Result<Self, NearbySendError> {
+        let file = File::open(file_path).map_err(|e| NearbySendError::io("Failed to open file", e))?;
+        let file_size = file.metadata().map_err(|e| NearbySendError::io("Failed to get file metadata", e))?.len();
         
         Ok(Self {
             file,
             chunk_size: chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE),
             file_size,
             current_position: 0,
+            hasher: blake3::Hasher::new(),
+            hashed_size: 0,
         })
     }
     
@@ -52,7 +60,7 @@ impl FileChunker {
     }
     
     // 读取下一个块
-    pub fn next_chunk(&mut self) -> Result<Option<Vec<u8>>, String> {
+    pub fn next_chunk(&mut self) -> Result<Option<Vec<u8>>, NearbySendError> {
         if self.is_complete() {
             return Ok(None);
         }
@@ -60,60 +68,162 @@ impl FileChunker {
         let mut buffer = vec![0u8; self.chunk_size];
         
         // 设置文件位置
-        self.file.seek(SeekFrom::Start(self.current_position)).map_err(|e| format!("Failed to seek file: {}", e))?;
+        self.file.seek(SeekFrom::Start(self.current_position)).map_err(|e| NearbySendError::io("Failed to seek file", e))?;
         
         // 读取数据
-        let bytes_read = self.file.read(&mut buffer).map_err(|e| format!("Failed to read file: {}", e))?;
+        let bytes_read = self.file.read(&mut buffer).map_err(|e| NearbySendError::io("Failed to read file", e))?;
         
         if bytes_read == 0 {
             return Ok(None);
         }
         
-        // 更新位置
-        self.current_position += bytes_read as u64;
-        
         // 调整缓冲区大小
         buffer.truncate(bytes_read);
         
+        // 顺序读取的数据直接计入哈希
+        if self.current_position == self.hashed_size {
+            self.hasher.update(&buffer);
+            self.hashed_size += bytes_read as u64;
+        }
+        
+        // 更新位置
+        self.current_position += bytes_read as u64;
+        
         Ok(Some(buffer))
     }
     
     // 重置位置
-    pub fn reset(&mut self) -> Result<(), String> {
+    pub fn reset(&mut self) -> Result<(), NearbySendError> {
         self.current_position = 0;
-        self.file.seek(SeekFrom::Start(0)).map_err(|e| format!("Failed to seek file: {}", e))?;
+        self.file.seek(SeekFrom::Start(0)).map_err(|e| NearbySendError::io("Failed to seek file", e))?;
+        Ok(())
+    }
+    
+    // 跳转到指定位置（用于断点续传）
+    pub fn seek(&mut self, position: u64) -> Result<(), NearbySendError> {
+        if position > self.file_size {
+            return Err(NearbySendError::invalid(format!("Seek position {} beyond file size {}", position, self.file_size)));
+        }
+        
+        // 先对跳过的数据计算哈希，之后的数据可以继续边读边算
+        hash_file_range(&mut self.file, &mut self.hasher, &mut self.hashed_size, position)?;
+        
+        self.current_position = position;
+        self.file.seek(SeekFrom::Start(position)).map_err(|e| NearbySendError::io("Failed to seek file", e))?;
         Ok(())
     }
+    
+    // 读取指定位置的数据（用于重传，不影响当前位置）
+    pub fn read_at(&mut self, position: u64, len: u64) -> Result<Vec<u8>, NearbySendError> {
+        if position + len > self.file_size {
+            return Err(NearbySendError::invalid(format!("Read beyond file size: {} + {} > {}", position, len, self.file_size)));
+        }
+        
+        let mut buffer = vec![0u8; len as usize];
+        self.file.seek(SeekFrom::Start(position)).map_err(|e| NearbySendError::io("Failed to seek file", e))?;
+        self.file.read_exact(&mut buffer).map_err(|e| NearbySendError::io("Failed to read file", e))?;
+        
+        Ok(buffer)
+    }
+    
+    // 获取整个文件的哈希（十六进制）
+    pub fn file_hash(&mut self) -> Result<String, NearbySendError> {
+        // 补齐尚未计入哈希的数据
+        hash_file_range(&mut self.file, &mut self.hasher, &mut self.hashed_size, self.file_size)?;
+        self.file.seek(SeekFrom::Start(self.current_position)).map_err(|e| NearbySendError::io("Failed to seek file", e))?;
+        
+        Ok(self.hasher.finalize().to_hex().to_string())
+    }
 }
 
-// 文件组装器
+// 获取文件接收过程中使用的临时文件路径（同一目录下的隐藏文件）
+pub fn partial_file_path(file_path: &str) -> PathBuf {
+    let path = Path::new(file_path);
+    let file_name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    path.with_file_name(format!(".{}.{}", file_name, PARTIAL_FILE_EXTENSION))
+}
+
+// 文件组装器（写入临时文件，校验通过后才重命名为目标文件；顺序写入时同时计算整个文件的哈希）
 pub struct FileAssembler {
     file: File,
     file_path: String,
+    temp_path: PathBuf,
     expected_size: u64,
     current_size: u64,
+    hasher: blake3::Hasher,
+    hashed_size: u64,
 }
 
 impl FileAssembler {
     // 创建新的文件组装器
-    pub fn new(file_path: &str, expected_size: u64) -> Result<Self, String> {
+    pub fn new(file_path: &str, expected_size: u64) -> Result<Self, NearbySendError> {
         // 确保目录存在
         if let Some(parent) = Path::new(file_path).parent() {
             if !parent.exists() {
-                std::fs::create_dir_all(parent).map_err(|e| format!("Failed to create directory: {}", e))?;
+                std::fs::create_dir_all(parent).map_err(|e| NearbySendError::io("Failed to create directory", e))?;
             }
         }
         
-        let file = File::create(file_path).map_err(|e| format!("Failed to create file: {}", e))?;
+        // 以读写方式创建临时文件，校验时可能需要读回数据
+        let temp_path = partial_file_path(file_path);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temp_path)
+            .map_err(|e| NearbySendError::io("Failed to create file", e))?;
         
         Ok(Self {
             file,
             file_path: file_path.to_string(),
+            temp_path,
             expected_size,
             current_size: 0,
+            hasher: blake3::Hasher::new(),
+            hashed_size: 0,
         })
     }
     
+    // 打开已有的未完成临时文件，从offset处继续写入（用于断点续传）
+    pub fn resume(file_path: &str, expected_size: u64, offset: u64) -> Result<Self, NearbySendError> {
+        if offset > expected_size {
+            return Err(NearbySendError::invalid(format!("Resume offset {} beyond expected size {}", offset, expected_size)));
+        }
+        
+        let temp_path = partial_file_path(file_path);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&temp_path)
+            .map_err(|e| NearbySendError::io("Failed to open file", e))?;
+        
+        // 丢弃偏移量之后可能不完整的数据
+        file.set_len(offset).map_err(|e| NearbySendError::io("Failed to truncate file", e))?;
+        
+        // 对已有的数据计算哈希，之后的数据可以继续边写边算
+        let mut hasher = blake3::Hasher::new();
+        let mut hashed_size = 0;
+        hash_file_range(&mut file, &mut hasher, &mut hashed_size, offset)?;
+        
+        file.seek(SeekFrom::Start(offset)).map_err(|e| NearbySendError::io("Failed to seek file", e))?;
+        
+        Ok(Self {
+            file,
+            file_path: file_path.to_string(),
+            temp_path,
+            expected_size,
+            current_size: offset,
+            hasher,
+            hashed_size,
+        })
+    }
+    
+    // 将已写入的数据同步到磁盘
+    pub fn sync(&self) -> Result<(), NearbySendError> {
+        self.file.sync_data().map_err(|e| NearbySendError::io("Failed to sync file", e))
+    }
+    
     // 获取预期大小
     pub fn expected_size(&self) -> u64 {
         self.expected_size
@@ -139,14 +249,21 @@ impl FileAssembler {
     }
     
     // 写入块
-    pub fn write_chunk(&mut self, chunk: &[u8], position: Option<u64>) -> Result<(), String> {
+    pub fn write_chunk(&mut self, chunk: &[u8], position: Option<u64>) -> Result<(), NearbySendError> {
         if let Some(pos) = position {
             // 设置文件位置
-            self.file.seek(SeekFrom::Start(pos)).map_err(|e| format!("Failed to seek file: {}", e))?;
+            self.file.seek(SeekFrom::Start(pos)).map_err(|e| NearbySendError::io("Failed to seek file", e))?;
         }
         
         // 写入数据
-        self.file.write_all(chunk).map_err(|e| format!("Failed to write to file: {}", e))?;
+        let start = self.file.stream_position().map_err(|e| NearbySendError::io("Failed to seek file", e))?;
+        self.file.write_all(chunk).map_err(|e| NearbySendError::io("Failed to write to file", e))?;
+        
+        // 顺序写入的数据直接计入哈希
+        if start == self.hashed_size {
+            self.hasher.update(chunk);
+            self.hashed_size += chunk.len() as u64;
+        }
         
         // 更新大小
         self.current_size += chunk.len() as u64;
@@ -154,16 +271,253 @@ impl FileAssembler {
         Ok(())
     }
     
-    // 完成组装
-    pub fn finish(self) -> Result<String, String> {
-        // 关闭文件
-        drop(self.file);
-        
+    // 完成组装：同步到磁盘后将临时文件重命名为目标文件
+    pub fn finish(self) -> Result<String, NearbySendError> {
         // 检查大小
         if self.current_size != self.expected_size {
-            return Err(format!("File size mismatch: expected {}, got {}", self.expected_size, self.current_size));
+            return Err(NearbySendError::verification(format!("File size mismatch: expected {}, got {}", self.expected_size, self.current_size)));
+        }
+        
+        // 确保数据落盘后再重命名，避免出现内容不完整的目标文件
+        self.file.sync_all().map_err(|e| NearbySendError::io("Failed to sync file", e))?;
+        drop(self.file);
+        
+        replace_file(&self.temp_path, Path::new(&self.file_path)).map_err(|e| NearbySendError::io("Failed to move file into place", e))?;
+        
+        // 同步目录，确保重命名本身也已落盘
+        #[cfg(unix)]
+        if let Some(parent) = Path::new(&self.file_path).parent() {
+            if let Err(e) = File::open(parent).and_then(|dir| dir.sync_all()) {
+                log::warn!("Failed to sync directory {}: {}", parent.display(), e);
+            }
         }
         
         Ok(self.file_path)
     }
+    
+    // 完成组装并校验大小和哈希，校验失败时删除临时文件
+    pub fn finish_verified(mut self, expected_hash: &str) -> Result<String, NearbySendError> {
+        // 补齐尚未计入哈希的数据（乱序写入或重传时）
+        let end = self.current_size;
+        hash_file_range(&mut self.file, &mut self.hasher, &mut self.hashed_size, end)?;
+        let actual_hash = self.hasher.finalize().to_hex().to_string();
+        
+        if !actual_hash.eq_ignore_ascii_case(expected_hash) {
+            self.abort();
+            return Err(NearbySendError::verification(format!("Integrity check failed: expected hash {}, got {}", expected_hash, actual_hash)));
+        }
+        
+        let temp_path = self.temp_path.clone();
+        let result = self.finish();
+        if result.is_err() {
+            remove_partial_file(&temp_path);
+        }
+        
+        result
+    }
+    
+    // 放弃组装，删除临时文件
+    pub fn abort(self) {
+        let temp_path = self.temp_path.clone();
+        drop(self.file);
+        remove_partial_file(&temp_path);
+    }
+}
+
+// 将临时文件移动到目标位置，目标文件已存在时替换（覆盖策略）
+fn replace_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    match std::fs::rename(from, to) {
+        // Windows上目标文件已存在时重命名可能失败，先删除旧文件再重命名
+        #[cfg(windows)]
+        Err(_) if to.is_file() => {
+            std::fs::remove_file(to)?;
+            std::fs::rename(from, to)
+        }
+        result => result,
+    }
+}
+
+// 删除临时文件（不存在时忽略）
+fn remove_partial_file(temp_path: &Path) {
+    match std::fs::remove_file(temp_path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => log::error!("Failed to remove partial file {}: {}", temp_path.display(), e),
+    }
+}
+
+// 计算单个数据块的校验和
+pub fn chunk_checksum(data: &[u8]) -> u32 {
+    crc32fast::hash(data)
+}
+
+// 从已计算的位置读取文件直到end，计入哈希
+fn hash_file_range(file: &mut File, hasher: &mut blake3::Hasher, hashed_size: &mut u64, end: u64) -> Result<(), NearbySendError> {
+    if *hashed_size >= end {
+        return Ok(());
+    }
+    
+    file.seek(SeekFrom::Start(*hashed_size)).map_err(|e| NearbySendError::io("Failed to seek file", e))?;
+    
+    let mut buffer = vec![0u8; DEFAULT_CHUNK_SIZE];
+    while *hashed_size < end {
+        let wanted = (end - *hashed_size).min(buffer.len() as u64) as usize;
+        let n = file.read(&mut buffer[..wanted]).map_err(|e| NearbySendError::io("Failed to read file", e))?;
+        if n == 0 {
+            return Err(NearbySendError::Io {
+                message: "Unexpected end of file while hashing".to_string(),
+            });
+        }
+        
+        hasher.update(&buffer[..n]);
+        *hashed_size += n as u64;
+    }
+    
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("nearbysend-chunking-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn sample_data() -> Vec<u8> {
+        (0..300_000u32).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn chunker_hash_matches_file_hash() {
+        let dir = temp_dir("chunker");
+        let path = dir.join("source.bin");
+        let data = sample_data();
+        std::fs::write(&path, &data).unwrap();
+        let expected = blake3::hash(&data).to_hex().to_string();
+
+        // 顺序读取
+        let mut chunker = FileChunker::new(&path.to_string_lossy(), None).unwrap();
+        while chunker.next_chunk().unwrap().is_some() {}
+        assert_eq!(chunker.file_hash().unwrap(), expected);
+
+        // 从中间续传
+        let mut chunker = FileChunker::new(&path.to_string_lossy(), None).unwrap();
+        chunker.seek(100_000).unwrap();
+        assert_eq!(chunker.next_chunk().unwrap().unwrap(), data[100_000..100_000 + DEFAULT_CHUNK_SIZE].to_vec());
+        assert_eq!(chunker.file_hash().unwrap(), expected);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn assembler_verifies_hash() {
+        let dir = temp_dir("assembler");
+        let path = dir.join("target.bin").to_string_lossy().to_string();
+        let data = sample_data();
+        let expected = blake3::hash(&data).to_hex().to_string();
+
+        let mut assembler = FileAssembler::new(&path, data.len() as u64).unwrap();
+        for chunk in data.chunks(DEFAULT_CHUNK_SIZE) {
+            assembler.write_chunk(chunk, None).unwrap();
+        }
+        
+        // 校验通过之前目标文件不可见
+        assert!(!Path::new(&path).exists());
+        assert!(partial_file_path(&path).exists());
+        
+        assert_eq!(assembler.finish_verified(&expected).unwrap(), path);
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+        assert!(!partial_file_path(&path).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn assembler_resume_keeps_hash() {
+        let dir = temp_dir("resume");
+        let path = dir.join("target.bin").to_string_lossy().to_string();
+        let data = sample_data();
+        let expected = blake3::hash(&data).to_hex().to_string();
+
+        // 写入一部分后中断，磁盘上多出一些不完整的数据
+        std::fs::write(partial_file_path(&path), &data[..150_000]).unwrap();
+
+        let mut assembler = FileAssembler::resume(&path, data.len() as u64, 120_000).unwrap();
+        assembler.write_chunk(&data[120_000..], Some(120_000)).unwrap();
+        assert_eq!(assembler.finish_verified(&expected).unwrap(), path);
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn assembler_rejects_corrupt_data() {
+        let dir = temp_dir("corrupt");
+        let path = dir.join("target.bin").to_string_lossy().to_string();
+        let data = sample_data();
+        let expected = blake3::hash(&data).to_hex().to_string();
+
+        let mut corrupt = data.clone();
+        corrupt[1234] ^= 0xFF;
+
+        let mut assembler = FileAssembler::new(&path, data.len() as u64).unwrap();
+        assembler.write_chunk(&corrupt, None).unwrap();
+
+        let error = assembler.finish_verified(&expected).unwrap_err();
+        assert!(matches!(error, NearbySendError::VerificationFailed { .. }));
+        assert!(!Path::new(&path).exists());
+        assert!(!partial_file_path(&path).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn assembler_keeps_existing_file_until_verified() {
+        let dir = temp_dir("atomic");
+        let path = dir.join("target.bin").to_string_lossy().to_string();
+        std::fs::write(&path, b"old contents").unwrap();
+
+        // 未完成的大小检查失败，原文件保持不变
+        let mut assembler = FileAssembler::new(&path, 100).unwrap();
+        assembler.write_chunk(&[1u8; 50], None).unwrap();
+        assert!(assembler.finish_verified(&blake3::hash(&[1u8; 50]).to_hex()).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), b"old contents");
+        assert!(!partial_file_path(&path).exists());
+
+        // 放弃接收时删除临时文件
+        let mut assembler = FileAssembler::new(&path, 100).unwrap();
+        assembler.write_chunk(&[1u8; 50], None).unwrap();
+        assembler.abort();
+        assert_eq!(std::fs::read(&path).unwrap(), b"old contents");
+        assert!(!partial_file_path(&path).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn assembler_replaces_existing_file() {
+        let dir = temp_dir("overwrite");
+        let path = dir.join("target.bin").to_string_lossy().to_string();
+        std::fs::write(&path, b"old contents that are longer than the new ones").unwrap();
+
+        // 覆盖策略下完成时替换已存在的目标文件
+        let data = b"new contents";
+        let mut assembler = FileAssembler::new(&path, data.len() as u64).unwrap();
+        assembler.write_chunk(data, None).unwrap();
+        assert_eq!(assembler.finish_verified(&blake3::hash(data).to_hex()).unwrap(), path);
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+        assert!(!partial_file_path(&path).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn partial_file_is_hidden_sibling() {
+        assert_eq!(partial_file_path("/downloads/album/a.jpg"), PathBuf::from("/downloads/album/.a.jpg.nearbysend-part"));
+    }
 }
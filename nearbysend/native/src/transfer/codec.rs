@@ -0,0 +1,258 @@
+use crate::api::NearbySendError;
+use crate::transfer::protocol::TransferMessage;
+
+// 当前协议版本（版本1为不分帧的纯JSON编码，由分帧层识别）
+pub const PROTOCOL_VERSION: u8 = 2;
+
+// 支持的最低协议版本
+pub const MIN_PROTOCOL_VERSION: u8 = 2;
+
+// 消息类型标记
+const KIND_CONTROL: u8 = 0x01;
+const KIND_DATA_CHUNK: u8 = 0x02;
+
+// 数据块标志位
+const FLAG_LAST_CHUNK: u8 = 0x01;
+
+// 消息头：版本(1) + 类型(1)
+const MESSAGE_HEADER_SIZE: usize = 2;
+
+// 数据块头：ID长度(1) + ID + 块索引(4) + 文件偏移(8) + 校验和(4) + 标志(1) + 数据长度(4)
+const CHUNK_FIXED_SIZE: usize = 1 + 4 + 8 + 4 + 1 + 4;
+
+// 编码传输消息：数据块使用二进制格式，控制消息使用JSON（version为握手协商的协议版本）
+pub fn encode_message(message: &TransferMessage, version: u8) -> Result<Vec<u8>, NearbySendError> {
+    if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+        return Err(NearbySendError::unsupported(format!("Protocol version {} is not supported", version)));
+    }
+
+    match message {
+        TransferMessage::DataChunk { id, chunk_index, offset, checksum, data, is_last } => {
+            let id_bytes = id.as_bytes();
+            if id_bytes.len() > u8::MAX as usize {
+                return Err(NearbySendError::invalid(format!("Transfer id too long: {} bytes", id_bytes.len())));
+            }
+            if data.len() > u32::MAX as usize {
+                return Err(NearbySendError::invalid(format!("Chunk too large: {} bytes", data.len())));
+            }
+
+            let mut buffer = Vec::with_capacity(MESSAGE_HEADER_SIZE + CHUNK_FIXED_SIZE + id_bytes.len() + data.len());
+            buffer.push(version);
+            buffer.push(KIND_DATA_CHUNK);
+            buffer.push(id_bytes.len() as u8);
+            buffer.extend_from_slice(id_bytes);
+            buffer.extend_from_slice(&chunk_index.to_be_bytes());
+            buffer.extend_from_slice(&offset.to_be_bytes());
+            buffer.extend_from_slice(&checksum.to_be_bytes());
+            buffer.push(if *is_last { FLAG_LAST_CHUNK } else { 0 });
+            buffer.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            buffer.extend_from_slice(data);
+
+            Ok(buffer)
+        }
+        _ => {
+            let json = serde_json::to_vec(message).map_err(|e| NearbySendError::internal(e.to_string()))?;
+
+            let mut buffer = Vec::with_capacity(MESSAGE_HEADER_SIZE + json.len());
+            buffer.push(version);
+            buffer.push(KIND_CONTROL);
+            buffer.extend_from_slice(&json);
+
+            Ok(buffer)
+        }
+    }
+}
+
+// 解码传输消息（消息的版本标记必须与握手协商的协议版本一致）
+pub fn decode_message(bytes: &[u8], version: u8) -> Result<TransferMessage, NearbySendError> {
+    if bytes.len() < MESSAGE_HEADER_SIZE {
+        return Err(NearbySendError::protocol("Message too short"));
+    }
+
+    // 检查版本标记
+    if bytes[0] != version {
+        return Err(NearbySendError::protocol(format!("Unexpected protocol version: {} (negotiated {})", bytes[0], version)));
+    }
+
+    let body = &bytes[MESSAGE_HEADER_SIZE..];
+    match bytes[1] {
+        KIND_CONTROL => {
+            let message: TransferMessage = serde_json::from_slice(body).map_err(|e| NearbySendError::protocol(format!("Invalid control message: {}", e)))?;
+            if let TransferMessage::DataChunk { .. } = message {
+                return Err(NearbySendError::protocol("Data chunk sent as control message"));
+            }
+            Ok(message)
+        }
+        KIND_DATA_CHUNK => decode_data_chunk(body),
+        kind => Err(NearbySendError::protocol(format!("Unknown message kind: {}", kind))),
+    }
+}
+
+// 解码二进制数据块
+fn decode_data_chunk(body: &[u8]) -> Result<TransferMessage, NearbySendError> {
+    let truncated = || NearbySendError::protocol("Truncated data chunk");
+
+    let id_len = *body.first().ok_or_else(truncated)? as usize;
+    if body.len() < CHUNK_FIXED_SIZE + id_len {
+        return Err(truncated());
+    }
+
+    let id = std::str::from_utf8(&body[1..1 + id_len])
+        .map_err(|_| NearbySendError::protocol("Invalid transfer id in data chunk"))?
+        .to_string();
+
+    let mut offset = 1 + id_len;
+    let chunk_index = u32::from_be_bytes(body[offset..offset + 4].try_into().unwrap());
+    offset += 4;
+    let file_offset = u64::from_be_bytes(body[offset..offset + 8].try_into().unwrap());
+    offset += 8;
+    let checksum = u32::from_be_bytes(body[offset..offset + 4].try_into().unwrap());
+    offset += 4;
+    let flags = body[offset];
+    offset += 1;
+    let data_len = u32::from_be_bytes(body[offset..offset + 4].try_into().unwrap()) as usize;
+    offset += 4;
+
+    if body.len() - offset != data_len {
+        return Err(NearbySendError::protocol(format!("Data chunk length mismatch: header says {}, got {}", data_len, body.len() - offset)));
+    }
+
+    Ok(TransferMessage::DataChunk {
+        id,
+        chunk_index,
+        offset: file_offset,
+        checksum,
+        data: body[offset..].to_vec(),
+        is_last: flags & FLAG_LAST_CHUNK != 0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transfer::chunking::chunk_checksum;
+
+    #[test]
+    fn data_chunk_round_trip() {
+        let message = TransferMessage::DataChunk {
+            id: "transfer-1".to_string(),
+            chunk_index: 42,
+            offset: 42 * 256,
+            checksum: 0xDEADBEEF,
+            data: (0..=255u8).collect(),
+            is_last: true,
+        };
+
+        let encoded = encode_message(&message, PROTOCOL_VERSION).unwrap();
+        assert_eq!(encoded[0], PROTOCOL_VERSION);
+        assert_eq!(encoded[1], KIND_DATA_CHUNK);
+
+        match decode_message(&encoded, PROTOCOL_VERSION).unwrap() {
+            TransferMessage::DataChunk { id, chunk_index, offset, checksum, data, is_last } => {
+                assert_eq!(id, "transfer-1");
+                assert_eq!(chunk_index, 42);
+                assert_eq!(offset, 42 * 256);
+                assert_eq!(checksum, 0xDEADBEEF);
+                assert_eq!(data, (0..=255u8).collect::<Vec<u8>>());
+                assert!(is_last);
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn data_chunk_is_compact() {
+        let data = vec![200u8; 64 * 1024];
+        let message = TransferMessage::DataChunk {
+            id: "transfer-1".to_string(),
+            chunk_index: 0,
+            offset: 0,
+            checksum: 0,
+            data: data.clone(),
+            is_last: false,
+        };
+
+        let encoded = encode_message(&message, PROTOCOL_VERSION).unwrap();
+        assert!(encoded.len() < data.len() + 64);
+        assert!(encoded.len() * 3 < serde_json::to_vec(&message).unwrap().len());
+    }
+
+    #[test]
+    fn corrupted_payload_fails_checksum() {
+        let data: Vec<u8> = (0..1024u32).map(|i| i as u8).collect();
+        let message = TransferMessage::DataChunk {
+            id: "transfer-1".to_string(),
+            chunk_index: 3,
+            offset: 3 * 1024,
+            checksum: chunk_checksum(&data),
+            data,
+            is_last: false,
+        };
+
+        // 翻转负载中的一个比特
+        let mut encoded = encode_message(&message, PROTOCOL_VERSION).unwrap();
+        let last = encoded.len() - 1;
+        encoded[last] ^= 0x01;
+
+        match decode_message(&encoded, PROTOCOL_VERSION).unwrap() {
+            TransferMessage::DataChunk { chunk_index, checksum, data, .. } => {
+                assert_eq!(chunk_index, 3);
+                assert_ne!(chunk_checksum(&data), checksum);
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn control_message_round_trip() {
+        let message = TransferMessage::ChunkAck {
+            id: "transfer-1".to_string(),
+            chunk_index: 7,
+        };
+
+        let encoded = encode_message(&message, PROTOCOL_VERSION).unwrap();
+        assert_eq!(encoded[1], KIND_CONTROL);
+
+        match decode_message(&encoded, PROTOCOL_VERSION).unwrap() {
+            TransferMessage::ChunkAck { id, chunk_index } => {
+                assert_eq!(id, "transfer-1");
+                assert_eq!(chunk_index, 7);
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_version_and_truncation() {
+        assert!(decode_message(&[9, KIND_CONTROL, b'{', b'}'], PROTOCOL_VERSION).unwrap_err().to_string().contains("Unexpected protocol version"));
+
+        let encoded = encode_message(
+            &TransferMessage::DataChunk {
+                id: "transfer-1".to_string(),
+                chunk_index: 0,
+                offset: 0,
+                checksum: 0,
+                data: vec![1, 2, 3, 4],
+                is_last: false,
+            },
+            PROTOCOL_VERSION,
+        )
+        .unwrap();
+        assert!(decode_message(&encoded[..encoded.len() - 1], PROTOCOL_VERSION).is_err());
+        assert!(decode_message(&encoded[..5], PROTOCOL_VERSION).is_err());
+    }
+
+    #[test]
+    fn uses_negotiated_version() {
+        let message = TransferMessage::TransferCancel { id: "transfer-1".to_string() };
+
+        // 只能使用双方协商的版本编码，解码时版本标记必须与协商的版本一致
+        let encoded = encode_message(&message, MIN_PROTOCOL_VERSION).unwrap();
+        assert_eq!(encoded[0], MIN_PROTOCOL_VERSION);
+        assert!(decode_message(&encoded, MIN_PROTOCOL_VERSION).is_ok());
+        assert!(decode_message(&encoded, MIN_PROTOCOL_VERSION + 1).is_err());
+
+        assert!(encode_message(&message, MIN_PROTOCOL_VERSION - 1).is_err());
+        assert!(encode_message(&message, PROTOCOL_VERSION + 1).is_err());
+    }
+}
@@ -0,0 +1,61 @@
+use crate::api::NearbySendError;
+use std::path::{Path, PathBuf};
+
+// 自动重命名时最多尝试的编号
+const MAX_RENAME_ATTEMPTS: u32 = 9999;
+
+// 为已存在的文件找到一个未被占用的路径，如"name (1).ext"
+pub fn next_available_path(path: &Path, is_taken: impl Fn(&Path) -> bool) -> Result<PathBuf, NearbySendError> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| NearbySendError::invalid(format!("Invalid save path: {}", path.display())))?
+        .to_string_lossy()
+        .to_string();
+
+    for n in 1..=MAX_RENAME_ATTEMPTS {
+        let candidate = path.with_file_name(numbered_file_name(&file_name, n));
+        if !is_taken(&candidate) {
+            return Ok(candidate);
+        }
+    }
+
+    Err(NearbySendError::Io {
+        message: format!("No available file name for {}", path.display()),
+    })
+}
+
+// 生成带编号的文件名，编号放在扩展名之前
+fn numbered_file_name(file_name: &str, n: u32) -> String {
+    match file_name.rfind('.') {
+        // 以点开头的隐藏文件没有扩展名
+        Some(index) if index > 0 => format!("{} ({}){}", &file_name[..index], n, &file_name[index..]),
+        _ => format!("{} ({})", file_name, n),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn numbers_before_extension() {
+        assert_eq!(numbered_file_name("photo.jpg", 1), "photo (1).jpg");
+        assert_eq!(numbered_file_name("archive.tar.gz", 2), "archive.tar (2).gz");
+        assert_eq!(numbered_file_name("README", 3), "README (3)");
+        assert_eq!(numbered_file_name(".bashrc", 1), ".bashrc (1)");
+    }
+
+    #[test]
+    fn skips_taken_names() {
+        let taken: HashSet<PathBuf> = ["/downloads/photo (1).jpg", "/downloads/photo (2).jpg"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+
+        let path = next_available_path(Path::new("/downloads/photo.jpg"), |p| taken.contains(p)).unwrap();
+        assert_eq!(path, PathBuf::from("/downloads/photo (3).jpg"));
+
+        assert!(next_available_path(Path::new("/downloads/photo.jpg"), |_| true).is_err());
+    }
+}
@@ -0,0 +1,146 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use tokio::sync::Notify;
+
+// 传输控制（由API设置，传输循环在处理消息的间隙检查）
+pub struct TransferControl {
+    // 控制消息中使用的传输ID（批量传输为整批的ID）
+    transfer_id: String,
+    cancelled: AtomicBool,
+    // 暂停状态：最低位为是否暂停，其余位为暂停代数（每次改变递增，双方按代数判断消息先后）
+    pause_state: AtomicU64,
+    // 本方暂停、恢复或取消时唤醒等待消息的传输循环
+    changed: Notify,
+}
+
+// 拆分暂停状态为(代数, 是否暂停)
+fn split_pause_state(state: u64) -> (u64, bool) {
+    (state >> 1, state & 1 == 1)
+}
+
+fn pause_state(generation: u64, paused: bool) -> u64 {
+    (generation << 1) | paused as u64
+}
+
+impl TransferControl {
+    // 创建新的传输控制
+    pub fn new(transfer_id: &str) -> Self {
+        Self {
+            transfer_id: transfer_id.to_string(),
+            cancelled: AtomicBool::new(false),
+            pause_state: AtomicU64::new(0),
+            changed: Notify::new(),
+        }
+    }
+
+    // 获取传输ID
+    pub fn transfer_id(&self) -> &str {
+        &self.transfer_id
+    }
+
+    // 请求取消传输，返回是否为首次取消
+    pub fn cancel(&self) -> bool {
+        let first = !self.cancelled.swap(true, Ordering::SeqCst);
+        self.changed.notify_one();
+        first
+    }
+
+    // 是否已请求取消
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    // 本方暂停或恢复传输，状态改变时返回通知对方用的新代数
+    pub fn set_paused(&self, paused: bool) -> Option<u64> {
+        let generation = self
+            .pause_state
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |state| {
+                let (generation, current) = split_pause_state(state);
+                (current != paused).then(|| pause_state(generation + 1, paused))
+            })
+            .ok()
+            .map(|state| split_pause_state(state).0 + 1);
+        if generation.is_some() {
+            self.changed.notify_one();
+        }
+        generation
+    }
+
+    // 应用对方的暂停或恢复消息，返回状态是否改变（不回复对方）
+    // 代数不比本方新的消息已被之后的改变取代或已经应用过，直接忽略
+    pub fn apply_peer_pause(&self, paused: bool, generation: u64) -> bool {
+        self.pause_state
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |state| {
+                let (current_generation, _) = split_pause_state(state);
+                (generation > current_generation).then(|| pause_state(generation, paused))
+            })
+            .is_ok_and(|state| split_pause_state(state).1 != paused)
+    }
+
+    // 是否已暂停
+    pub fn is_paused(&self) -> bool {
+        split_pause_state(self.pause_state.load(Ordering::SeqCst)).1
+    }
+
+    // 等待本方改变暂停或取消状态（在等待之前发生的改变也会立即返回）
+    pub async fn changed(&self) {
+        self.changed.notified().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_state_changes_once() {
+        let control = TransferControl::new("transfer-1");
+        assert_eq!(control.transfer_id(), "transfer-1");
+
+        // 重复的暂停和恢复不会再次通知对方
+        assert_eq!(control.set_paused(true), Some(1));
+        assert_eq!(control.set_paused(true), None);
+        assert!(control.is_paused());
+        assert_eq!(control.set_paused(false), Some(2));
+        assert_eq!(control.set_paused(false), None);
+        assert!(!control.is_paused());
+
+        assert!(control.cancel());
+        assert!(!control.cancel());
+        assert!(control.is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn wakes_waiter_on_local_changes() {
+        let control = TransferControl::new("transfer-1");
+
+        // 改变发生在开始等待之前，等待也不会错过
+        control.set_paused(true);
+        tokio::time::timeout(std::time::Duration::from_secs(1), control.changed()).await.unwrap();
+
+        // 没有改变时保持等待
+        control.set_paused(true);
+        assert!(tokio::time::timeout(std::time::Duration::from_millis(20), control.changed()).await.is_err());
+
+        control.cancel();
+        tokio::time::timeout(std::time::Duration::from_secs(1), control.changed()).await.unwrap();
+    }
+
+    #[test]
+    fn ignores_stale_peer_messages() {
+        let control = TransferControl::new("transfer-1");
+
+        // 对方先暂停再恢复
+        assert!(control.apply_peer_pause(true, 1));
+        assert!(control.apply_peer_pause(false, 2));
+
+        // 迟到的旧消息和重复的消息不再改变状态
+        assert!(!control.apply_peer_pause(true, 1));
+        assert!(!control.apply_peer_pause(false, 2));
+        assert!(!control.is_paused());
+
+        // 本方之后的改变使用更新的代数
+        assert_eq!(control.set_paused(true), Some(3));
+        assert!(!control.apply_peer_pause(false, 2));
+        assert!(control.is_paused());
+    }
+}
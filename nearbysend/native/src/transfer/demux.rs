@@ -0,0 +1,444 @@
+use crate::api::NearbySendError;
+use crate::connection::framing::MAX_FRAME_SIZE;
+use crate::connection::wifi_direct::{get_protocol_version, receive_data, send_data, subscribe_connection_changes};
+use crate::transfer::codec::{decode_message, encode_message};
+use crate::transfer::protocol::TransferMessage;
+use crate::transfer::window::MAX_WINDOW_SIZE;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Mutex as AsyncMutex;
+use tokio::sync::{mpsc, watch};
+
+// 每个传输通道最多缓存的消息数（对方在发送窗口内最多有MAX_WINDOW_SIZE个在途数据块，
+// 另外留出控制消息和重传的余量；仍然放不下说明该传输处理不过来，只结束这一个传输）
+const CHANNEL_CAPACITY: usize = 4 * MAX_WINDOW_SIZE;
+
+// 最多排队等待处理的传输请求数（满时拒绝新的请求）
+const REQUEST_QUEUE_CAPACITY: usize = 8;
+
+// 路由表
+struct RouteTable {
+    routes: HashMap<String, mpsc::Sender<TransferMessage>>,
+    // 因通道已满被移除路由的传输，通道取完消息后返回错误
+    overloaded: HashSet<String>,
+    // 新的传输请求队列（连接关闭后为None）
+    requests: Option<mpsc::Sender<TransferMessage>>,
+}
+
+// 连接级的消息分发器：按传输ID将收到的消息分发到各个传输的通道，新的传输请求进入请求队列
+pub struct Demultiplexer {
+    table: Arc<Mutex<RouteTable>>,
+    requests: AsyncMutex<mpsc::Receiver<TransferMessage>>,
+}
+
+impl Default for Demultiplexer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Demultiplexer {
+    // 创建新的分发器
+    pub fn new() -> Self {
+        let (sender, receiver) = mpsc::channel(REQUEST_QUEUE_CAPACITY);
+        let table = RouteTable {
+            routes: HashMap::new(),
+            overloaded: HashSet::new(),
+            requests: Some(sender),
+        };
+
+        Self {
+            table: Arc::new(Mutex::new(table)),
+            requests: AsyncMutex::new(receiver),
+        }
+    }
+
+    // 为传输打开消息通道（批量传输使用整批的ID）
+    pub fn open_channel(&self, transfer_id: &str) -> Result<TransferChannel, NearbySendError> {
+        let mut table = self.table.lock()?;
+        if table.requests.is_none() {
+            return Err(NearbySendError::NotConnected);
+        }
+
+        let route_id = route_id(transfer_id).to_string();
+        if table.routes.contains_key(&route_id) {
+            return Err(NearbySendError::invalid(format!("Transfer already active on this connection: {}", route_id)));
+        }
+
+        let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+        table.routes.insert(route_id.clone(), sender);
+
+        Ok(TransferChannel {
+            route_id,
+            receiver,
+            table: self.table.clone(),
+        })
+    }
+
+    // 分发一条收到的消息，返回需要回复对方的消息
+    // 从不等待：某个传输的通道已满时只结束该传输，不阻塞同一连接上的其他传输
+    pub fn dispatch(&self, message: TransferMessage) -> Result<Option<TransferMessage>, NearbySendError> {
+        let transfer_id = message.transfer_id().to_string();
+        let mut table = self.table.lock()?;
+
+        // 新的传输请求进入请求队列，积压过多时拒绝
+        if matches!(message, TransferMessage::TransferRequest { .. } | TransferMessage::BatchTransferRequest { .. }) {
+            if let Some(requests) = &table.requests {
+                match requests.try_send(message) {
+                    Ok(()) => {}
+                    Err(mpsc::error::TrySendError::Full(message)) => {
+                        log::warn!("Too many pending requests, declining request {}", transfer_id);
+                        return Ok(message.decline("Receiver is busy"));
+                    }
+                    Err(mpsc::error::TrySendError::Closed(_)) => return Err(NearbySendError::internal("Request queue closed")),
+                }
+            }
+            return Ok(None);
+        }
+
+        // 已结束的传输可能还有对方未及处理的消息，直接丢弃
+        let route_id = route_id(&transfer_id).to_string();
+        let result = match table.routes.get(&route_id) {
+            Some(sender) => sender.try_send(message),
+            None => {
+                log::warn!("Dropping message for unknown transfer {}", transfer_id);
+                return Ok(None);
+            }
+        };
+
+        match result {
+            Ok(()) => Ok(None),
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                // 移除路由后通道取完已收到的消息即返回错误，并通知对方取消该传输
+                log::error!("Transfer {} is not keeping up with incoming messages, cancelling it", route_id);
+                table.routes.remove(&route_id);
+                table.overloaded.insert(route_id.clone());
+                Ok(Some(TransferMessage::TransferCancel { id: route_id }))
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                log::warn!("Transfer {} is no longer receiving messages", transfer_id);
+                Ok(None)
+            }
+        }
+    }
+
+    // 分发一帧收到的数据（按协商的协议版本解码）：无法解码的帧只丢弃，不影响同一连接上的其他传输
+    pub fn dispatch_frame(&self, data: &[u8], version: u8) -> Result<Option<TransferMessage>, NearbySendError> {
+        match decode_message(data, version) {
+            Ok(message) => self.dispatch(message),
+            Err(e) => {
+                log::warn!("Dropping undecodable frame of {} bytes: {}", data.len(), e);
+                Ok(None)
+            }
+        }
+    }
+
+    // 等待下一个传输请求
+    pub async fn next_request(&self) -> Result<TransferMessage, NearbySendError> {
+        let mut requests = self.requests.lock().await;
+        requests.recv().await.ok_or_else(NearbySendError::connection_closed)
+    }
+
+    // 关闭分发器，所有通道和请求队列在取完已收到的消息后返回连接关闭
+    pub fn close(&self) {
+        match self.table.lock() {
+            Ok(mut table) => {
+                table.routes.clear();
+                table.overloaded.clear();
+                table.requests = None;
+            }
+            Err(e) => log::error!("Failed to close demultiplexer: {}", e),
+        }
+    }
+}
+
+// 单个传输的消息通道，释放时自动取消路由
+pub struct TransferChannel {
+    route_id: String,
+    receiver: mpsc::Receiver<TransferMessage>,
+    table: Arc<Mutex<RouteTable>>,
+}
+
+impl TransferChannel {
+    // 接收下一条发给本传输的消息
+    pub async fn recv(&mut self) -> Result<TransferMessage, NearbySendError> {
+        match self.receiver.recv().await {
+            Some(message) => Ok(message),
+            None if self.table.lock()?.overloaded.contains(&self.route_id) => {
+                Err(NearbySendError::protocol(format!("Transfer {} fell behind incoming messages", self.route_id)))
+            }
+            None => Err(NearbySendError::connection_closed()),
+        }
+    }
+}
+
+impl Drop for TransferChannel {
+    fn drop(&mut self) {
+        if let Ok(mut table) = self.table.lock() {
+            table.routes.remove(&self.route_id);
+            table.overloaded.remove(&self.route_id);
+        }
+    }
+}
+
+// 消息的路由ID（批量传输中单个文件的ID为"整批ID:序号"，路由到整批的通道）
+pub fn route_id(transfer_id: &str) -> &str {
+    transfer_id.split_once(':').map_or(transfer_id, |(batch_id, _)| batch_id)
+}
+
+// 当前连接的分发器
+lazy_static::lazy_static! {
+    static ref CURRENT_DEMULTIPLEXER: Arc<Mutex<Option<Arc<Demultiplexer>>>> = Arc::new(Mutex::new(None));
+}
+
+// 获取当前连接的分发器，尚未运行时启动读取任务
+pub fn connection_demultiplexer() -> Result<Arc<Demultiplexer>, NearbySendError> {
+    let mut current = CURRENT_DEMULTIPLEXER.lock()?;
+    if let Some(demultiplexer) = &*current {
+        return Ok(demultiplexer.clone());
+    }
+
+    let demultiplexer = Arc::new(Demultiplexer::new());
+    *current = Some(demultiplexer.clone());
+    tokio::spawn(run_reader(demultiplexer.clone()));
+
+    Ok(demultiplexer)
+}
+
+// 读取任务：持续读取当前连接上的消息并分发，只有连接出错、断开或更换时才关闭分发器
+async fn run_reader(demultiplexer: Arc<Demultiplexer>) {
+    let mut changes = subscribe_connection_changes();
+    changes.borrow_and_update();
+
+    // 读取任务只服务于启动时的连接，消息按该连接握手协商的协议版本编解码
+    let reason = match get_protocol_version() {
+        Ok(version) => read_messages(&demultiplexer, &mut changes, version).await,
+        Err(e) => e,
+    };
+    log::info!("Connection reader stopped: {}", reason);
+
+    demultiplexer.close();
+
+    // 移除已关闭的分发器，下次使用时为新连接重新启动
+    if let Ok(mut current) = CURRENT_DEMULTIPLEXER.lock() {
+        if current.as_ref().is_some_and(|active| Arc::ptr_eq(active, &demultiplexer)) {
+            *current = None;
+        }
+    }
+}
+
+// 读取并分发消息，直到连接出错、断开或更换，返回停止的原因
+async fn read_messages(demultiplexer: &Demultiplexer, changes: &mut watch::Receiver<u64>, version: u8) -> NearbySendError {
+    loop {
+        let data = tokio::select! {
+            data = receive_data(MAX_FRAME_SIZE) => data,
+            _ = changes.changed() => Err(NearbySendError::NotConnected),
+        };
+
+        match data.and_then(|data| demultiplexer.dispatch_frame(&data, version)) {
+            Ok(Some(reply)) => send_reply(reply, version),
+            Ok(None) => {}
+            Err(e) => return e,
+        }
+    }
+}
+
+// 在后台回复对方，不阻塞读取任务
+fn send_reply(reply: TransferMessage, version: u8) {
+    tokio::spawn(async move {
+        let result = match encode_message(&reply, version) {
+            Ok(data) => send_data(&data).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            log::warn!("Failed to reply for transfer {}: {}", reply.transfer_id(), e);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transfer::codec::{encode_message, PROTOCOL_VERSION};
+
+    fn ack(id: &str, chunk_index: u32) -> TransferMessage {
+        TransferMessage::ChunkAck {
+            id: id.to_string(),
+            chunk_index,
+        }
+    }
+
+    fn ack_index(message: TransferMessage) -> u32 {
+        match message {
+            TransferMessage::ChunkAck { chunk_index, .. } => chunk_index,
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn routes_interleaved_transfers() {
+        let demultiplexer = Demultiplexer::new();
+        let mut first = demultiplexer.open_channel("first").unwrap();
+        let mut second = demultiplexer.open_channel("second").unwrap();
+        let mut batch = demultiplexer.open_channel("batch").unwrap();
+
+        for i in 0..3 {
+            demultiplexer.dispatch(ack("first", i)).unwrap();
+            demultiplexer.dispatch(ack("second", 10 + i)).unwrap();
+            demultiplexer.dispatch(ack(&format!("batch:{}", i), 20 + i)).unwrap();
+        }
+
+        for i in 0..3 {
+            assert_eq!(ack_index(first.recv().await.unwrap()), i);
+            assert_eq!(ack_index(second.recv().await.unwrap()), 10 + i);
+            assert_eq!(ack_index(batch.recv().await.unwrap()), 20 + i);
+        }
+    }
+
+    #[tokio::test]
+    async fn queues_requests_and_drops_unknown_messages() {
+        let demultiplexer = Demultiplexer::new();
+        let mut channel = demultiplexer.open_channel("known").unwrap();
+
+        demultiplexer.dispatch(ack("unknown", 0)).unwrap();
+        demultiplexer
+            .dispatch(TransferMessage::TransferCancel {
+                id: "known".to_string(),
+            })
+            .unwrap();
+        demultiplexer
+            .dispatch(TransferMessage::TransferRequest {
+                id: "incoming".to_string(),
+                file_name: "a.txt".to_string(),
+                file_size: 1,
+                fingerprint: String::new(),
+                sender_name: String::new(),
+            })
+            .unwrap();
+
+        assert!(matches!(channel.recv().await.unwrap(), TransferMessage::TransferCancel { .. }));
+        assert_eq!(demultiplexer.next_request().await.unwrap().transfer_id(), "incoming");
+    }
+
+    #[tokio::test]
+    async fn channels_unregister_and_close() {
+        let demultiplexer = Demultiplexer::new();
+        let channel = demultiplexer.open_channel("transfer").unwrap();
+        assert!(demultiplexer.open_channel("transfer").is_err());
+
+        // 释放后可以重新打开
+        drop(channel);
+        let mut channel = demultiplexer.open_channel("transfer").unwrap();
+
+        // 关闭前收到的消息仍然可以取出
+        demultiplexer.dispatch(ack("transfer", 1)).unwrap();
+        demultiplexer.close();
+        assert_eq!(ack_index(channel.recv().await.unwrap()), 1);
+        assert!(channel.recv().await.is_err());
+        assert!(demultiplexer.next_request().await.is_err());
+        assert!(demultiplexer.open_channel("other").is_err());
+    }
+
+    #[tokio::test]
+    async fn malformed_frames_are_dropped() {
+        let demultiplexer = Demultiplexer::new();
+        let mut first = demultiplexer.open_channel("first").unwrap();
+        let mut second = demultiplexer.open_channel("second").unwrap();
+
+        demultiplexer.dispatch_frame(&encode_message(&ack("first", 1), PROTOCOL_VERSION).unwrap(), PROTOCOL_VERSION).unwrap();
+
+        // 损坏的帧、旧版本的帧和截断的数据块都只丢弃该帧
+        let mut truncated = encode_message(
+            &TransferMessage::DataChunk {
+                id: "second".to_string(),
+                chunk_index: 0,
+                offset: 0,
+                checksum: 0,
+                data: vec![1, 2, 3],
+                is_last: true,
+            },
+            PROTOCOL_VERSION,
+        )
+        .unwrap();
+        truncated.truncate(truncated.len() - 2);
+        for frame in [b"garbage".to_vec(), b"{\"old\":1}".to_vec(), truncated] {
+            demultiplexer.dispatch_frame(&frame, PROTOCOL_VERSION).unwrap();
+        }
+
+        // 其他传输不受影响，分发器仍然可用
+        demultiplexer.dispatch_frame(&encode_message(&ack("second", 2), PROTOCOL_VERSION).unwrap(), PROTOCOL_VERSION).unwrap();
+        assert_eq!(ack_index(first.recv().await.unwrap()), 1);
+        assert_eq!(ack_index(second.recv().await.unwrap()), 2);
+        assert!(demultiplexer.open_channel("third").is_ok());
+    }
+
+    #[tokio::test]
+    async fn full_channel_cancels_only_that_transfer() {
+        let demultiplexer = Demultiplexer::new();
+        let mut slow = demultiplexer.open_channel("slow").unwrap();
+        let mut other = demultiplexer.open_channel("other").unwrap();
+        for i in 0..CHANNEL_CAPACITY as u32 {
+            assert!(demultiplexer.dispatch(ack("slow", i)).unwrap().is_none());
+        }
+
+        // 通道已满时不等待，只取消该传输并通知对方
+        match demultiplexer.dispatch(ack("slow", CHANNEL_CAPACITY as u32)).unwrap() {
+            Some(TransferMessage::TransferCancel { id }) => assert_eq!(id, "slow"),
+            other => panic!("unexpected reply: {:?}", other),
+        }
+        assert!(demultiplexer.dispatch(ack("slow", 0)).unwrap().is_none());
+
+        // 同一连接上的其他传输不受影响
+        demultiplexer.dispatch(ack("other", 7)).unwrap();
+        assert_eq!(ack_index(other.recv().await.unwrap()), 7);
+
+        // 被取消的传输取完已收到的消息后返回错误
+        for i in 0..CHANNEL_CAPACITY as u32 {
+            assert_eq!(ack_index(slow.recv().await.unwrap()), i);
+        }
+        assert!(slow.recv().await.unwrap_err().to_string().contains("fell behind"));
+    }
+
+    #[tokio::test]
+    async fn declines_requests_beyond_queue_capacity() {
+        let demultiplexer = Demultiplexer::new();
+        for i in 0..REQUEST_QUEUE_CAPACITY {
+            let request = TransferMessage::TransferRequest {
+                id: format!("request-{}", i),
+                file_name: "a.txt".to_string(),
+                file_size: 1,
+                fingerprint: String::new(),
+                sender_name: String::new(),
+            };
+            assert!(demultiplexer.dispatch(request).unwrap().is_none());
+        }
+
+        // 队列已满时回复拒绝，发送方不必一直等待
+        let overflow = TransferMessage::BatchTransferRequest {
+            id: "overflow".to_string(),
+            name: "2 files".to_string(),
+            files: Vec::new(),
+            sender_name: String::new(),
+        };
+        match demultiplexer.dispatch(overflow).unwrap() {
+            Some(TransferMessage::BatchTransferResponse { id, accepted, reason, .. }) => {
+                assert_eq!(id, "overflow");
+                assert!(!accepted);
+                assert!(reason.is_some());
+            }
+            other => panic!("unexpected reply: {:?}", other),
+        }
+
+        for i in 0..REQUEST_QUEUE_CAPACITY {
+            assert_eq!(demultiplexer.next_request().await.unwrap().transfer_id(), format!("request-{}", i));
+        }
+        demultiplexer.close();
+        assert!(demultiplexer.next_request().await.is_err());
+    }
+
+    #[test]
+    fn batch_files_route_to_batch() {
+        assert_eq!(route_id("batch:3"), "batch");
+        assert_eq!(route_id("single"), "single");
+    }
+}
@@ -0,0 +1,166 @@
+use crate::api::{NearbySendError, TransferEvent};
+use flutter_rust_bridge::DartFnFuture;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+
+// 同一传输两次进度事件之间的最小间隔
+pub const PROGRESS_EVENT_INTERVAL: Duration = Duration::from_millis(250);
+
+// 吞吐量的平滑系数（指数移动平均，越大越接近最近一次的速度）
+const THROUGHPUT_SMOOTHING: f64 = 0.3;
+
+// 事件通道容量（订阅者处理过慢时丢弃最早的事件）
+const EVENT_CHANNEL_CAPACITY: usize = 256;
+
+// 全局事件通道
+lazy_static::lazy_static! {
+    static ref TRANSFER_EVENTS: broadcast::Sender<TransferEvent> = broadcast::channel(EVENT_CHANNEL_CAPACITY).0;
+}
+
+// 订阅传输事件
+pub fn subscribe_transfer_events() -> broadcast::Receiver<TransferEvent> {
+    TRANSFER_EVENTS.subscribe()
+}
+
+// 发送传输事件（没有订阅者时直接丢弃）
+pub fn emit_transfer_event(event: TransferEvent) {
+    let _ = TRANSFER_EVENTS.send(event);
+}
+
+// 将传输事件持续推送给Flutter端的回调
+pub async fn listen_transfer_events(on_event: impl Fn(TransferEvent) -> DartFnFuture<()>) -> Result<(), NearbySendError> {
+    let mut events = subscribe_transfer_events();
+
+    loop {
+        match events.recv().await {
+            Ok(event) => on_event(event).await,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                log::warn!("Transfer event listener lagged, skipped {} events", skipped);
+            }
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
+        }
+    }
+}
+
+// 一次进度采样的结果
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProgressSample {
+    pub bytes_per_second: u64,
+    pub eta_seconds: Option<u64>,
+}
+
+// 单个传输的进度节流与吞吐量估算
+pub struct ProgressTracker {
+    last_sample: Instant,
+    last_bytes: u64,
+    bytes_per_second: Option<f64>,
+}
+
+impl ProgressTracker {
+    // 从已传输的字节数开始跟踪（续传时不计入之前的部分）
+    pub fn new(now: Instant, transferred_bytes: u64) -> Self {
+        Self {
+            last_sample: now,
+            last_bytes: transferred_bytes,
+            bytes_per_second: None,
+        }
+    }
+
+    // 记录新的进度，距上次采样不足间隔时返回None（传输完成时总是返回）
+    pub fn update(&mut self, now: Instant, transferred_bytes: u64, total_bytes: u64) -> Option<ProgressSample> {
+        let elapsed = now.saturating_duration_since(self.last_sample);
+        let finished = transferred_bytes >= total_bytes;
+        if elapsed < PROGRESS_EVENT_INTERVAL && !finished {
+            return None;
+        }
+
+        // 间隔太短的采样误差较大，只用于完成事件，不参与速度估算
+        if elapsed >= PROGRESS_EVENT_INTERVAL {
+            let rate = transferred_bytes.saturating_sub(self.last_bytes) as f64 / elapsed.as_secs_f64();
+            self.bytes_per_second = Some(match self.bytes_per_second {
+                Some(previous) => previous + THROUGHPUT_SMOOTHING * (rate - previous),
+                None => rate,
+            });
+            self.last_sample = now;
+            self.last_bytes = transferred_bytes;
+        }
+
+        let bytes_per_second = self.bytes_per_second.unwrap_or(0.0);
+        let remaining = total_bytes.saturating_sub(transferred_bytes);
+        let eta_seconds = if remaining == 0 {
+            Some(0)
+        } else if bytes_per_second >= 1.0 {
+            Some((remaining as f64 / bytes_per_second).ceil() as u64)
+        } else {
+            None
+        };
+
+        Some(ProgressSample {
+            bytes_per_second: bytes_per_second as u64,
+            eta_seconds,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttles_progress_samples() {
+        let start = Instant::now();
+        let mut tracker = ProgressTracker::new(start, 0);
+
+        assert_eq!(tracker.update(start + Duration::from_millis(100), 100, 10_000), None);
+
+        // 1秒传输1000字节，剩余9000字节
+        let sample = tracker.update(start + Duration::from_secs(1), 1_000, 10_000).unwrap();
+        assert_eq!(sample.bytes_per_second, 1_000);
+        assert_eq!(sample.eta_seconds, Some(9));
+
+        assert_eq!(tracker.update(start + Duration::from_millis(1_100), 1_200, 10_000), None);
+
+        // 完成时不受节流限制
+        let sample = tracker.update(start + Duration::from_millis(1_101), 10_000, 10_000).unwrap();
+        assert_eq!(sample.eta_seconds, Some(0));
+    }
+
+    #[test]
+    fn smooths_throughput() {
+        let start = Instant::now();
+        let mut tracker = ProgressTracker::new(start, 500);
+
+        // 续传前的字节不计入速度
+        let first = tracker.update(start + Duration::from_secs(1), 1_500, 100_000).unwrap();
+        assert_eq!(first.bytes_per_second, 1_000);
+
+        // 速度突然升高时平滑上升
+        let second = tracker.update(start + Duration::from_secs(2), 3_500, 100_000).unwrap();
+        assert_eq!(second.bytes_per_second, 1_300);
+
+        // 停滞时没有速度则没有ETA
+        let mut stalled = ProgressTracker::new(start, 0);
+        assert_eq!(stalled.update(start + Duration::from_secs(1), 0, 100).unwrap().eta_seconds, None);
+    }
+
+    #[tokio::test]
+    async fn broadcasts_to_subscribers() {
+        let mut events = subscribe_transfer_events();
+        emit_transfer_event(TransferEvent::Progress {
+            transfer_id: "events-test".to_string(),
+            transferred_bytes: 1,
+            total_bytes: 2,
+            bytes_per_second: 0,
+            eta_seconds: None,
+        });
+
+        // 其他测试可能同时发送事件
+        loop {
+            if let TransferEvent::Progress { transfer_id, .. } = events.recv().await.unwrap() {
+                if transfer_id == "events-test" {
+                    break;
+                }
+            }
+        }
+    }
+}
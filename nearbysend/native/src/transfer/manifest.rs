@@ -0,0 +1,133 @@
+use crate::api::NearbySendError;
+use crate::transfer::resume::compute_fingerprint;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// 清单中的单个文件
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ManifestEntry {
+    // 相对路径，统一使用'/'分隔
+    pub relative_path: String,
+    pub file_size: u64,
+    // 修改时间（Unix秒）
+    pub modified: u64,
+    pub fingerprint: String,
+}
+
+// 根据文件和目录列表创建传输清单，返回(源文件路径, 清单条目)
+pub fn build_manifest(paths: &[String]) -> Result<Vec<(String, ManifestEntry)>, NearbySendError> {
+    let mut entries = Vec::new();
+
+    for path in paths {
+        let path = Path::new(path);
+        let name = path
+            .file_name()
+            .ok_or_else(|| NearbySendError::invalid(format!("Invalid file name: {}", path.display())))?
+            .to_string_lossy()
+            .to_string();
+
+        collect_entries(path, &name, &mut entries)?;
+    }
+
+    if entries.is_empty() {
+        return Err(NearbySendError::invalid("No files to send"));
+    }
+
+    Ok(entries)
+}
+
+// 递归收集目录中的文件
+fn collect_entries(path: &Path, relative_path: &str, entries: &mut Vec<(String, ManifestEntry)>) -> Result<(), NearbySendError> {
+    // 不跟随符号链接，避免循环和发送目录之外的文件
+    let metadata = std::fs::symlink_metadata(path).map_err(|e| NearbySendError::io("Failed to get file metadata", e))?;
+
+    if metadata.is_dir() {
+        let mut children: Vec<_> = std::fs::read_dir(path)
+            .map_err(|e| NearbySendError::io("Failed to read directory", e))?
+            .flatten()
+            .collect();
+        children.sort_by_key(|entry| entry.file_name());
+
+        for child in children {
+            let child_name = child.file_name().to_string_lossy().to_string();
+            collect_entries(&child.path(), &format!("{}/{}", relative_path, child_name), entries)?;
+        }
+    } else if metadata.is_file() {
+        let source_path = path.to_string_lossy().to_string();
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+
+        entries.push((
+            source_path.clone(),
+            ManifestEntry {
+                relative_path: relative_path.to_string(),
+                file_size: metadata.len(),
+                modified,
+                fingerprint: compute_fingerprint(&source_path)?,
+            },
+        ));
+    } else {
+        log::warn!("Skipping special file: {}", path.display());
+    }
+
+    Ok(())
+}
+
+// 恢复接收文件的修改时间
+pub fn apply_modified_time(file_path: &str, modified: u64) -> Result<(), NearbySendError> {
+    if modified == 0 {
+        return Ok(());
+    }
+
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(file_path)
+        .map_err(|e| NearbySendError::io("Failed to open file", e))?;
+    file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(modified))
+        .map_err(|e| NearbySendError::io("Failed to set modification time", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifest_walks_directories() {
+        let root = std::env::temp_dir().join(format!("nearbysend-manifest-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("album/2024")).unwrap();
+        std::fs::write(root.join("album/a.jpg"), b"aaaa").unwrap();
+        std::fs::write(root.join("album/2024/b.jpg"), b"bb").unwrap();
+        std::fs::write(root.join("notes.txt"), b"n").unwrap();
+
+        let paths = vec![
+            root.join("album").to_string_lossy().to_string(),
+            root.join("notes.txt").to_string_lossy().to_string(),
+        ];
+        let manifest = build_manifest(&paths).unwrap();
+
+        let relative: Vec<_> = manifest.iter().map(|(_, entry)| entry.relative_path.as_str()).collect();
+        assert_eq!(relative, vec!["album/2024/b.jpg", "album/a.jpg", "notes.txt"]);
+        assert_eq!(manifest[0].1.file_size, 2);
+        assert!(manifest[1].1.modified > 0);
+        assert_eq!(manifest[2].0, root.join("notes.txt").to_string_lossy());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn empty_manifest_is_rejected() {
+        let root = std::env::temp_dir().join(format!("nearbysend-manifest-empty-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+
+        assert!(build_manifest(&[root.to_string_lossy().to_string()]).is_err());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,6 +1,24 @@
 pub mod protocol;
 pub mod chunking;
+pub mod codec;
+pub mod window;
+pub mod resume;
+pub mod manifest;
+pub mod paths;
+pub mod conflict;
+pub mod control;
+pub mod demux;
+pub mod events;
 
 // 重新导出模块
 pub use protocol::*;
 pub use chunking::*;
+pub use codec::*;
+pub use window::*;
+pub use resume::*;
+pub use manifest::*;
+pub use paths::*;
+pub use conflict::*;
+pub use control::*;
+pub use demux::*;
+pub use events::*;
@@ -0,0 +1,226 @@
+use crate::api::NearbySendError;
+use std::path::{Component, Path, PathBuf};
+use unicode_normalization::UnicodeNormalization;
+
+// 文件名最大长度（字节，大多数文件系统限制为255，为临时文件和续传日志的后缀预留空间）
+const MAX_FILE_NAME_BYTES: usize = 200;
+
+// Windows保留的设备名
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9", "LPT1", "LPT2",
+    "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+// 清理发送方提供的单个文件名
+pub fn sanitize_file_name(name: &str) -> Result<String, NearbySendError> {
+    // 统一为NFC形式，去掉控制字符和不可见的格式字符
+    let mut sanitized: String = name
+        .nfc()
+        .filter(|c| !c.is_control() && !is_invisible_format_char(*c))
+        .map(|c| match c {
+            // 路径分隔符以及Windows不允许的字符
+            '/' | '\\' | ':' | '<' | '>' | '"' | '|' | '?' | '*' => '_',
+            _ => c,
+        })
+        .collect();
+
+    // Windows会忽略结尾的点和空格
+    let trimmed_len = sanitized.trim_end_matches(['.', ' ']).len();
+    sanitized.truncate(trimmed_len);
+    let sanitized = sanitized.trim_start_matches(' ').to_string();
+
+    if sanitized.is_empty() || sanitized == "." || sanitized == ".." {
+        return Err(NearbySendError::protocol(format!("Invalid file name from sender: {:?}", name)));
+    }
+
+    // 拒绝保留的设备名（包括带扩展名的形式，如CON.txt）
+    let stem = sanitized.split('.').next().unwrap_or("").trim_end();
+    if RESERVED_NAMES.iter().any(|reserved| reserved.eq_ignore_ascii_case(stem)) {
+        return Err(NearbySendError::protocol(format!("Reserved file name from sender: {:?}", name)));
+    }
+
+    Ok(truncate_file_name(&sanitized))
+}
+
+// 将发送方提供的相对路径解析为保存目录下的路径，保证结果不会离开保存目录
+pub fn resolve_save_path(save_dir: &str, relative_path: &str) -> Result<PathBuf, NearbySendError> {
+    let mut path = PathBuf::from(save_dir);
+    let mut depth = 0;
+
+    // 同时按'/'和'\\'拆分，不信任发送方的分隔符
+    for component in relative_path.split(['/', '\\']) {
+        match component {
+            "" | "." => continue,
+            ".." => return Err(NearbySendError::protocol(format!("Invalid path from sender: {:?}", relative_path))),
+            _ => {
+                path.push(sanitize_file_name(component)?);
+                depth += 1;
+            }
+        }
+    }
+
+    if depth == 0 {
+        return Err(NearbySendError::protocol(format!("Invalid path from sender: {:?}", relative_path)));
+    }
+
+    ensure_within(Path::new(save_dir), &path)?;
+    Ok(path)
+}
+
+// 检查路径位于保存目录内（包括通过符号链接逃逸的情况）
+fn ensure_within(save_dir: &Path, path: &Path) -> Result<(), NearbySendError> {
+    let escaped = || NearbySendError::protocol(format!("Path escapes save directory: {}", path.display()));
+
+    // 词法检查
+    let relative = path.strip_prefix(save_dir).map_err(|_| escaped())?;
+    if relative.components().any(|c| !matches!(c, Component::Normal(_))) {
+        return Err(escaped());
+    }
+
+    // 目标文件本身不能是符号链接，否则写入时会跟随链接
+    if std::fs::symlink_metadata(path).map(|m| m.file_type().is_symlink()).unwrap_or(false) {
+        return Err(escaped());
+    }
+
+    // 已存在的上级目录解析符号链接后仍需位于保存目录内
+    let save_dir = match save_dir.canonicalize() {
+        Ok(dir) => dir,
+        Err(_) => return Ok(()),
+    };
+    let existing = path.ancestors().skip(1).find(|ancestor| ancestor.exists());
+    if let Some(existing) = existing {
+        let resolved = existing.canonicalize().map_err(|_| escaped())?;
+        if !resolved.starts_with(&save_dir) {
+            return Err(escaped());
+        }
+    }
+
+    Ok(())
+}
+
+// 不可见的格式字符（双向控制符、零宽字符等），常被用来伪装扩展名
+fn is_invisible_format_char(c: char) -> bool {
+    matches!(c, '\u{200B}'..='\u{200F}' | '\u{202A}'..='\u{202E}' | '\u{2060}'..='\u{2069}' | '\u{FEFF}')
+}
+
+// 截断过长的文件名，尽量保留扩展名
+fn truncate_file_name(name: &str) -> String {
+    if name.len() <= MAX_FILE_NAME_BYTES {
+        return name.to_string();
+    }
+
+    let (stem, extension) = match name.rfind('.') {
+        Some(index) if index > 0 && name.len() - index <= 16 => (&name[..index], &name[index..]),
+        _ => (name, ""),
+    };
+
+    let mut end = MAX_FILE_NAME_BYTES - extension.len();
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    format!("{}{}", &stem[..end], extension)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_directory_structure() {
+        assert_eq!(
+            resolve_save_path("/downloads", "album/2024/a.jpg").unwrap(),
+            PathBuf::from("/downloads/album/2024/a.jpg")
+        );
+        assert_eq!(
+            resolve_save_path("/downloads", "/album/./a.jpg").unwrap(),
+            PathBuf::from("/downloads/album/a.jpg")
+        );
+    }
+
+    #[test]
+    fn rejects_escaping_paths() {
+        assert!(resolve_save_path("/downloads", "../.bashrc").is_err());
+        assert!(resolve_save_path("/downloads", "album/../../etc/passwd").is_err());
+        assert!(resolve_save_path("/downloads", "..\\windows\\system32").is_err());
+        assert!(resolve_save_path("/downloads", "").is_err());
+        assert!(resolve_save_path("/downloads", "/").is_err());
+        assert!(resolve_save_path("/downloads", "album/CON/a.jpg").is_err());
+    }
+
+    #[test]
+    fn hostile_file_names() {
+        let cases = [
+            ("../../.bashrc", Some(".._.._.bashrc")),
+            ("/etc/passwd", Some("_etc_passwd")),
+            ("C:\\Windows\\evil.dll", Some("C__Windows_evil.dll")),
+            ("\\\\server\\share\\x", Some("__server_share_x")),
+            ("report\u{202E}fdp.exe", Some("reportfdp.exe")),
+            ("zero\u{200B}width.txt", Some("zerowidth.txt")),
+            ("nul\0byte.txt", Some("nulbyte.txt")),
+            ("line\nbreak.txt", Some("linebreak.txt")),
+            ("what?.txt", Some("what_.txt")),
+            ("trailing. . .", Some("trailing")),
+            ("  leading.txt", Some("leading.txt")),
+            ("CON", None),
+            ("con.txt", None),
+            ("Lpt1.log", None),
+            ("aux .tar.gz", None),
+            (".", None),
+            ("..", None),
+            ("", None),
+            ("   ", None),
+            ("...", None),
+            ("\u{202E}", None),
+        ];
+
+        for (name, expected) in cases {
+            match expected {
+                Some(expected) => assert_eq!(sanitize_file_name(name).unwrap(), expected, "input {:?}", name),
+                None => assert!(sanitize_file_name(name).is_err(), "input {:?} should be rejected", name),
+            }
+        }
+
+        // 单个文件名中的分隔符被替换，结果始终位于保存目录内
+        for name in ["../../.bashrc", "/etc/passwd", "a/../../b"] {
+            let sanitized = sanitize_file_name(name).unwrap();
+            let path = resolve_save_path("/downloads", &sanitized).unwrap();
+            assert_eq!(path.parent(), Some(Path::new("/downloads")));
+        }
+    }
+
+    #[test]
+    fn normalizes_unicode() {
+        // 分解形式的é被合成为单个字符
+        assert_eq!(sanitize_file_name("caf\u{0065}\u{0301}.txt").unwrap(), "caf\u{00E9}.txt");
+        assert_eq!(sanitize_file_name("照片.jpg").unwrap(), "照片.jpg");
+    }
+
+    #[test]
+    fn truncates_long_names() {
+        let long = format!("{}.jpeg", "长".repeat(200));
+        let sanitized = sanitize_file_name(&long).unwrap();
+        assert!(sanitized.len() <= MAX_FILE_NAME_BYTES);
+        assert!(sanitized.ends_with(".jpeg"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rejects_symlink_escape() {
+        let root = std::env::temp_dir().join(format!("nearbysend-paths-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let save_dir = root.join("downloads");
+        let outside = root.join("outside");
+        std::fs::create_dir_all(&save_dir).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, save_dir.join("link")).unwrap();
+        std::os::unix::fs::symlink(outside.join("target"), save_dir.join("file.txt")).unwrap();
+
+        let save_dir_str = save_dir.to_string_lossy().to_string();
+        assert!(resolve_save_path(&save_dir_str, "link/evil.txt").is_err());
+        assert!(resolve_save_path(&save_dir_str, "file.txt").is_err());
+        assert!(resolve_save_path(&save_dir_str, "safe/ok.txt").is_ok());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
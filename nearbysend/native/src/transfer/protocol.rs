@@ -1,68 +1,370 @@
+use crate::api::ConflictPolicy;
+use crate::api::NearbySendError;
+use crate::api::FileConflict;
 use crate::api::FileTransfer;
-use crate::api::TransferStatus;
-use crate::connection::wifi_direct::{send_data, receive_data};
+use crate::api::{get_device_name, IncomingFile, IncomingTransferRequest};
+use crate::api::ResumableTransfer;
+use crate::api::{TransferEvent, TransferStatus};
+use crate::connection::handshake::{CAPABILITY_BATCH, CAPABILITY_CANCEL, CAPABILITY_PAUSE, CAPABILITY_RESUME};
+use crate::connection::wifi_direct::{get_peer_address, get_protocol_version, require_peer_capability, send_data};
+use crate::transfer::chunking::{chunk_checksum, partial_file_path, FileAssembler, FileChunker};
+use crate::transfer::codec::encode_message;
+use crate::transfer::conflict::next_available_path;
+use crate::transfer::control::TransferControl;
+use crate::transfer::demux::{connection_demultiplexer, TransferChannel};
+use crate::transfer::events::{emit_transfer_event, ProgressTracker};
+use crate::transfer::manifest::{apply_modified_time, build_manifest, ManifestEntry};
+use crate::transfer::paths::{resolve_save_path, sanitize_file_name};
+use crate::transfer::resume::{compute_fingerprint, list_resume_journals, ResumeJournal};
+use crate::transfer::window::{ChunkVerdict, ReceiveTracker, SendWindow, DEFAULT_WINDOW_SIZE, MAX_WINDOW_SIZE};
 use serde::{Deserialize, Serialize};
-use std::fs::File;
-use std::io::{Read, Write};
-use std::path::Path;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
 use tokio::time;
 use uuid::Uuid;
 
 // 传输消息类型
 #[derive(Serialize, Deserialize, Debug)]
-enum TransferMessage {
+pub enum TransferMessage {
     // 传输请求
     TransferRequest {
         id: String,
         file_name: String,
         file_size: u64,
+        fingerprint: String,
+        // 发送方设备名称，显示给接收方确认
+        #[serde(default)]
+        sender_name: String,
     },
-    // 传输响应
+    // 传输响应（resume_offset为接收方已有的字节数，发送方从此处继续）
     TransferResponse {
         id: String,
         accepted: bool,
+        resume_offset: u64,
+        // 因文件名冲突被接收方跳过（与批量传输中的skipped相同，不发送数据）
+        #[serde(default)]
+        skipped: bool,
+        // 拒绝原因
+        #[serde(default)]
+        reason: Option<String>,
     },
-    // 数据块
+    // 批量传输请求（多个文件或目录，files为带相对路径的清单）
+    BatchTransferRequest {
+        id: String,
+        name: String,
+        files: Vec<ManifestEntry>,
+        #[serde(default)]
+        sender_name: String,
+    },
+    // 批量传输响应（resume_offsets和skipped与清单中的文件一一对应）
+    BatchTransferResponse {
+        id: String,
+        accepted: bool,
+        resume_offsets: Vec<u64>,
+        // 因文件名冲突被接收方跳过的文件
+        #[serde(default)]
+        skipped: Vec<bool>,
+        #[serde(default)]
+        reason: Option<String>,
+    },
+    // 数据块（offset为数据在文件中的位置，checksum为数据的CRC32）
     DataChunk {
         id: String,
         chunk_index: u32,
+        offset: u64,
+        checksum: u32,
         data: Vec<u8>,
         is_last: bool,
     },
-    // 确认接收
+    // 确认接收（累计确认chunk_index及之前的所有数据块）
     ChunkAck {
         id: String,
         chunk_index: u32,
     },
-    // 传输完成
+    // 否定确认（请求重传单个损坏的数据块）
+    ChunkNack {
+        id: String,
+        chunk_index: u32,
+    },
+    // 传输完成（hash为发送方计算的整个文件的BLAKE3哈希）
     TransferComplete {
         id: String,
         success: bool,
+        hash: String,
+    },
+    // 校验结果（接收方校验哈希后回复）
+    TransferVerified {
+        id: String,
+        verified: bool,
+    },
+    // 取消传输（任意一方发送，对方回复同样的消息作为确认；批量传输使用整批的ID）
+    TransferCancel {
+        id: String,
+    },
+    // 暂停传输（发送方停止发送新的数据块，连接保持；generation为发起方改变后的暂停代数，对方不回复）
+    TransferPause {
+        id: String,
+        #[serde(default)]
+        generation: u64,
+    },
+    // 恢复传输
+    TransferResume {
+        id: String,
+        #[serde(default)]
+        generation: u64,
     },
 }
 
+impl TransferMessage {
+    // 消息所属的传输ID
+    pub fn transfer_id(&self) -> &str {
+        match self {
+            TransferMessage::TransferRequest { id, .. }
+            | TransferMessage::TransferResponse { id, .. }
+            | TransferMessage::BatchTransferRequest { id, .. }
+            | TransferMessage::BatchTransferResponse { id, .. }
+            | TransferMessage::DataChunk { id, .. }
+            | TransferMessage::ChunkAck { id, .. }
+            | TransferMessage::ChunkNack { id, .. }
+            | TransferMessage::TransferComplete { id, .. }
+            | TransferMessage::TransferVerified { id, .. }
+            | TransferMessage::TransferCancel { id }
+            | TransferMessage::TransferPause { id, .. }
+            | TransferMessage::TransferResume { id, .. } => id,
+        }
+    }
+
+    // 拒绝传输请求的响应（不是传输请求时为None）
+    pub fn decline(&self, reason: &str) -> Option<TransferMessage> {
+        match self {
+            TransferMessage::TransferRequest { id, .. } => Some(TransferMessage::TransferResponse {
+                id: id.clone(),
+                accepted: false,
+                resume_offset: 0,
+                skipped: false,
+                reason: Some(reason.to_string()),
+            }),
+            TransferMessage::BatchTransferRequest { id, .. } => Some(TransferMessage::BatchTransferResponse {
+                id: id.clone(),
+                accepted: false,
+                resume_offsets: Vec::new(),
+                skipped: Vec::new(),
+                reason: Some(reason.to_string()),
+            }),
+            _ => None,
+        }
+    }
+}
+
+// 每接收多少个数据块同步一次续传日志
+const JOURNAL_SYNC_INTERVAL: u32 = 16;
+
+// 等待用户处理文件名冲突的超时时间（超时后跳过该文件）
+const CONFLICT_DECISION_TIMEOUT: Duration = Duration::from_secs(60);
+
+// 等待用户确认传输请求的超时时间（超时后拒绝）
+const REQUEST_DECISION_TIMEOUT: Duration = Duration::from_secs(60);
+
+// 发送方等待传输响应的超时时间（包括接收方用户确认请求和处理文件名冲突的时间）
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(300);
+
+// 等待对方确认取消的超时时间
+const CANCEL_ACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+// 等待用户决定的文件名冲突，以及用于回复决定的通道
+type PendingConflict = (FileConflict, oneshot::Sender<ConflictPolicy>);
+
+// 等待用户确认的传输请求，以及用于回复决定的通道（None表示接受，否则为拒绝原因）
+type PendingRequest = (IncomingTransferRequest, oneshot::Sender<Option<String>>);
+
 // 全局传输状态
 lazy_static::lazy_static! {
     static ref CURRENT_TRANSFERS: Arc<Mutex<Vec<FileTransfer>>> = Arc::new(Mutex::new(Vec::new()));
+    static ref SEND_WINDOW_SIZE: Arc<Mutex<usize>> = Arc::new(Mutex::new(DEFAULT_WINDOW_SIZE));
+    static ref OUTGOING_FILES: Arc<Mutex<HashMap<String, String>>> = Arc::new(Mutex::new(HashMap::new()));
+    static ref CONFLICT_POLICY: Arc<Mutex<ConflictPolicy>> = Arc::new(Mutex::new(ConflictPolicy::Rename));
+    static ref PENDING_CONFLICTS: Arc<Mutex<HashMap<String, PendingConflict>>> = Arc::new(Mutex::new(HashMap::new()));
+    static ref PENDING_REQUESTS: Arc<Mutex<HashMap<String, PendingRequest>>> = Arc::new(Mutex::new(HashMap::new()));
+    static ref TRANSFER_CONTROLS: Arc<Mutex<HashMap<String, Arc<TransferControl>>>> = Arc::new(Mutex::new(HashMap::new()));
+    static ref PROGRESS_TRACKERS: Arc<Mutex<HashMap<String, ProgressTracker>>> = Arc::new(Mutex::new(HashMap::new()));
+}
+
+// 设置发送窗口大小（同时在途的数据块数量）
+pub fn set_send_window_size(window_size: usize) -> Result<(), NearbySendError> {
+    if window_size == 0 || window_size > MAX_WINDOW_SIZE {
+        return Err(NearbySendError::invalid(format!("Window size must be between 1 and {}", MAX_WINDOW_SIZE)));
+    }
+    
+    let mut size = SEND_WINDOW_SIZE.lock()?;
+    *size = window_size;
+    Ok(())
+}
+
+// 获取发送窗口大小
+fn get_send_window_size() -> Result<usize, NearbySendError> {
+    let size = SEND_WINDOW_SIZE.lock()?;
+    Ok(*size)
+}
+
+// 设置文件名冲突策略
+pub fn set_conflict_policy(policy: ConflictPolicy) -> Result<(), NearbySendError> {
+    let mut current = CONFLICT_POLICY.lock()?;
+    *current = policy;
+    Ok(())
+}
+
+// 获取文件名冲突策略
+fn get_conflict_policy() -> Result<ConflictPolicy, NearbySendError> {
+    let policy = CONFLICT_POLICY.lock()?;
+    Ok(policy.clone())
+}
+
+// 获取等待用户决定的文件名冲突
+pub fn get_pending_conflicts() -> Result<Vec<FileConflict>, NearbySendError> {
+    let pending = PENDING_CONFLICTS.lock()?;
+    Ok(pending.values().map(|(conflict, _)| conflict.clone()).collect())
+}
+
+// 用户决定如何处理文件名冲突（重命名、覆盖或跳过）
+pub fn resolve_file_conflict(transfer_id: &str, policy: ConflictPolicy) -> Result<(), NearbySendError> {
+    if policy == ConflictPolicy::Ask {
+        return Err(NearbySendError::invalid("Conflict resolution must be rename, overwrite or skip"));
+    }
+    
+    let (_, sender) = {
+        let mut pending = PENDING_CONFLICTS.lock()?;
+        pending.remove(transfer_id).ok_or_else(|| NearbySendError::not_found(format!("No pending conflict for transfer: {}", transfer_id)))?
+    };
+    
+    sender.send(policy).map_err(|_| NearbySendError::invalid(format!("Conflict for transfer {} is no longer pending", transfer_id)))
+}
+
+// 获取等待用户确认的传输请求
+pub fn get_pending_requests() -> Result<Vec<IncomingTransferRequest>, NearbySendError> {
+    let pending = PENDING_REQUESTS.lock()?;
+    Ok(pending.values().map(|(request, _)| request.clone()).collect())
+}
+
+// 接受传输请求
+pub fn accept_transfer(transfer_id: &str) -> Result<(), NearbySendError> {
+    answer_request(transfer_id, None)
+}
+
+// 拒绝传输请求，原因会返回给发送方
+pub fn reject_transfer(transfer_id: &str, reason: Option<String>) -> Result<(), NearbySendError> {
+    answer_request(transfer_id, Some(reason.unwrap_or_else(|| "Declined by user".to_string())))
+}
+
+// 回复等待确认的传输请求
+fn answer_request(transfer_id: &str, decision: Option<String>) -> Result<(), NearbySendError> {
+    let (_, sender) = {
+        let mut pending = PENDING_REQUESTS.lock()?;
+        pending.remove(transfer_id).ok_or_else(|| NearbySendError::not_found(format!("No pending request for transfer: {}", transfer_id)))?
+    };
+    
+    sender.send(decision).map_err(|_| NearbySendError::invalid(format!("Request for transfer {} is no longer pending", transfer_id)))
+}
+
+// 取消传输（批量传输中的单个文件会取消整批）
+pub async fn cancel_transfer(transfer_id: &str) -> Result<(), NearbySendError> {
+    // 尚未确认的传输请求直接拒绝
+    let pending = PENDING_REQUESTS.lock()?.contains_key(transfer_id);
+    if pending {
+        return answer_request(transfer_id, Some("Cancelled by receiver".to_string()));
+    }
+    
+    // 通知对方，传输任务收到对方的确认或在处理下一条消息前发现取消标志后结束
+    require_peer_capability(CAPABILITY_CANCEL)?;
+    let control = find_transfer_control(transfer_id)?;
+    if control.cancel() {
+        send_message(&TransferMessage::TransferCancel { id: control.transfer_id().to_string() }).await?;
+    }
+    
+    Ok(())
+}
+
+// 暂停传输（批量传输中的单个文件会暂停整批）
+pub async fn pause_transfer(transfer_id: &str) -> Result<(), NearbySendError> {
+    set_paused(transfer_id, true).await
+}
+
+// 恢复暂停的传输
+pub async fn resume_transfer(transfer_id: &str) -> Result<(), NearbySendError> {
+    set_paused(transfer_id, false).await
+}
+
+// 改变暂停状态并通知对方
+async fn set_paused(transfer_id: &str, paused: bool) -> Result<(), NearbySendError> {
+    require_peer_capability(CAPABILITY_PAUSE)?;
+    
+    let control = find_transfer_control(transfer_id)?;
+    if control.is_cancelled() {
+        return Err(NearbySendError::invalid(format!("Transfer is being cancelled: {}", transfer_id)));
+    }
+    
+    if let Some(generation) = control.set_paused(paused) {
+        set_transfer_paused(control.transfer_id(), paused)?;
+        send_message(&pause_message(control.transfer_id(), paused, generation)).await?;
+    }
+    
+    Ok(())
+}
+
+// 获取正在进行的传输的控制（批量传输中的单个文件使用整批的控制）
+fn find_transfer_control(transfer_id: &str) -> Result<Arc<TransferControl>, NearbySendError> {
+    let target_id = get_transfer(transfer_id)?.parent_id.unwrap_or_else(|| transfer_id.to_string());
+    let controls = TRANSFER_CONTROLS.lock()?;
+    controls.get(&target_id).cloned().ok_or_else(|| NearbySendError::invalid(format!("Transfer is not active: {}", transfer_id)))
+}
+
+// 注册传输控制，供正在进行的传输任务检查
+fn register_transfer_control(transfer_id: &str) -> Result<Arc<TransferControl>, NearbySendError> {
+    let control = Arc::new(TransferControl::new(transfer_id));
+    let mut controls = TRANSFER_CONTROLS.lock()?;
+    controls.insert(transfer_id.to_string(), control.clone());
+    Ok(control)
+}
+
+// 传输任务结束后移除传输控制
+fn unregister_transfer_control(transfer_id: &str) {
+    match TRANSFER_CONTROLS.lock() {
+        Ok(mut controls) => {
+            controls.remove(transfer_id);
+        }
+        Err(e) => log::error!("Failed to unregister transfer control: {}", e),
+    }
+}
+
+// 传输任务出错后更新状态（被取消的传输标记为Cancelled，其他标记为Failed）
+fn mark_transfers_interrupted(transfer_ids: &[&str], control: &TransferControl) {
+    let status = if control.is_cancelled() { TransferStatus::Cancelled } else { TransferStatus::Failed };
+    
+    for transfer_id in transfer_ids {
+        if let Err(e) = update_transfer_status(transfer_id, status.clone()) {
+            log::error!("Failed to update transfer status: {}", e);
+        }
+    }
 }
 
 // 发送文件
-pub async fn send_file(file_path: &str) -> Result<String, String> {
+pub async fn send_file(file_path: &str) -> Result<String, NearbySendError> {
     // 检查文件是否存在
     let path = Path::new(file_path);
     if !path.exists() {
-        return Err(format!("File not found: {}", file_path));
+        return Err(NearbySendError::not_found(format!("File not found: {}", file_path)));
     }
     
     // 获取文件信息
     let file_name = path.file_name()
-        .ok_or_else(|| "Invalid file name".to_string())?
+        .ok_or_else(|| NearbySendError::invalid("Invalid file name"))?
         .to_string_lossy()
         .to_string();
     
     let file_size = std::fs::metadata(path)
-        .map_err(|e| format!("Failed to get file metadata: {}", e))?
+        .map_err(|e| NearbySendError::io("Failed to get file metadata", e))?
         .len();
     
     // 创建传输ID
@@ -75,316 +377,1478 @@ pub async fn send_file(file_path: &str) -> Result<String, String> {
         file_size,
         transferred_bytes: 0,
         status: TransferStatus::Pending,
+        parent_id: None,
+        save_path: None,
     };
     
     // 添加到传输列表
+    upsert_transfer(transfer)?;
+    
+    // 记录源文件路径，以便连接中断后续传
     {
-        let mut transfers = CURRENT_TRANSFERS.lock().map_err(|e| e.to_string())?;
-        transfers.push(transfer);
+        let mut outgoing = OUTGOING_FILES.lock()?;
+        outgoing.insert(transfer_id.clone(), file_path.to_string());
+    }
+    
+    offer_file(file_path, &transfer_id, file_name, file_size).await
+}
+
+// 在一次会话中发送多个文件或目录（接收方一次同意即可接收整批文件）
+pub async fn send_files(paths: Vec<String>) -> Result<String, NearbySendError> {
+    require_peer_capability(CAPABILITY_BATCH)?;
+    
+    // 创建传输清单
+    let manifest = build_manifest(&paths)?;
+    
+    // 创建传输ID
+    let batch_id = Uuid::new_v4().to_string();
+    let name = match paths.as_slice() {
+        [path] => Path::new(path).file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default(),
+        _ => format!("{} files", manifest.len()),
+    };
+    let total_size = manifest.iter().map(|(_, entry)| entry.file_size).sum();
+    
+    // 创建整批和每个文件的传输对象
+    upsert_transfer(FileTransfer {
+        id: batch_id.clone(),
+        file_name: name.clone(),
+        file_size: total_size,
+        transferred_bytes: 0,
+        status: TransferStatus::Pending,
+        parent_id: None,
+        save_path: None,
+    })?;
+    
+    for (index, (_, entry)) in manifest.iter().enumerate() {
+        upsert_transfer(FileTransfer {
+            id: batch_file_id(&batch_id, index),
+            file_name: entry.relative_path.clone(),
+            file_size: entry.file_size,
+            transferred_bytes: 0,
+            status: TransferStatus::Pending,
+            parent_id: Some(batch_id.clone()),
+            save_path: None,
+        })?;
+    }
+    
+    // 创建传输请求
+    let request = TransferMessage::BatchTransferRequest {
+        id: batch_id.clone(),
+        name,
+        files: manifest.iter().map(|(_, entry)| entry.clone()).collect(),
+        sender_name: get_device_name(),
+    };
+    
+    // 先打开消息通道，再发送请求
+    let mut channel = connection_demultiplexer()?.open_channel(&batch_id)?;
+    send_message(&request).await?;
+    
+    // 更新传输状态
+    update_transfer_status(&batch_id, TransferStatus::Connecting)?;
+    
+    // 等待响应
+    let response = match recv_response(&mut channel, RESPONSE_TIMEOUT).await {
+        Ok(response) => response,
+        Err(e) => {
+            update_transfer_status(&batch_id, TransferStatus::Failed)?;
+            return Err(e);
+        }
+    };
+    
+    match response {
+        TransferMessage::BatchTransferResponse { id, accepted, resume_offsets, skipped, reason } if id == batch_id => {
+            if !accepted {
+                update_transfer_status(&batch_id, TransferStatus::Failed)?;
+                return Err(NearbySendError::Rejected { reason });
+            }
+            
+            if resume_offsets.len() != manifest.len() || (!skipped.is_empty() && skipped.len() != manifest.len()) {
+                update_transfer_status(&batch_id, TransferStatus::Failed)?;
+                return Err(NearbySendError::protocol("Invalid response from receiver"));
+            }
+            
+            // 按清单顺序依次发送文件
+            let task_id = batch_id.clone();
+            let control = register_transfer_control(&batch_id)?;
+            tokio::spawn(async move {
+                if let Err(e) = update_transfer_status(&task_id, TransferStatus::Transferring) {
+                    log::error!("Failed to update transfer status: {}", e);
+                }
+                
+                let mut completed = true;
+                for (index, ((source_path, _), resume_offset)) in manifest.into_iter().zip(resume_offsets).enumerate() {
+                    let file_id = batch_file_id(&task_id, index);
+                    
+                    // 接收方已有同名文件并选择跳过
+                    if skipped.get(index).copied().unwrap_or(false) {
+                        if let Err(e) = update_transfer_status(&file_id, TransferStatus::Skipped) {
+                            log::error!("Failed to update transfer status: {}", e);
+                        }
+                        continue;
+                    }
+                    
+                    if let Err(e) = send_file_chunks(&source_path, &file_id, resume_offset, &control, &mut channel).await {
+                        log::error!("Failed to send file {}: {}", source_path, e);
+                        mark_transfers_interrupted(&[&file_id, &task_id], &control);
+                        completed = false;
+                        break;
+                    }
+                }
+                
+                if completed {
+                    if let Err(e) = update_transfer_status(&task_id, TransferStatus::Completed) {
+                        log::error!("Failed to update transfer status: {}", e);
+                    }
+                }
+                
+                unregister_transfer_control(&task_id);
+            });
+            
+            Ok(batch_id)
+        }
+        _ => {
+            update_transfer_status(&batch_id, TransferStatus::Failed)?;
+            Err(NearbySendError::protocol("Invalid response from receiver"))
+        }
     }
+}
+
+// 批量传输中单个文件的传输ID
+fn batch_file_id(batch_id: &str, index: usize) -> String {
+    format!("{}:{}", batch_id, index)
+}
+
+// 重新发起一个因连接中断而失败的发送（接收方根据续传日志返回续传偏移量）
+pub async fn resume_interrupted_send(transfer_id: &str) -> Result<String, NearbySendError> {
+    require_peer_capability(CAPABILITY_RESUME)?;
+    
+    // 获取源文件路径
+    let file_path = {
+        let outgoing = OUTGOING_FILES.lock()?;
+        outgoing.get(transfer_id).cloned().ok_or_else(|| NearbySendError::not_found(format!("Transfer not found: {}", transfer_id)))?
+    };
+    
+    // 只有失败的传输可以续传
+    let transfer = get_transfer(transfer_id)?;
+    if !matches!(transfer.status, TransferStatus::Failed) {
+        return Err(NearbySendError::invalid(format!("Transfer is not interrupted: {}", transfer_id)));
+    }
+    
+    // 文件可能已被修改，重新获取文件大小
+    let file_size = std::fs::metadata(&file_path)
+        .map_err(|e| NearbySendError::io("Failed to get file metadata", e))?
+        .len();
+    
+    let file_name = transfer.file_name.clone();
+    upsert_transfer(FileTransfer {
+        file_size,
+        status: TransferStatus::Pending,
+        ..transfer
+    })?;
+    
+    offer_file(&file_path, transfer_id, file_name, file_size).await
+}
+
+// 放弃一个因连接中断而失败的发送
+pub fn discard_interrupted_send(transfer_id: &str) -> Result<(), NearbySendError> {
+    let mut outgoing = OUTGOING_FILES.lock()?;
+    outgoing.remove(transfer_id);
+    Ok(())
+}
+
+// 发送传输请求，接收方同意后开始发送文件块
+async fn offer_file(file_path: &str, transfer_id: &str, file_name: String, file_size: u64) -> Result<String, NearbySendError> {
+    // 计算内容指纹，接收方用它判断能否续传
+    let fingerprint = compute_fingerprint(file_path)?;
     
     // 创建传输请求
     let request = TransferMessage::TransferRequest {
-        id: transfer_id.clone(),
+        id: transfer_id.to_string(),
         file_name,
         file_size,
+        fingerprint,
+        sender_name: get_device_name(),
     };
     
-    // 序列化请求
-    let request_data = serde_json::to_vec(&request).map_err(|e| e.to_string())?;
-    
-    // 发送请求
-    send_data(&request_data).await?;
+    // 先打开消息通道，再发送请求
+    let mut channel = connection_demultiplexer()?.open_channel(transfer_id)?;
+    send_message(&request).await?;
     
     // 更新传输状态
-    update_transfer_status(&transfer_id, TransferStatus::Connecting)?;
+    update_transfer_status(transfer_id, TransferStatus::Connecting)?;
     
     // 等待响应
-    let response_data = receive_data(1024).await?;
-    let response: TransferMessage = serde_json::from_slice(&response_data).map_err(|e| e.to_string())?;
+    let response = match recv_response(&mut channel, RESPONSE_TIMEOUT).await {
+        Ok(response) => response,
+        Err(e) => {
+            update_transfer_status(transfer_id, TransferStatus::Failed)?;
+            return Err(e);
+        }
+    };
     
     match response {
-        TransferMessage::TransferResponse { id, accepted } if id == transfer_id => {
+        TransferMessage::TransferResponse { id, accepted, skipped, .. } if id == transfer_id && accepted && skipped => {
+            // 接收方已有同名文件并选择跳过
+            log::info!("Transfer {} skipped by receiver", transfer_id);
+            update_transfer_status(transfer_id, TransferStatus::Skipped)?;
+            discard_interrupted_send(transfer_id)?;
+            Ok(transfer_id.to_string())
+        }
+        TransferMessage::TransferResponse { id, accepted, resume_offset, reason, .. } if id == transfer_id => {
             if accepted {
+                if resume_offset > 0 {
+                    log::info!("Resuming transfer {} at byte {}", transfer_id, resume_offset);
+                }
+                
                 // 开始传输文件
+                let file_path = file_path.to_string();
+                let task_id = transfer_id.to_string();
+                let control = register_transfer_control(transfer_id)?;
                 tokio::spawn(async move {
-                    if let Err(e) = send_file_chunks(file_path, &transfer_id).await {
+                    if let Err(e) = send_file_chunks(&file_path, &task_id, resume_offset, &control, &mut channel).await {
                         log::error!("Failed to send file: {}", e);
-                        if let Err(e) = update_transfer_status(&transfer_id, TransferStatus::Failed) {
-                            log::error!("Failed to update transfer status: {}", e);
-                        }
+                        mark_transfers_interrupted(&[&task_id], &control);
                     }
+                    
+                    unregister_transfer_control(&task_id);
                 });
                 
-                Ok(transfer_id)
+                Ok(transfer_id.to_string())
             } else {
-                update_transfer_status(&transfer_id, TransferStatus::Failed)?;
-                Err("Transfer rejected by receiver".to_string())
+                update_transfer_status(transfer_id, TransferStatus::Failed)?;
+                Err(NearbySendError::Rejected { reason })
             }
         }
         _ => {
-            update_transfer_status(&transfer_id, TransferStatus::Failed)?;
-            Err("Invalid response from receiver".to_string())
+            update_transfer_status(transfer_id, TransferStatus::Failed)?;
+            Err(NearbySendError::protocol("Invalid response from receiver"))
         }
     }
 }
 
-// 接收文件
-pub async fn receive_file(save_dir: &str) -> Result<String, String> {
-    // 接收传输请求
-    let request_data = receive_data(1024).await?;
-    let request: TransferMessage = serde_json::from_slice(&request_data).map_err(|e| e.to_string())?;
+// 接收文件（单个文件或一批文件）
+pub async fn receive_file(save_dir: &str) -> Result<String, NearbySendError> {
+    // 等待连接上的下一个传输请求
+    let demultiplexer = connection_demultiplexer()?;
+    let request = demultiplexer.next_request().await?;
     
     match request {
-        TransferMessage::TransferRequest { id, file_name, file_size } => {
-            // 创建传输对象
-            let transfer = FileTransfer {
-                id: id.clone(),
-                file_name: file_name.clone(),
-                file_size,
-                transferred_bytes: 0,
-                status: TransferStatus::Pending,
+        TransferMessage::TransferRequest { id, file_name, file_size, fingerprint, sender_name } => {
+            let mut channel = demultiplexer.open_channel(&id)?;
+            
+            // 清理文件名并创建保存路径，不安全的文件名直接拒绝
+            let resolved = sanitize_file_name(&file_name)
+                .and_then(|name| Ok((resolve_save_path(save_dir, &name)?, name)));
+            let (save_path, file_name) = match resolved {
+                Ok(resolved) => resolved,
+                Err(e) => {
+                    decline_transfer(&id, &e.to_string()).await?;
+                    return Err(e);
+                }
             };
             
-            // 添加到传输列表
-            {
-                let mut transfers = CURRENT_TRANSFERS.lock().map_err(|e| e.to_string())?;
-                transfers.push(transfer);
+            // 等待用户确认（续传已同意过的传输时不再询问）
+            let resuming = ResumeJournal::load(&save_path.to_string_lossy())
+                .is_some_and(|journal| journal.matches(&id, &fingerprint, file_size));
+            if !resuming {
+                let request = IncomingTransferRequest {
+                    id: id.clone(),
+                    sender_name,
+                    sender_address: get_peer_address()?,
+                    files: vec![IncomingFile { file_name: file_name.clone(), file_size }],
+                    total_size: file_size,
+                };
+                
+                if let Some(reason) = wait_for_decision(request, REQUEST_DECISION_TIMEOUT).await? {
+                    decline_transfer(&id, &reason).await?;
+                    return Err(NearbySendError::Rejected { reason: Some(reason) });
+                }
             }
             
-            // 创建保存路径
-            let save_path = Path::new(save_dir).join(&file_name);
+            // 按冲突策略处理同名文件
+            let policy = get_conflict_policy()?;
+            let chosen = choose_save_path(&id, &file_name, file_size, &fingerprint, save_path.clone(), &HashSet::new(), policy).await?;
+            let save_path = match chosen {
+                Some(path) => path.to_string_lossy().to_string(),
+                None => {
+                    skip_incoming_file(&id, &file_name, file_size, &save_path, None)?;
+                    send_message(&TransferMessage::TransferResponse {
+                        id: id.clone(),
+                        accepted: true,
+                        resume_offset: 0,
+                        skipped: true,
+                        reason: None,
+                    })
+                    .await?;
+                    return Ok(id);
+                }
+            };
+            
+            // 创建传输对象和续传日志
+            let journal = prepare_incoming_file(&id, &file_name, file_size, &fingerprint, save_path, None)?;
+            let resume_offset = journal.received_bytes;
             
             // 创建响应
             let response = TransferMessage::TransferResponse {
                 id: id.clone(),
                 accepted: true,
+                resume_offset,
+                skipped: false,
+                reason: None,
             };
             
-            // 序列化响应
-            let response_data = serde_json::to_vec(&response).map_err(|e| e.to_string())?;
-            
             // 发送响应
-            send_data(&response_data).await?;
+            send_message(&response).await?;
             
             // 更新传输状态
             update_transfer_status(&id, TransferStatus::Transferring)?;
             
             // 开始接收文件
+            let task_id = id.clone();
+            let control = register_transfer_control(&id)?;
             tokio::spawn(async move {
-                if let Err(e) = receive_file_chunks(&id, save_path.to_string_lossy().to_string()).await {
+                if let Err(e) = receive_file_chunks(&task_id, journal, &control, &mut channel).await {
                     log::error!("Failed to receive file: {}", e);
-                    if let Err(e) = update_transfer_status(&id, TransferStatus::Failed) {
-                        log::error!("Failed to update transfer status: {}", e);
-                    }
+                    mark_transfers_interrupted(&[&task_id], &control);
                 }
+                
+                unregister_transfer_control(&task_id);
             });
             
             Ok(id)
         }
-        _ => Err("Invalid request from sender".to_string()),
-    }
-}
-
-// 发送文件块
-async fn send_file_chunks(file_path: &str, transfer_id: &str) -> Result<(), String> {
-    // 打开文件
-    let mut file = File::open(file_path).map_err(|e| format!("Failed to open file: {}", e))?;
-    
-    // 获取文件大小
-    let file_size = file.metadata().map_err(|e| format!("Failed to get file metadata: {}", e))?.len();
-    
-    // 更新传输状态
-    update_transfer_status(transfer_id, TransferStatus::Transferring)?;
-    
-    // 设置块大小
-    const CHUNK_SIZE: usize = 64 * 1024; // 64KB
-    
-    // 创建缓冲区
-    let mut buffer = vec![0u8; CHUNK_SIZE];
-    let mut transferred: u64 = 0;
-    let mut chunk_index: u32 = 0;
-    
-    // 循环发送文件块
-    loop {
-        // 读取文件块
-        let n = file.read(&mut buffer).map_err(|e| format!("Failed to read file: {}", e))?;
-        
-        if n == 0 {
-            // 文件已读取完毕
-            break;
-        }
-        
-        // 是否为最后一块
-        let is_last = transferred + n as u64 >= file_size;
-        
-        // 创建数据块消息
-        let chunk = TransferMessage::DataChunk {
-            id: transfer_id.to_string(),
-            chunk_index,
-            data: buffer[..n].to_vec(),
-            is_last,
-        };
-        
-        // 序列化数据块
-        let chunk_data = serde_json::to_vec(&chunk).map_err(|e| e.to_string())?;
-        
-        // 发送数据块
-        send_data(&chunk_data).await?;
-        
-        // 等待确认
-        let ack_data = receive_data(1024).await?;
-        let ack: TransferMessage = serde_json::from_slice(&ack_data).map_err(|e| e.to_string())?;
-        
-        match ack {
-            TransferMessage::ChunkAck { id, chunk_index: ack_index } if id == transfer_id && ack_index == chunk_index => {
-                // 更新传输进度
-                transferred += n as u64;
-                update_transfer_progress(transfer_id, transferred)?;
-                
-                // 增加块索引
-                chunk_index += 1;
+        TransferMessage::BatchTransferRequest { id, name, files, sender_name } => {
+            let mut channel = demultiplexer.open_channel(&id)?;
+            
+            // 先校验所有路径，任何一个不安全都拒绝整批
+            let resolved: Result<Vec<_>, NearbySendError> = files
+                .iter()
+                .map(|entry| resolve_save_path(save_dir, &entry.relative_path))
+                .collect();
+            let save_paths = match resolved {
+                Ok(save_paths) => save_paths,
+                Err(e) => {
+                    decline_batch_transfer(&id, &e.to_string()).await?;
+                    return Err(e);
+                }
+            };
+            
+            // 等待用户确认整批文件
+            let total_size = files.iter().map(|entry| entry.file_size).sum();
+            let request = IncomingTransferRequest {
+                id: id.clone(),
+                sender_name,
+                sender_address: get_peer_address()?,
+                files: files
+                    .iter()
+                    .map(|entry| IncomingFile { file_name: entry.relative_path.clone(), file_size: entry.file_size })
+                    .collect(),
+                total_size,
+            };
+            
+            if let Some(reason) = wait_for_decision(request, REQUEST_DECISION_TIMEOUT).await? {
+                decline_batch_transfer(&id, &reason).await?;
+                return Err(NearbySendError::Rejected { reason: Some(reason) });
+            }
+            
+            // 创建整批的传输对象
+            upsert_transfer(FileTransfer {
+                id: id.clone(),
+                file_name: name,
+                file_size: total_size,
+                transferred_bytes: 0,
+                status: TransferStatus::Pending,
+                parent_id: None,
+                save_path: None,
+            })?;
+            
+            // 为每个文件创建传输对象和续传日志，按冲突策略处理同名文件
+            let mut journals = Vec::with_capacity(files.len());
+            let mut claimed = HashSet::new();
+            let policy = get_conflict_policy()?;
+            for (index, (entry, save_path)) in files.iter().zip(save_paths).enumerate() {
+                let file_id = batch_file_id(&id, index);
                 
-                // 如果是最后一块，发送传输完成消息
-                if is_last {
-                    let complete = TransferMessage::TransferComplete {
-                        id: transfer_id.to_string(),
-                        success: true,
+                let chosen = choose_save_path(&file_id, &entry.relative_path, entry.file_size, &entry.fingerprint, save_path.clone(), &claimed, policy.clone()).await?;
+                match chosen {
+                    Some(path) => {
+                        claimed.insert(path.clone());
+                        journals.push(Some(prepare_incoming_file(
+                            &file_id,
+                            &entry.relative_path,
+                            entry.file_size,
+                            &entry.fingerprint,
+                            path.to_string_lossy().to_string(),
+                            Some(&id),
+                        )?));
+                    }
+                    None => {
+                        skip_incoming_file(&file_id, &entry.relative_path, entry.file_size, &save_path, Some(&id))?;
+                        journals.push(None);
+                    }
+                }
+            }
+            
+            // 一次同意覆盖整批文件
+            let response = TransferMessage::BatchTransferResponse {
+                id: id.clone(),
+                accepted: true,
+                resume_offsets: journals.iter().map(|journal| journal.as_ref().map_or(0, |j| j.received_bytes)).collect(),
+                skipped: journals.iter().map(Option::is_none).collect(),
+                reason: None,
+            };
+            
+            send_message(&response).await?;
+            
+            // 更新传输状态
+            update_transfer_status(&id, TransferStatus::Transferring)?;
+            
+            // 按清单顺序依次接收文件
+            let task_id = id.clone();
+            let control = register_transfer_control(&id)?;
+            tokio::spawn(async move {
+                let mut completed = true;
+                for (journal, entry) in journals.into_iter().zip(files) {
+                    let Some(journal) = journal else {
+                        continue;
                     };
+                    let file_id = journal.transfer_id.clone();
+                    let save_path = journal.save_path.clone();
                     
-                    let complete_data = serde_json::to_vec(&complete).map_err(|e| e.to_string())?;
-                    send_data(&complete_data).await?;
-                    
-                    // 更新传输状态
-                    update_transfer_status(transfer_id, TransferStatus::Completed)?;
+                    if let Err(e) = receive_file_chunks(&file_id, journal, &control, &mut channel).await {
+                        log::error!("Failed to receive file {}: {}", entry.relative_path, e);
+                        mark_transfers_interrupted(&[&file_id, &task_id], &control);
+                        completed = false;
+                        break;
+                    }
                     
-                    break;
+                    // 恢复修改时间
+                    if let Err(e) = apply_modified_time(&save_path, entry.modified) {
+                        log::warn!("Failed to restore modification time of {}: {}", save_path, e);
+                    }
                 }
-            }
-            _ => {
-                return Err("Invalid acknowledgment from receiver".to_string());
-            }
+                
+                if completed {
+                    if let Err(e) = update_transfer_status(&task_id, TransferStatus::Completed) {
+                        log::error!("Failed to update transfer status: {}", e);
+                    }
+                }
+                
+                unregister_transfer_control(&task_id);
+            });
+            
+            Ok(id)
         }
+        _ => Err(NearbySendError::protocol("Invalid request from sender")),
     }
-    
-    Ok(())
 }
 
-// 接收文件块
-async fn receive_file_chunks(transfer_id: &str, save_path: String) -> Result<(), String> {
-    // 创建文件
-    let mut file = File::create(&save_path).map_err(|e| format!("Failed to create file: {}", e))?;
+// 等待用户确认传输请求，返回None表示接受，否则为拒绝原因（超时视为拒绝）
+async fn wait_for_decision(request: IncomingTransferRequest, timeout: Duration) -> Result<Option<String>, NearbySendError> {
+    let transfer_id = request.id.clone();
+    let (sender, receiver) = oneshot::channel();
+    
+    // 同一ID已有等待确认的请求时不能覆盖，否则先到的请求永远得不到回复
+    {
+        let mut pending = PENDING_REQUESTS.lock()?;
+        if pending.contains_key(&transfer_id) {
+            return Err(NearbySendError::invalid(format!("Transfer request already pending: {}", transfer_id)));
+        }
+        pending.insert(transfer_id.clone(), (request, sender));
+    }
+    
+    match time::timeout(timeout, receiver).await {
+        Ok(Ok(decision)) => Ok(decision),
+        _ => {
+            // 超时后移除未处理的请求（回复时已被移除，这里不会误删后来的同ID请求）
+            PENDING_REQUESTS.lock()?.remove(&transfer_id);
+            
+            log::warn!("No decision for transfer request {}, declining", transfer_id);
+            Ok(Some("Request timed out".to_string()))
+        }
+    }
+}
+
+// 拒绝单个文件的传输请求，并告知发送方原因
+async fn decline_transfer(transfer_id: &str, reason: &str) -> Result<(), NearbySendError> {
+    let response = TransferMessage::TransferResponse {
+        id: transfer_id.to_string(),
+        accepted: false,
+        resume_offset: 0,
+        skipped: false,
+        reason: Some(reason.to_string()),
+    };
+    
+    send_message(&response).await
+}
+
+// 拒绝批量传输请求，并告知发送方原因
+async fn decline_batch_transfer(transfer_id: &str, reason: &str) -> Result<(), NearbySendError> {
+    let response = TransferMessage::BatchTransferResponse {
+        id: transfer_id.to_string(),
+        accepted: false,
+        resume_offsets: Vec::new(),
+        skipped: Vec::new(),
+        reason: Some(reason.to_string()),
+    };
+    
+    send_message(&response).await
+}
+
+// 为即将接收的文件创建传输对象，并根据续传日志确定续传偏移量
+fn prepare_incoming_file(
+    transfer_id: &str,
+    file_name: &str,
+    file_size: u64,
+    fingerprint: &str,
+    save_path: String,
+    parent_id: Option<&str>,
+) -> Result<ResumeJournal, NearbySendError> {
+    // 检查是否有匹配的续传日志
+    let resume_offset = match ResumeJournal::load(&save_path) {
+        Some(journal) if journal.matches(transfer_id, fingerprint, file_size) => journal.resume_offset(),
+        _ => 0,
+    };
+    
+    // 创建传输对象
+    let transfer = FileTransfer {
+        id: transfer_id.to_string(),
+        file_name: file_name.to_string(),
+        file_size,
+        transferred_bytes: resume_offset,
+        status: TransferStatus::Pending,
+        parent_id: parent_id.map(|id| id.to_string()),
+        save_path: Some(save_path.clone()),
+    };
+    
+    // 添加到传输列表
+    upsert_transfer(transfer)?;
+    
+    // 创建续传日志
+    Ok(ResumeJournal {
+        transfer_id: transfer_id.to_string(),
+        file_name: file_name.to_string(),
+        file_size,
+        fingerprint: fingerprint.to_string(),
+        save_path,
+        received_bytes: resume_offset,
+    })
+}
+
+// 记录因文件名冲突而跳过的文件
+fn skip_incoming_file(
+    transfer_id: &str,
+    file_name: &str,
+    file_size: u64,
+    existing_path: &Path,
+    parent_id: Option<&str>,
+) -> Result<(), NearbySendError> {
+    log::info!("Skipping {}: {} already exists", file_name, existing_path.display());
+    
+    upsert_transfer(FileTransfer {
+        id: transfer_id.to_string(),
+        file_name: file_name.to_string(),
+        file_size,
+        transferred_bytes: 0,
+        status: TransferStatus::Skipped,
+        parent_id: parent_id.map(|id| id.to_string()),
+        save_path: Some(existing_path.to_string_lossy().to_string()),
+    })
+}
+
+// 按冲突策略确定最终保存路径，返回None表示跳过该文件
+async fn choose_save_path(
+    transfer_id: &str,
+    file_name: &str,
+    file_size: u64,
+    fingerprint: &str,
+    save_path: PathBuf,
+    claimed: &HashSet<PathBuf>,
+    policy: ConflictPolicy,
+) -> Result<Option<PathBuf>, NearbySendError> {
+    // 已存在的文件或其他传输未完成的文件都算冲突，同一传输未完成的文件需要续传
+    let is_taken = |path: &Path| {
+        claimed.contains(path)
+            || std::fs::symlink_metadata(path).is_ok()
+            || ResumeJournal::load(&path.to_string_lossy()).is_some_and(|journal| !journal.matches(transfer_id, fingerprint, file_size))
+    };
+    
+    if !is_taken(&save_path) {
+        return Ok(Some(save_path));
+    }
+    
+    let policy = match policy {
+        ConflictPolicy::Ask => ask_conflict_policy(transfer_id, file_name, file_size, &save_path).await?,
+        policy => policy,
+    };
+    
+    match policy {
+        ConflictPolicy::Rename => Ok(Some(next_available_path(&save_path, is_taken)?)),
+        ConflictPolicy::Overwrite => Ok(Some(save_path)),
+        ConflictPolicy::Skip | ConflictPolicy::Ask => Ok(None),
+    }
+}
+
+// 等待用户决定如何处理文件名冲突（超时则跳过）
+async fn ask_conflict_policy(transfer_id: &str, file_name: &str, file_size: u64, existing_path: &Path) -> Result<ConflictPolicy, NearbySendError> {
+    let (sender, receiver) = oneshot::channel();
+    let conflict = FileConflict {
+        transfer_id: transfer_id.to_string(),
+        file_name: file_name.to_string(),
+        file_size,
+        existing_path: existing_path.to_string_lossy().to_string(),
+    };
+    
+    {
+        let mut pending = PENDING_CONFLICTS.lock()?;
+        pending.insert(transfer_id.to_string(), (conflict, sender));
+    }
+    
+    let decision = time::timeout(CONFLICT_DECISION_TIMEOUT, receiver).await;
+    
+    // 超时后移除未处理的冲突
+    {
+        let mut pending = PENDING_CONFLICTS.lock()?;
+        pending.remove(transfer_id);
+    }
+    
+    match decision {
+        Ok(Ok(policy)) => Ok(policy),
+        _ => {
+            log::warn!("No decision for conflicting file {}, skipping", file_name);
+            Ok(ConflictPolicy::Skip)
+        }
+    }
+}
+
+// 列出保存目录中可续传的接收（等待发送方重新连接后自动续传）
+pub fn get_resumable_transfers(save_dir: &str) -> Result<Vec<ResumableTransfer>, NearbySendError> {
+    let journals = list_resume_journals(save_dir)?;
+    
+    Ok(journals
+        .into_iter()
+        .map(|journal| ResumableTransfer {
+            received_bytes: journal.resume_offset(),
+            id: journal.transfer_id,
+            file_name: journal.file_name,
+            file_size: journal.file_size,
+            save_path: journal.save_path,
+        })
+        .collect())
+}
+
+// 放弃一个可续传的接收，删除未完成的文件和续传日志
+pub fn discard_resumable_transfer(save_dir: &str, transfer_id: &str) -> Result<(), NearbySendError> {
+    let journal = list_resume_journals(save_dir)?
+        .into_iter()
+        .find(|journal| journal.transfer_id == transfer_id)
+        .ok_or_else(|| NearbySendError::not_found(format!("Transfer not found: {}", transfer_id)))?;
+    
+    match std::fs::remove_file(partial_file_path(&journal.save_path)) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(NearbySendError::io("Failed to remove partial file", e)),
+    }
+    
+    ResumeJournal::remove(&journal.save_path)
+}
+
+// 发送文件块（滑动窗口流水线发送，接收方使用累计确认）
+async fn send_file_chunks(
+    file_path: &str,
+    transfer_id: &str,
+    resume_offset: u64,
+    control: &TransferControl,
+    channel: &mut TransferChannel,
+) -> Result<(), NearbySendError> {
+    // 打开文件，从续传偏移量开始读取
+    let mut chunker = FileChunker::new(file_path, None)?;
+    chunker.seek(resume_offset)?;
+    
+    // 创建发送窗口
+    let mut window = SendWindow::new(get_send_window_size()?)?;
+    
+    // 更新传输状态
+    update_transfer_status(transfer_id, active_status(control))?;
+    
+    // 循环发送文件块
+    loop {
+        // 本方已取消，等待接收方确认
+        if control.is_cancelled() {
+            return await_cancel_ack(control, channel).await;
+        }
+        
+        // 在窗口允许的范围内连续发送数据块（暂停时不再发送新的数据块）
+        while window.has_capacity() && !control.is_paused() {
+            let offset = chunker.current_position();
+            let data = match chunker.next_chunk()? {
+                Some(data) => data,
+                None => break,
+            };
+            
+            // 是否为最后一块
+            let is_last = chunker.is_complete();
+            let chunk_index = window.push(offset, data.len() as u64)?;
+            
+            // 发送数据块
+            send_message(&data_chunk_message(transfer_id, chunk_index, offset, data, is_last)).await?;
+        }
+        
+        // 所有数据块都已确认
+        if window.is_empty() && !control.is_paused() {
+            break;
+        }
+        
+        // 窗口已满、文件已读完或已暂停，等待确认或控制消息
+        // 本方暂停、恢复或取消时对方不一定会发来消息，因此同时等待本方状态的改变
+        let ack = tokio::select! {
+            message = channel.recv() => message?,
+            _ = control.changed() => continue,
+        };
+        
+        match ack {
+            TransferMessage::ChunkAck { id, chunk_index } if id == transfer_id => {
+                // 累计确认，释放窗口
+                if window.acknowledge(chunk_index)? > 0 {
+                    // 仅按已确认的字节数更新传输进度
+                    update_transfer_progress(transfer_id, resume_offset + window.acknowledged_bytes())?;
+                }
+            }
+            TransferMessage::ChunkNack { id, chunk_index } if id == transfer_id => {
+                // 只重传损坏的数据块
+                let (offset, len) = window.retransmit(chunk_index)?;
+                let data = chunker.read_at(offset, len)?;
+                let is_last = offset + len >= chunker.file_size();
+                
+                log::warn!("Retransmitting chunk {} of transfer {}", chunk_index, transfer_id);
+                send_message(&data_chunk_message(transfer_id, chunk_index, offset, data, is_last)).await?;
+            }
+            TransferMessage::TransferCancel { id } if id == control.transfer_id() => {
+                return handle_peer_cancel(control).await;
+            }
+            TransferMessage::TransferPause { id, generation } if id == control.transfer_id() => {
+                handle_peer_pause(control, true, generation)?;
+            }
+            TransferMessage::TransferResume { id, generation } if id == control.transfer_id() => {
+                handle_peer_pause(control, false, generation)?;
+            }
+            _ => {
+                return Err(NearbySendError::protocol("Invalid acknowledgment from receiver"));
+            }
+        }
+    }
+    
+    // 发送传输完成消息，附带整个文件的哈希
+    let complete = TransferMessage::TransferComplete {
+        id: transfer_id.to_string(),
+        success: true,
+        hash: chunker.file_hash()?,
+    };
+    
+    send_message(&complete).await?;
+    
+    // 等待接收方校验结果
+    loop {
+        match channel.recv().await? {
+            TransferMessage::TransferVerified { id, verified } if id == transfer_id => {
+                if !verified {
+                    return Err(NearbySendError::verification("Integrity check failed on receiver"));
+                }
+                break;
+            }
+            // 数据已发送完毕，暂停不再有影响
+            TransferMessage::TransferPause { id, .. } | TransferMessage::TransferResume { id, .. } if id == control.transfer_id() => {}
+            _ => {
+                return Err(NearbySendError::protocol("Invalid verification message from receiver"));
+            }
+        }
+    }
     
     // 更新传输状态
-    update_transfer_status(transfer_id, TransferStatus::Transferring)?;
+    update_transfer_status(transfer_id, TransferStatus::Completed)?;
+    
+    // 传输完成后不再需要续传
+    discard_interrupted_send(transfer_id)?;
+    
+    Ok(())
+}
+
+// 接收文件块（写入临时文件，从续传日志记录的偏移量开始）
+async fn receive_file_chunks(
+    transfer_id: &str,
+    mut journal: ResumeJournal,
+    control: &TransferControl,
+    channel: &mut TransferChannel,
+) -> Result<(), NearbySendError> {
+    // 创建临时文件，续传时打开已有的未完成临时文件
+    let mut assembler = if journal.received_bytes > 0 {
+        FileAssembler::resume(&journal.save_path, journal.file_size, journal.received_bytes)?
+    } else {
+        FileAssembler::new(&journal.save_path, journal.file_size)?
+    };
     
-    let mut transferred: u64 = 0;
-    let mut expected_chunk_index: u32 = 0;
+    // 在文件旁边保存续传日志
+    journal.save()?;
+    
+    let expected_hash = match receive_file_data(transfer_id, &mut assembler, &mut journal, control, channel).await {
+        Ok(hash) => hash,
+        Err(e) => {
+            // 已记录进度的临时文件保留以便续传，被取消或没有进度时直接清理
+            if control.is_cancelled() || journal.received_bytes == 0 {
+                assembler.abort();
+                ResumeJournal::remove(&journal.save_path)?;
+            }
+            return Err(e);
+        }
+    };
+    
+    // 校验大小和哈希，通过后才将临时文件重命名为目标文件，失败时删除临时文件
+    let result = assembler.finish_verified(&expected_hash);
+    
+    // 无论校验是否通过都不再需要续传
+    ResumeJournal::remove(&journal.save_path)?;
+    
+    // 通知发送方校验结果
+    let verified = TransferMessage::TransferVerified {
+        id: transfer_id.to_string(),
+        verified: result.is_ok(),
+    };
+    
+    send_message(&verified).await?;
+    result?;
+    
+    // 更新传输状态
+    update_transfer_status(transfer_id, TransferStatus::Completed)?;
+    
+    Ok(())
+}
+
+// 接收数据块并写入临时文件，返回发送方提供的文件哈希
+async fn receive_file_data(
+    transfer_id: &str,
+    assembler: &mut FileAssembler,
+    journal: &mut ResumeJournal,
+    control: &TransferControl,
+    channel: &mut TransferChannel,
+) -> Result<String, NearbySendError> {
+    // 更新传输状态
+    update_transfer_status(transfer_id, active_status(control))?;
+    
+    // 跟踪乱序到达和需要重传的数据块
+    let resume_offset = journal.received_bytes;
+    let mut tracker = ReceiveTracker::new(resume_offset);
     
     // 循环接收文件块
     loop {
+        // 本方已取消，等待发送方确认
+        if control.is_cancelled() {
+            return await_cancel_ack(control, channel).await;
+        }
+        
         // 接收数据块
-        let chunk_data = receive_data(1024 * 1024).await?; // 允许接收更大的数据块
-        let chunk: TransferMessage = serde_json::from_slice(&chunk_data).map_err(|e| e.to_string())?;
+        let chunk = channel.recv().await?;
         
         match chunk {
-            TransferMessage::DataChunk { id, chunk_index, data, is_last } if id == transfer_id => {
-                if chunk_index != expected_chunk_index {
-                    return Err(format!("Unexpected chunk index: expected {}, got {}", expected_chunk_index, chunk_index));
+            TransferMessage::DataChunk { id, chunk_index, offset, checksum, data, is_last } if id == transfer_id => {
+                // 数据块必须位于文件范围内
+                if offset < resume_offset || offset + data.len() as u64 > journal.file_size {
+                    return Err(NearbySendError::protocol(format!("Chunk {} out of file bounds at offset {}", chunk_index, offset)));
                 }
                 
-                // 写入文件
-                file.write_all(&data).map_err(|e| format!("Failed to write to file: {}", e))?;
-                
-                // 更新传输进度
-                transferred += data.len() as u64;
-                update_transfer_progress(transfer_id, transferred)?;
-                
-                // 发送确认
-                let ack = TransferMessage::ChunkAck {
-                    id: transfer_id.to_string(),
-                    chunk_index,
-                };
-                
-                let ack_data = serde_json::to_vec(&ack).map_err(|e| e.to_string())?;
-                send_data(&ack_data).await?;
-                
-                // 增加期望的块索引
-                expected_chunk_index += 1;
-                
-                // 如果是最后一块，等待传输完成消息
-                if is_last {
-                    let complete_data = receive_data(1024).await?;
-                    let complete: TransferMessage = serde_json::from_slice(&complete_data).map_err(|e| e.to_string())?;
-                    
-                    match complete {
-                        TransferMessage::TransferComplete { id, success } if id == transfer_id && success => {
-                            // 更新传输状态
-                            update_transfer_status(transfer_id, TransferStatus::Completed)?;
-                            
-                            // 关闭文件
-                            drop(file);
-                            
-                            break;
-                        }
-                        _ => {
-                            return Err("Invalid completion message from sender".to_string());
+                let checksum_ok = chunk_checksum(&data) == checksum;
+                match tracker.accept(chunk_index, data.len() as u64, checksum_ok, is_last) {
+                    ChunkVerdict::Corrupt => {
+                        // 请求重传损坏的数据块
+                        log::warn!("Chunk {} of transfer {} is corrupt, requesting retransmission", chunk_index, transfer_id);
+                        let nack = TransferMessage::ChunkNack {
+                            id: transfer_id.to_string(),
+                            chunk_index,
+                        };
+                        
+                        send_message(&nack).await?;
+                        continue;
+                    }
+                    ChunkVerdict::Duplicate => {
+                        log::warn!("Duplicate chunk {} of transfer {}", chunk_index, transfer_id);
+                    }
+                    ChunkVerdict::Accepted => {
+                        // 写入文件
+                        assembler.write_chunk(&data, Some(offset))?;
+                        
+                        // 更新传输进度
+                        update_transfer_progress(transfer_id, tracker.contiguous_bytes())?;
+                        
+                        // 定期将连续接收的进度写入续传日志
+                        if tracker.is_complete() || (chunk_index + 1) % JOURNAL_SYNC_INTERVAL == 0 {
+                            assembler.sync()?;
+                            journal.received_bytes = tracker.contiguous_bytes();
+                            journal.save()?;
                         }
                     }
                 }
+                
+                // 发送累计确认
+                if let Some(ack_index) = tracker.cumulative_ack() {
+                    let ack = TransferMessage::ChunkAck {
+                        id: transfer_id.to_string(),
+                        chunk_index: ack_index,
+                    };
+                    
+                    send_message(&ack).await?;
+                }
             }
-            TransferMessage::TransferComplete { id, success } if id == transfer_id => {
+            // 收齐所有数据块后发送方发送传输完成消息
+            TransferMessage::TransferComplete { id, success, hash } if id == transfer_id => {
                 if success {
-                    // 更新传输状态
-                    update_transfer_status(transfer_id, TransferStatus::Completed)?;
-                    
-                    // 关闭文件
-                    drop(file);
-                    
-                    break;
+                    return Ok(hash);
                 } else {
-                    return Err("Transfer failed".to_string());
+                    return Err(NearbySendError::Io {
+                        message: "Transfer failed on sender".to_string(),
+                    });
                 }
             }
+            TransferMessage::TransferCancel { id } if id == control.transfer_id() => {
+                return handle_peer_cancel(control).await;
+            }
+            TransferMessage::TransferPause { id, generation } if id == control.transfer_id() => {
+                handle_peer_pause(control, true, generation)?;
+            }
+            TransferMessage::TransferResume { id, generation } if id == control.transfer_id() => {
+                handle_peer_pause(control, false, generation)?;
+            }
             _ => {
-                return Err("Invalid message from sender".to_string());
+                return Err(NearbySendError::protocol("Invalid message from sender"));
+            }
+        }
+    }
+}
+
+// 等待接收方对传输请求的响应
+async fn recv_response(channel: &mut TransferChannel, timeout: Duration) -> Result<TransferMessage, NearbySendError> {
+    time::timeout(timeout, channel.recv())
+        .await
+        .map_err(|_| NearbySendError::timeout("Waiting for transfer response"))?
+}
+
+// 本方已取消传输：丢弃对方确认之前仍在发送的消息
+async fn await_cancel_ack<T>(control: &TransferControl, channel: &mut TransferChannel) -> Result<T, NearbySendError> {
+    let transfer_id = control.transfer_id();
+    let drain = async {
+        loop {
+            match channel.recv().await? {
+                TransferMessage::TransferCancel { id } if id == transfer_id => return Ok::<(), NearbySendError>(()),
+                _ => continue,
             }
         }
+    };
+    
+    match time::timeout(CANCEL_ACK_TIMEOUT, drain).await {
+        Ok(result) => result?,
+        Err(_) => log::warn!("Peer did not acknowledge cancellation of transfer {}", transfer_id),
+    }
+    
+    Err(NearbySendError::Cancelled)
+}
+
+// 收到取消消息：本方已取消时即为对方的确认，否则回复确认
+async fn handle_peer_cancel<T>(control: &TransferControl) -> Result<T, NearbySendError> {
+    if control.cancel() {
+        log::info!("Transfer {} cancelled by peer", control.transfer_id());
+        send_message(&TransferMessage::TransferCancel { id: control.transfer_id().to_string() }).await?;
+    }
+    
+    Err(NearbySendError::Cancelled)
+}
+
+// 收到暂停或恢复消息：比本方状态新时更新传输状态（从不回复，避免双方来回反射）
+fn handle_peer_pause(control: &TransferControl, paused: bool, generation: u64) -> Result<(), NearbySendError> {
+    if control.apply_peer_pause(paused, generation) {
+        log::info!("Transfer {} {} by peer", control.transfer_id(), if paused { "paused" } else { "resumed" });
+        set_transfer_paused(control.transfer_id(), paused)?;
     }
     
     Ok(())
 }
 
+// 创建暂停或恢复消息
+fn pause_message(transfer_id: &str, paused: bool, generation: u64) -> TransferMessage {
+    let id = transfer_id.to_string();
+    if paused {
+        TransferMessage::TransferPause { id, generation }
+    } else {
+        TransferMessage::TransferResume { id, generation }
+    }
+}
+
+// 创建数据块消息，附带校验和
+fn data_chunk_message(transfer_id: &str, chunk_index: u32, offset: u64, data: Vec<u8>, is_last: bool) -> TransferMessage {
+    TransferMessage::DataChunk {
+        id: transfer_id.to_string(),
+        chunk_index,
+        offset,
+        checksum: chunk_checksum(&data),
+        data,
+        is_last,
+    }
+}
+
+// 发送传输消息（每条消息占用一个帧）
+async fn send_message(message: &TransferMessage) -> Result<(), NearbySendError> {
+    let data = encode_message(message, get_protocol_version()?)?;
+    send_data(&data).await
+}
+
+// 添加传输对象，已存在时（续传）替换原有记录
+fn upsert_transfer(transfer: FileTransfer) -> Result<(), NearbySendError> {
+    {
+        let mut transfers = CURRENT_TRANSFERS.lock()?;
+        
+        match transfers.iter_mut().find(|t| t.id == transfer.id) {
+            Some(existing) => *existing = transfer.clone(),
+            None => transfers.push(transfer.clone()),
+        }
+    }
+    
+    emit_transfer_event(TransferEvent::StatusChanged { transfer });
+    Ok(())
+}
+
+// 获取单个传输对象
+fn get_transfer(transfer_id: &str) -> Result<FileTransfer, NearbySendError> {
+    let transfers = CURRENT_TRANSFERS.lock()?;
+    
+    transfers
+        .iter()
+        .find(|t| t.id == transfer_id)
+        .cloned()
+        .ok_or_else(|| NearbySendError::not_found(format!("Transfer not found: {}", transfer_id)))
+}
+
 // 更新传输状态
-fn update_transfer_status(transfer_id: &str, status: TransferStatus) -> Result<(), String> {
-    let mut transfers = CURRENT_TRANSFERS.lock().map_err(|e| e.to_string())?;
+fn update_transfer_status(transfer_id: &str, status: TransferStatus) -> Result<(), NearbySendError> {
+    let transfer = {
+        let mut transfers = CURRENT_TRANSFERS.lock()?;
+        
+        let transfer = transfers
+            .iter_mut()
+            .find(|t| t.id == transfer_id)
+            .ok_or_else(|| NearbySendError::not_found(format!("Transfer not found: {}", transfer_id)))?;
+        transfer.status = status;
+        transfer.clone()
+    };
     
-    for transfer in transfers.iter_mut() {
-        if transfer.id == transfer_id {
-            transfer.status = status;
-            return Ok(());
+    // 传输结束后不再需要估算速度
+    if !matches!(transfer.status, TransferStatus::Pending | TransferStatus::Connecting | TransferStatus::Transferring | TransferStatus::Paused) {
+        PROGRESS_TRACKERS.lock()?.remove(transfer_id);
+    }
+    
+    emit_transfer_event(TransferEvent::StatusChanged { transfer });
+    Ok(())
+}
+
+// 正在进行的传输的状态（已暂停或传输中）
+fn active_status(control: &TransferControl) -> TransferStatus {
+    if control.is_paused() {
+        TransferStatus::Paused
+    } else {
+        TransferStatus::Transferring
+    }
+}
+
+// 更新暂停状态（批量传输同时更新正在传输的文件）
+fn set_transfer_paused(transfer_id: &str, paused: bool) -> Result<(), NearbySendError> {
+    let mut changed = Vec::new();
+    {
+        let mut transfers = CURRENT_TRANSFERS.lock()?;
+        
+        for transfer in transfers.iter_mut() {
+            if transfer.id != transfer_id && transfer.parent_id.as_deref() != Some(transfer_id) {
+                continue;
+            }
+            
+            match (&transfer.status, paused) {
+                (TransferStatus::Transferring, true) => transfer.status = TransferStatus::Paused,
+                (TransferStatus::Paused, false) => transfer.status = TransferStatus::Transferring,
+                _ => continue,
+            }
+            changed.push(transfer.clone());
         }
     }
     
-    Err(format!("Transfer not found: {}", transfer_id))
+    // 暂停期间不计入速度，恢复后重新开始估算
+    let mut trackers = PROGRESS_TRACKERS.lock()?;
+    for transfer in changed {
+        trackers.remove(&transfer.id);
+        emit_transfer_event(TransferEvent::StatusChanged { transfer });
+    }
+    
+    Ok(())
 }
 
-// 更新传输进度
-fn update_transfer_progress(transfer_id: &str, transferred_bytes: u64) -> Result<(), String> {
-    let mut transfers = CURRENT_TRANSFERS.lock().map_err(|e| e.to_string())?;
+// 更新传输进度（批量传输中的文件同时更新整批的总进度）
+fn update_transfer_progress(transfer_id: &str, transferred_bytes: u64) -> Result<(), NearbySendError> {
+    // 更新前后的进度：(传输ID, 之前的字节数, 当前字节数, 总大小)
+    let mut updates = Vec::new();
+    {
+        let mut transfers = CURRENT_TRANSFERS.lock()?;
+        
+        let transfer = transfers
+            .iter_mut()
+            .find(|t| t.id == transfer_id)
+            .ok_or_else(|| NearbySendError::not_found(format!("Transfer not found: {}", transfer_id)))?;
+        updates.push((transfer.id.clone(), transfer.transferred_bytes, transferred_bytes, transfer.file_size));
+        transfer.transferred_bytes = transferred_bytes;
+        
+        // 汇总整批的进度
+        if let Some(parent_id) = transfer.parent_id.clone() {
+            let total = transfers
+                .iter()
+                .filter(|t| t.parent_id.as_deref() == Some(parent_id.as_str()))
+                .map(|t| t.transferred_bytes)
+                .sum();
+            
+            if let Some(parent) = transfers.iter_mut().find(|t| t.id == parent_id) {
+                updates.push((parent.id.clone(), parent.transferred_bytes, total, parent.file_size));
+                parent.transferred_bytes = total;
+            }
+        }
+    }
     
-    for transfer in transfers.iter_mut() {
-        if transfer.id == transfer_id {
-            transfer.transferred_bytes = transferred_bytes;
-            return Ok(());
+    // 按节流间隔发送进度事件
+    let now = Instant::now();
+    let mut trackers = PROGRESS_TRACKERS.lock()?;
+    for (id, previous_bytes, transferred_bytes, total_bytes) in updates {
+        let tracker = trackers
+            .entry(id.clone())
+            .or_insert_with(|| ProgressTracker::new(now, previous_bytes));
+        
+        if let Some(sample) = tracker.update(now, transferred_bytes, total_bytes) {
+            emit_transfer_event(TransferEvent::Progress {
+                transfer_id: id,
+                transferred_bytes,
+                total_bytes,
+                bytes_per_second: sample.bytes_per_second,
+                eta_seconds: sample.eta_seconds,
+            });
         }
     }
     
-    Err(format!("Transfer not found: {}", transfer_id))
+    Ok(())
 }
 
 // 获取当前传输列表
-pub fn get_transfers() -> Result<Vec<FileTransfer>, String> {
-    let transfers = CURRENT_TRANSFERS.lock().map_err(|e| e.to_string())?;
+pub fn get_transfers() -> Result<Vec<FileTransfer>, NearbySendError> {
+    let transfers = CURRENT_TRANSFERS.lock()?;
     Ok(transfers.clone())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::framing::{read_frame, write_frame, MAX_FRAME_SIZE};
+    use crate::connection::handshake::perform_handshake;
+    use crate::connection::wifi_direct::connect_to_device;
+    use crate::transfer::codec::{decode_message, PROTOCOL_VERSION};
+    use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+
+    lazy_static::lazy_static! {
+        // 使用全局连接的测试依次运行
+        static ref CONNECTION_TEST_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
+    }
+
+    // 通过本机TCP连接与本方握手的对端，按帧收发传输消息
+    struct TestPeer {
+        reader: OwnedReadHalf,
+        writer: OwnedWriteHalf,
+    }
+
+    impl TestPeer {
+        // 建立连接并设为本方的当前连接
+        async fn connect() -> Self {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let peer = tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (mut reader, mut writer) = stream.into_split();
+                perform_handshake(&mut reader, &mut writer).await.unwrap();
+                TestPeer { reader, writer }
+            });
+
+            connect_to_device("127.0.0.1".parse().unwrap(), port).await.unwrap();
+            peer.await.unwrap()
+        }
+
+        async fn send(&mut self, message: &TransferMessage) {
+            write_frame(&mut self.writer, &encode_message(message, PROTOCOL_VERSION).unwrap()).await.unwrap();
+        }
+
+        async fn recv(&mut self) -> TransferMessage {
+            let data = time::timeout(Duration::from_secs(5), read_frame(&mut self.reader, MAX_FRAME_SIZE)).await.unwrap().unwrap();
+            decode_message(&data, PROTOCOL_VERSION).unwrap()
+        }
+    }
+
+    // 等待传输进入指定状态
+    async fn wait_for_status(transfer_id: &str, expected: impl Fn(&TransferStatus) -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !expected(&get_transfer(transfer_id).unwrap().status) {
+            assert!(Instant::now() < deadline, "transfer {} stuck in {:?}", transfer_id, get_transfer(transfer_id).unwrap().status);
+            time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("nearbysend-protocol-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // 在目录中创建一个已存在的文件，返回它的路径
+    fn existing_file(dir: &Path, name: &str) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, b"existing").unwrap();
+        path
+    }
+
+    fn incoming_request(transfer_id: &str) -> IncomingTransferRequest {
+        IncomingTransferRequest {
+            id: transfer_id.to_string(),
+            sender_name: "Pixel".to_string(),
+            sender_address: "192.168.1.20:8080".to_string(),
+            files: vec![IncomingFile { file_name: "photo.jpg".to_string(), file_size: 8 }],
+            total_size: 8,
+        }
+    }
+
+    // 在后台等待用户确认，直到请求出现在待处理列表中
+    async fn spawn_decision(transfer_id: &str, timeout: Duration) -> tokio::task::JoinHandle<Result<Option<String>, NearbySendError>> {
+        let request = incoming_request(transfer_id);
+        let task = tokio::spawn(async move { wait_for_decision(request, timeout).await });
+        while !get_pending_requests().unwrap().iter().any(|r| r.id == transfer_id) {
+            tokio::task::yield_now().await;
+        }
+        task
+    }
+
+    fn is_pending(transfer_id: &str) -> bool {
+        get_pending_requests().unwrap().iter().any(|r| r.id == transfer_id)
+    }
+
+    #[tokio::test]
+    async fn accepts_and_rejects_requests() {
+        let accepted = spawn_decision("request-accept", REQUEST_DECISION_TIMEOUT).await;
+        accept_transfer("request-accept").unwrap();
+        assert_eq!(accepted.await.unwrap().unwrap(), None);
+        assert!(!is_pending("request-accept"));
+        assert!(accept_transfer("request-accept").is_err());
+
+        let rejected = spawn_decision("request-reject", REQUEST_DECISION_TIMEOUT).await;
+        reject_transfer("request-reject", Some("Too large".to_string())).unwrap();
+        assert_eq!(rejected.await.unwrap().unwrap().as_deref(), Some("Too large"));
+
+        let rejected = spawn_decision("request-reject-default", REQUEST_DECISION_TIMEOUT).await;
+        reject_transfer("request-reject-default", None).unwrap();
+        assert_eq!(rejected.await.unwrap().unwrap().as_deref(), Some("Declined by user"));
+    }
+
+    #[tokio::test]
+    async fn unanswered_request_times_out() {
+        let task = spawn_decision("request-timeout", Duration::from_millis(20)).await;
+        assert_eq!(task.await.unwrap().unwrap().as_deref(), Some("Request timed out"));
+        assert!(!is_pending("request-timeout"));
+        assert!(accept_transfer("request-timeout").is_err());
+    }
+
+    #[tokio::test]
+    async fn duplicate_request_id_is_rejected() {
+        let first = spawn_decision("request-duplicate", REQUEST_DECISION_TIMEOUT).await;
+
+        // 第二个同ID的请求不能覆盖第一个
+        let duplicate = wait_for_decision(incoming_request("request-duplicate"), Duration::from_millis(20)).await;
+        assert!(duplicate.is_err());
+        assert!(is_pending("request-duplicate"));
+
+        accept_transfer("request-duplicate").unwrap();
+        assert_eq!(first.await.unwrap().unwrap(), None);
+    }
+
+    // 模拟一端：本方的改变产生消息，对方的消息只应用不回复
+    fn deliver(control: &TransferControl, message: &TransferMessage) {
+        let decoded = crate::transfer::codec::decode_message(&encode_message(message, PROTOCOL_VERSION).unwrap(), PROTOCOL_VERSION).unwrap();
+        match decoded {
+            TransferMessage::TransferPause { generation, .. } => handle_peer_pause(control, true, generation).unwrap(),
+            TransferMessage::TransferResume { generation, .. } => handle_peer_pause(control, false, generation).unwrap(),
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+
+    fn toggle(control: &TransferControl, paused: bool, outbox: &mut Vec<TransferMessage>) {
+        if let Some(generation) = control.set_paused(paused) {
+            outbox.push(pause_message(control.transfer_id(), paused, generation));
+        }
+    }
+
+    #[test]
+    fn rapid_pause_toggles_settle() {
+        // A快速暂停又恢复，B按顺序收到两条消息后不再回复，迟到的暂停也不会让A重新暂停
+        let a = TransferControl::new("pause-settle");
+        let b = TransferControl::new("pause-settle");
+        let mut to_b = Vec::new();
+        toggle(&a, true, &mut to_b);
+        toggle(&a, false, &mut to_b);
+        for message in &to_b {
+            deliver(&b, message);
+        }
+        deliver(&a, &to_b[0]);
+        assert!(!a.is_paused() && !b.is_paused());
+
+        // 双方同时快速切换，消息以各种顺序交错到达
+        for seed in 0..50u64 {
+            let a = TransferControl::new("pause-race");
+            let b = TransferControl::new("pause-race");
+            let (mut to_a, mut to_b) = (Vec::new(), Vec::new());
+            let mut rng = seed;
+            let mut next = || {
+                rng = rng.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                rng >> 33
+            };
+
+            for step in 0..20 {
+                match next() % 4 {
+                    0 => toggle(&a, step % 2 == 0, &mut to_b),
+                    1 => toggle(&b, step % 3 == 0, &mut to_a),
+                    2 if !to_b.is_empty() => deliver(&b, &to_b.remove(0)),
+                    3 if !to_a.is_empty() => deliver(&a, &to_a.remove(0)),
+                    _ => {}
+                }
+            }
+
+            // 收到的消息不产生回复，双方不再操作后收完在途消息流量即停止，且状态一致
+            for message in to_b.drain(..) {
+                deliver(&b, &message);
+            }
+            for message in to_a.drain(..) {
+                deliver(&a, &message);
+            }
+            assert_eq!(a.is_paused(), b.is_paused(), "seed {}", seed);
+        }
+    }
+
+    async fn choose(transfer_id: &str, path: &Path, claimed: &HashSet<PathBuf>, policy: ConflictPolicy) -> Option<PathBuf> {
+        choose_save_path(transfer_id, "photo.jpg", 8, "fingerprint", path.to_path_buf(), claimed, policy).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn choose_save_path_applies_policy() {
+        let dir = temp_dir("conflict-policy");
+        let free = dir.join("free.jpg");
+        let taken = existing_file(&dir, "photo.jpg");
+        let none = HashSet::new();
+
+        // 没有冲突时任何策略都使用原路径
+        for policy in [ConflictPolicy::Rename, ConflictPolicy::Overwrite, ConflictPolicy::Skip, ConflictPolicy::Ask] {
+            assert_eq!(choose("free", &free, &none, policy).await, Some(free.clone()));
+        }
+
+        assert_eq!(choose("rename", &taken, &none, ConflictPolicy::Rename).await, Some(dir.join("photo (1).jpg")));
+        assert_eq!(choose("overwrite", &taken, &none, ConflictPolicy::Overwrite).await, Some(taken.clone()));
+        assert_eq!(choose("skip", &taken, &none, ConflictPolicy::Skip).await, None);
+
+        // 同一批中已分配给其他文件的路径也算冲突
+        let claimed = HashSet::from([free.clone()]);
+        assert_eq!(choose("claimed", &free, &claimed, ConflictPolicy::Rename).await, Some(dir.join("free (1).jpg")));
+        assert_eq!(choose("claimed-skip", &free, &claimed, ConflictPolicy::Skip).await, None);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn choose_save_path_asks_user() {
+        let dir = temp_dir("conflict-ask");
+        let taken = existing_file(&dir, "photo.jpg");
+
+        for (transfer_id, decision, expected) in [
+            ("ask-rename", ConflictPolicy::Rename, Some(dir.join("photo (1).jpg"))),
+            ("ask-overwrite", ConflictPolicy::Overwrite, Some(taken.clone())),
+            ("ask-skip", ConflictPolicy::Skip, None),
+        ] {
+            let path = taken.clone();
+            let task = tokio::spawn(async move { choose(transfer_id, &path, &HashSet::new(), ConflictPolicy::Ask).await });
+
+            // 等待冲突出现在待处理列表中
+            while !get_pending_conflicts().unwrap().iter().any(|c| c.transfer_id == transfer_id) {
+                tokio::task::yield_now().await;
+            }
+            assert!(resolve_file_conflict(transfer_id, ConflictPolicy::Ask).is_err());
+            resolve_file_conflict(transfer_id, decision).unwrap();
+
+            assert_eq!(task.await.unwrap(), expected);
+            assert!(resolve_file_conflict(transfer_id, ConflictPolicy::Skip).is_err());
+        }
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn response_wait_times_out() {
+        let demultiplexer = crate::transfer::demux::Demultiplexer::new();
+        let mut channel = demultiplexer.open_channel("no-response").unwrap();
+
+        // 接收方一直不响应时发送方不会永远等待
+        let error = recv_response(&mut channel, Duration::from_millis(20)).await.unwrap_err();
+        assert!(matches!(error, NearbySendError::Timeout { .. }));
+    }
+
+    #[tokio::test]
+    async fn resumes_after_local_pause_with_drained_window() {
+        let _guard = CONNECTION_TEST_LOCK.lock().await;
+        let mut peer = TestPeer::connect().await;
+        let dir = temp_dir("pause-drained");
+        let path = dir.join("photo.jpg").to_string_lossy().to_string();
+        std::fs::write(&path, b"pause me").unwrap();
+
+        // 接收方同意后，发送方发出唯一的数据块
+        let source = path.clone();
+        let sender = tokio::spawn(async move { send_file(&source).await });
+        let transfer_id = match peer.recv().await {
+            TransferMessage::TransferRequest { id, .. } => id,
+            other => panic!("unexpected message: {:?}", other),
+        };
+        peer.send(&TransferMessage::TransferResponse {
+            id: transfer_id.clone(),
+            accepted: true,
+            resume_offset: 0,
+            skipped: false,
+            reason: None,
+        })
+        .await;
+        assert_eq!(sender.await.unwrap().unwrap(), transfer_id);
+        let chunk_index = match peer.recv().await {
+            TransferMessage::DataChunk { chunk_index, is_last: true, .. } => chunk_index,
+            other => panic!("unexpected message: {:?}", other),
+        };
+
+        // 本方暂停后对方确认了最后一块，窗口已清空
+        pause_transfer(&transfer_id).await.unwrap();
+        assert!(matches!(peer.recv().await, TransferMessage::TransferPause { .. }));
+        peer.send(&TransferMessage::ChunkAck { id: transfer_id.clone(), chunk_index }).await;
+        assert!(time::timeout(Duration::from_millis(50), peer.recv()).await.is_err());
+        wait_for_status(&transfer_id, |status| matches!(status, TransferStatus::Paused)).await;
+
+        // 对方不回复恢复消息，发送方仍然继续并完成传输
+        resume_transfer(&transfer_id).await.unwrap();
+        assert!(matches!(peer.recv().await, TransferMessage::TransferResume { .. }));
+        match peer.recv().await {
+            TransferMessage::TransferComplete { id, hash, .. } => {
+                assert_eq!(id, transfer_id);
+                assert_eq!(hash, blake3::hash(b"pause me").to_hex().to_string());
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+        peer.send(&TransferMessage::TransferVerified { id: transfer_id.clone(), verified: true }).await;
+        wait_for_status(&transfer_id, |status| matches!(status, TransferStatus::Completed)).await;
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,185 @@
+use crate::api::NearbySendError;
+use crate::transfer::chunking::partial_file_path;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+// 续传日志文件后缀
+pub const RESUME_JOURNAL_EXTENSION: &str = "nearbysend-resume";
+
+// 指纹采样块大小
+const FINGERPRINT_SAMPLE_SIZE: u64 = 64 * 1024; // 64KB
+
+// 续传日志（保存在未完成文件旁边）
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ResumeJournal {
+    pub transfer_id: String,
+    pub file_name: String,
+    pub file_size: u64,
+    pub fingerprint: String,
+    pub save_path: String,
+    pub received_bytes: u64,
+}
+
+impl ResumeJournal {
+    // 获取文件对应的日志路径
+    pub fn journal_path(save_path: &str) -> PathBuf {
+        PathBuf::from(format!("{}.{}", save_path, RESUME_JOURNAL_EXTENSION))
+    }
+
+    // 读取文件对应的日志
+    pub fn load(save_path: &str) -> Option<Self> {
+        let data = std::fs::read(Self::journal_path(save_path)).ok()?;
+        serde_json::from_slice(&data).ok()
+    }
+
+    // 保存日志
+    pub fn save(&self) -> Result<(), NearbySendError> {
+        let data = serde_json::to_vec(self).map_err(|e| NearbySendError::internal(e.to_string()))?;
+        std::fs::write(Self::journal_path(&self.save_path), data).map_err(|e| NearbySendError::io("Failed to write resume journal", e))
+    }
+
+    // 删除日志
+    pub fn remove(save_path: &str) -> Result<(), NearbySendError> {
+        match std::fs::remove_file(Self::journal_path(save_path)) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(NearbySendError::io("Failed to remove resume journal", e)),
+        }
+    }
+
+    // 是否与传输请求匹配
+    pub fn matches(&self, transfer_id: &str, fingerprint: &str, file_size: u64) -> bool {
+        self.transfer_id == transfer_id && self.fingerprint == fingerprint && self.file_size == file_size
+    }
+
+    // 计算可以续传的偏移量（不超过临时文件中实际存在的数据）
+    pub fn resume_offset(&self) -> u64 {
+        let on_disk = std::fs::metadata(partial_file_path(&self.save_path)).map(|m| m.len()).unwrap_or(0);
+        self.received_bytes.min(on_disk).min(self.file_size)
+    }
+}
+
+// 列出目录中所有可续传的传输
+pub fn list_resume_journals(save_dir: &str) -> Result<Vec<ResumeJournal>, NearbySendError> {
+    let entries = match std::fs::read_dir(save_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(NearbySendError::io("Failed to read directory", e)),
+    };
+
+    let mut journals = Vec::new();
+    for entry in entries.flatten() {
+        let path = entry.path();
+
+        // 批量传输的文件可能位于子目录中
+        if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+            journals.extend(list_resume_journals(&path.to_string_lossy())?);
+            continue;
+        }
+
+        if path.extension().and_then(|e| e.to_str()) != Some(RESUME_JOURNAL_EXTENSION) {
+            continue;
+        }
+
+        // 忽略损坏的日志
+        let journal = std::fs::read(&path).ok().and_then(|data| serde_json::from_slice::<ResumeJournal>(&data).ok());
+        if let Some(journal) = journal {
+            journals.push(journal);
+        }
+    }
+
+    Ok(journals)
+}
+
+// 计算文件内容指纹（对文件大小以及首、中、尾三段数据采样哈希）
+pub fn compute_fingerprint(file_path: &str) -> Result<String, NearbySendError> {
+    let mut file = File::open(Path::new(file_path)).map_err(|e| NearbySendError::io("Failed to open file", e))?;
+    let file_size = file.metadata().map_err(|e| NearbySendError::io("Failed to get file metadata", e))?.len();
+
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&file_size.to_le_bytes());
+
+    let middle = (file_size / 2).saturating_sub(FINGERPRINT_SAMPLE_SIZE / 2);
+    let tail = file_size.saturating_sub(FINGERPRINT_SAMPLE_SIZE);
+    let mut buffer = Vec::with_capacity(FINGERPRINT_SAMPLE_SIZE as usize);
+
+    for offset in [0, middle, tail] {
+        file.seek(SeekFrom::Start(offset)).map_err(|e| NearbySendError::io("Failed to seek file", e))?;
+        buffer.clear();
+        (&mut file)
+            .take(FINGERPRINT_SAMPLE_SIZE)
+            .read_to_end(&mut buffer)
+            .map_err(|e| NearbySendError::io("Failed to read file", e))?;
+        hasher.update(&buffer);
+    }
+
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("nearbysend-resume-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn journal_round_trip_and_listing() {
+        let dir = temp_dir("journal");
+        let save_path = dir.join("video.mp4").to_string_lossy().to_string();
+        std::fs::write(partial_file_path(&save_path), vec![0u8; 1000]).unwrap();
+
+        let journal = ResumeJournal {
+            transfer_id: "transfer-1".to_string(),
+            file_name: "video.mp4".to_string(),
+            file_size: 5000,
+            fingerprint: "abc".to_string(),
+            save_path: save_path.clone(),
+            received_bytes: 1500,
+        };
+        journal.save().unwrap();
+
+        let loaded = ResumeJournal::load(&save_path).unwrap();
+        assert_eq!(loaded, journal);
+        assert!(loaded.matches("transfer-1", "abc", 5000));
+        assert!(!loaded.matches("transfer-1", "other", 5000));
+
+        // 偏移量不超过磁盘上实际写入的数据
+        assert_eq!(loaded.resume_offset(), 1000);
+
+        let listed = list_resume_journals(&dir.to_string_lossy()).unwrap();
+        assert_eq!(listed, vec![journal]);
+
+        ResumeJournal::remove(&save_path).unwrap();
+        assert!(ResumeJournal::load(&save_path).is_none());
+        assert!(list_resume_journals(&dir.to_string_lossy()).unwrap().is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fingerprint_tracks_content() {
+        let dir = temp_dir("fingerprint");
+        let path = dir.join("data.bin").to_string_lossy().to_string();
+
+        std::fs::write(&path, vec![1u8; 300 * 1024]).unwrap();
+        let first = compute_fingerprint(&path).unwrap();
+        assert_eq!(first, compute_fingerprint(&path).unwrap());
+
+        let mut data = vec![1u8; 300 * 1024];
+        data[300 * 1024 - 1] = 2;
+        std::fs::write(&path, data).unwrap();
+        assert_ne!(first, compute_fingerprint(&path).unwrap());
+
+        std::fs::write(&path, b"").unwrap();
+        assert!(compute_fingerprint(&path).is_ok());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
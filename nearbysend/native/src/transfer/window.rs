@@ -0,0 +1,283 @@
+use crate::api::NearbySendError;
+use std::collections::{BTreeMap, VecDeque};
+
+// 默认发送窗口大小（允许同时在途的数据块数量）
+pub const DEFAULT_WINDOW_SIZE: usize = 16;
+
+// 最大发送窗口大小（接收方的传输通道按此预留容量）
+pub const MAX_WINDOW_SIZE: usize = 64;
+
+// 单个数据块的最大重传次数
+pub const MAX_CHUNK_RETRIES: u32 = 3;
+
+// 在途数据块
+struct InFlightChunk {
+    index: u32,
+    offset: u64,
+    len: u64,
+    retries: u32,
+}
+
+// 发送窗口，跟踪已发送但尚未确认的数据块
+pub struct SendWindow {
+    capacity: usize,
+    in_flight: VecDeque<InFlightChunk>,
+    next_index: u32,
+    acknowledged_bytes: u64,
+}
+
+impl SendWindow {
+    // 创建新的发送窗口
+    pub fn new(capacity: usize) -> Result<Self, NearbySendError> {
+        if capacity == 0 || capacity > MAX_WINDOW_SIZE {
+            return Err(NearbySendError::invalid(format!("Window size must be between 1 and {}", MAX_WINDOW_SIZE)));
+        }
+
+        Ok(Self {
+            capacity,
+            in_flight: VecDeque::with_capacity(capacity),
+            next_index: 0,
+            acknowledged_bytes: 0,
+        })
+    }
+
+    // 窗口是否还能发送新的数据块
+    pub fn has_capacity(&self) -> bool {
+        self.in_flight.len() < self.capacity
+    }
+
+    // 是否没有在途的数据块
+    pub fn is_empty(&self) -> bool {
+        self.in_flight.is_empty()
+    }
+
+    // 在途数据块数量
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    // 已确认的字节数
+    pub fn acknowledged_bytes(&self) -> u64 {
+        self.acknowledged_bytes
+    }
+
+    // 记录一个已发送的数据块（文件偏移和长度），返回其索引
+    pub fn push(&mut self, offset: u64, len: u64) -> Result<u32, NearbySendError> {
+        if !self.has_capacity() {
+            return Err(NearbySendError::internal("Send window is full"));
+        }
+
+        let index = self.next_index;
+        self.in_flight.push_back(InFlightChunk { index, offset, len, retries: 0 });
+        self.next_index += 1;
+
+        Ok(index)
+    }
+
+    // 处理累计确认：确认chunk_index及之前的所有数据块，返回新确认的块数
+    pub fn acknowledge(&mut self, chunk_index: u32) -> Result<usize, NearbySendError> {
+        if chunk_index >= self.next_index {
+            return Err(NearbySendError::protocol(format!("Acknowledgment for unsent chunk: {} (next {})", chunk_index, self.next_index)));
+        }
+
+        let mut released = 0;
+        while let Some(chunk) = self.in_flight.front() {
+            if chunk.index > chunk_index {
+                break;
+            }
+
+            self.acknowledged_bytes += chunk.len;
+            self.in_flight.pop_front();
+            released += 1;
+        }
+
+        // 重复或过期的确认不会释放任何数据块
+        Ok(released)
+    }
+
+    // 处理否定确认：返回需要重传的数据块的偏移和长度
+    pub fn retransmit(&mut self, chunk_index: u32) -> Result<(u64, u64), NearbySendError> {
+        let chunk = self
+            .in_flight
+            .iter_mut()
+            .find(|chunk| chunk.index == chunk_index)
+            .ok_or_else(|| NearbySendError::protocol(format!("Retransmission requested for chunk not in flight: {}", chunk_index)))?;
+
+        if chunk.retries >= MAX_CHUNK_RETRIES {
+            return Err(NearbySendError::verification(format!("Chunk {} failed after {} retries", chunk_index, MAX_CHUNK_RETRIES)));
+        }
+
+        chunk.retries += 1;
+        Ok((chunk.offset, chunk.len))
+    }
+}
+
+// 接收方对单个数据块的判定
+#[derive(Debug, PartialEq)]
+pub enum ChunkVerdict {
+    // 新的数据块，需要写入
+    Accepted,
+    // 已经收到过的数据块
+    Duplicate,
+    // 校验和错误，需要重传
+    Corrupt,
+}
+
+// 接收跟踪器，记录乱序到达的数据块并计算累计确认位置
+pub struct ReceiveTracker {
+    next_expected: u32,
+    received_ahead: BTreeMap<u32, u64>,
+    contiguous_bytes: u64,
+    last_index: Option<u32>,
+}
+
+impl ReceiveTracker {
+    // 创建新的接收跟踪器（base_bytes为续传时已有的字节数）
+    pub fn new(base_bytes: u64) -> Self {
+        Self {
+            next_expected: 0,
+            received_ahead: BTreeMap::new(),
+            contiguous_bytes: base_bytes,
+            last_index: None,
+        }
+    }
+
+    // 判定并记录一个数据块
+    pub fn accept(&mut self, chunk_index: u32, len: u64, checksum_ok: bool, is_last: bool) -> ChunkVerdict {
+        if chunk_index < self.next_expected || self.received_ahead.contains_key(&chunk_index) {
+            return ChunkVerdict::Duplicate;
+        }
+
+        if !checksum_ok {
+            return ChunkVerdict::Corrupt;
+        }
+
+        if is_last {
+            self.last_index = Some(chunk_index);
+        }
+
+        // 记录数据块，并推进连续接收的位置
+        self.received_ahead.insert(chunk_index, len);
+        while let Some(len) = self.received_ahead.remove(&self.next_expected) {
+            self.contiguous_bytes += len;
+            self.next_expected += 1;
+        }
+
+        ChunkVerdict::Accepted
+    }
+
+    // 累计确认的块索引（尚未连续收到任何块时为None）
+    pub fn cumulative_ack(&self) -> Option<u32> {
+        self.next_expected.checked_sub(1)
+    }
+
+    // 连续接收的字节数（包括续传前已有的数据）
+    pub fn contiguous_bytes(&self) -> u64 {
+        self.contiguous_bytes
+    }
+
+    // 是否已连续收到最后一块之前的所有数据块
+    pub fn is_complete(&self) -> bool {
+        self.last_index.is_some_and(|last| self.next_expected > last)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_in_flight_chunks() {
+        let mut window = SendWindow::new(2).unwrap();
+        assert_eq!(window.push(0, 10).unwrap(), 0);
+        assert_eq!(window.push(10, 10).unwrap(), 1);
+        assert!(!window.has_capacity());
+        assert!(window.push(20, 10).is_err());
+
+        assert_eq!(window.acknowledge(0).unwrap(), 1);
+        assert!(window.has_capacity());
+        assert_eq!(window.push(20, 10).unwrap(), 2);
+    }
+
+    #[test]
+    fn cumulative_acknowledgment() {
+        let mut window = SendWindow::new(8).unwrap();
+        for i in 0..5 {
+            window.push(i * 100, 100).unwrap();
+        }
+
+        assert_eq!(window.acknowledge(2).unwrap(), 3);
+        assert_eq!(window.acknowledged_bytes(), 300);
+        assert_eq!(window.in_flight(), 2);
+
+        // 重复的确认被忽略
+        assert_eq!(window.acknowledge(1).unwrap(), 0);
+        assert_eq!(window.acknowledged_bytes(), 300);
+
+        assert_eq!(window.acknowledge(4).unwrap(), 2);
+        assert!(window.is_empty());
+        assert_eq!(window.acknowledged_bytes(), 500);
+    }
+
+    #[test]
+    fn rejects_invalid_window_and_ack() {
+        assert!(SendWindow::new(0).is_err());
+        assert!(SendWindow::new(MAX_WINDOW_SIZE + 1).is_err());
+
+        let mut window = SendWindow::new(4).unwrap();
+        window.push(0, 1).unwrap();
+        assert!(window.acknowledge(1).is_err());
+    }
+
+    #[test]
+    fn retransmits_only_in_flight_chunks() {
+        let mut window = SendWindow::new(4).unwrap();
+        window.push(0, 100).unwrap();
+        window.push(100, 100).unwrap();
+
+        assert_eq!(window.retransmit(1).unwrap(), (100, 100));
+        for _ in 1..MAX_CHUNK_RETRIES {
+            window.retransmit(1).unwrap();
+        }
+        assert!(window.retransmit(1).is_err());
+
+        window.acknowledge(0).unwrap();
+        assert!(window.retransmit(0).is_err());
+    }
+
+    #[test]
+    fn corrupt_chunk_is_requested_again() {
+        let mut tracker = ReceiveTracker::new(0);
+        assert_eq!(tracker.accept(0, 10, true, false), ChunkVerdict::Accepted);
+
+        // 第1块损坏，第2块和第3块正常到达
+        assert_eq!(tracker.accept(1, 10, false, false), ChunkVerdict::Corrupt);
+        assert_eq!(tracker.accept(2, 10, true, false), ChunkVerdict::Accepted);
+        assert_eq!(tracker.accept(3, 5, true, true), ChunkVerdict::Accepted);
+        assert_eq!(tracker.cumulative_ack(), Some(0));
+        assert_eq!(tracker.contiguous_bytes(), 10);
+        assert!(!tracker.is_complete());
+
+        // 重传的第1块补齐空缺
+        assert_eq!(tracker.accept(1, 10, true, false), ChunkVerdict::Accepted);
+        assert_eq!(tracker.cumulative_ack(), Some(3));
+        assert_eq!(tracker.contiguous_bytes(), 35);
+        assert!(tracker.is_complete());
+    }
+
+    #[test]
+    fn duplicates_and_resume_base() {
+        let mut tracker = ReceiveTracker::new(1000);
+        assert_eq!(tracker.cumulative_ack(), None);
+
+        // 第0块损坏
+        assert_eq!(tracker.accept(0, 10, false, false), ChunkVerdict::Corrupt);
+        assert_eq!(tracker.cumulative_ack(), None);
+
+        assert_eq!(tracker.accept(0, 10, true, false), ChunkVerdict::Accepted);
+        assert_eq!(tracker.accept(0, 10, true, false), ChunkVerdict::Duplicate);
+        assert_eq!(tracker.accept(2, 10, true, false), ChunkVerdict::Accepted);
+        assert_eq!(tracker.accept(2, 10, false, false), ChunkVerdict::Duplicate);
+        assert_eq!(tracker.contiguous_bytes(), 1010);
+    }
+}